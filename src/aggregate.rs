@@ -0,0 +1,251 @@
+//! Summary statistics over the records of a DB.
+//!
+//! Sums and means are computed with `f64`, so they cannot overflow whatever the value type of the DB is.
+
+use crate::{Precision, RecordInfo, TSLiteError, Timestamp, Value};
+
+/// The statistics that can be computed over some records.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Aggregation {
+    Min,
+    Max,
+    Mean,
+    Sum,
+    Count,
+    First,
+    Last,
+}
+
+/// Summary statistics over some records, computed in one pass.
+/// Records are expected to be pushed in chronological order for `first` and `last` to make sense.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aggregate<V: Value = u8> {
+    pub count: u64,
+    pub sum: f64,
+    pub min: Option<V>,
+    pub max: Option<V>,
+    pub first: Option<RecordInfo<V>>,
+    pub last: Option<RecordInfo<V>>,
+}
+
+impl<V: Value> Default for Aggregate<V> {
+    fn default() -> Aggregate<V> {
+        Aggregate {
+            count: 0,
+            sum: 0.0,
+            min: None,
+            max: None,
+            first: None,
+            last: None,
+        }
+    }
+}
+
+impl<V: Value> Aggregate<V> {
+    /// Add a record to the statistics.
+    pub fn push(&mut self, record: RecordInfo<V>) {
+        self.count += 1;
+        self.sum += record.value.to_f64();
+        if self.min.map_or(true, |min| record.value < min) {
+            self.min = Some(record.value);
+        }
+        if self.max.map_or(true, |max| record.value > max) {
+            self.max = Some(record.value);
+        }
+        if self.first.is_none() {
+            self.first = Some(record);
+        }
+        self.last = Some(record);
+    }
+
+    /// The mean of the values, `None` if there was no record.
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        Some(self.sum / self.count as f64)
+    }
+
+    /// Get one of the statistics as a `f64`.
+    /// Return `None` if there was no record, except for `Count` and `Sum` which are `0` in this case.
+    pub fn get(&self, aggregation: Aggregation) -> Option<f64> {
+        match aggregation {
+            Aggregation::Min => self.min.map(Value::to_f64),
+            Aggregation::Max => self.max.map(Value::to_f64),
+            Aggregation::Mean => self.mean(),
+            Aggregation::Sum => Some(self.sum),
+            Aggregation::Count => Some(self.count as f64),
+            Aggregation::First => self.first.map(|r| r.value.to_f64()),
+            Aggregation::Last => self.last.map(|r| r.value.to_f64()),
+        }
+    }
+}
+
+impl<V: Value> Extend<RecordInfo<V>> for Aggregate<V> {
+    fn extend<I: IntoIterator<Item = RecordInfo<V>>>(&mut self, iter: I) {
+        for record in iter {
+            self.push(record);
+        }
+    }
+}
+
+/// How to report the buckets without any record when downsampling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fill {
+    /// Empty buckets are not reported.
+    None,
+    /// Empty buckets are reported without value.
+    Null,
+    /// Empty buckets take the value of the previous non-empty bucket.
+    Previous,
+    /// Empty buckets are linearly interpolated between the surrounding non-empty buckets.
+    /// Empty buckets at the start or the end of the range are reported without value.
+    Linear,
+}
+
+/// One bucket of a downsampled range, starting at `start`.
+/// `value` is `None` if the bucket is empty and could not be filled.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bucket {
+    pub start: Timestamp,
+    pub value: Option<f64>,
+}
+
+/// The buckets covering a range, identified by their index since the origin date of a DB.
+/// Both `first` and `last` are included, `step` is the number of time units of `precision` in a bucket.
+pub(crate) struct Buckets {
+    first: i64,
+    last: i64,
+    step: i64,
+    precision: Precision,
+}
+
+impl Buckets {
+    /// Compute the buckets of `interval` covering the time offsets in `[from, to)`,
+    /// counted in time units of `precision`.
+    /// Return `None` if there is no bucket in the range, which is the case if `to` is not after `from`.
+    pub(crate) fn new(
+        from: i64,
+        to: i64,
+        interval: chrono::Duration,
+        precision: Precision,
+    ) -> Result<Option<Buckets>, TSLiteError> {
+        let step = precision.units(interval);
+        if step <= 0 || interval != precision.duration(step) {
+            return Err(TSLiteError::InvalidInterval);
+        }
+
+        if to <= from {
+            return Ok(None);
+        }
+
+        Ok(Some(Buckets {
+            first: from.div_euclid(step),
+            last: (to - 1).div_euclid(step),
+            step,
+            precision,
+        }))
+    }
+
+    /// Compute `aggregation` over the records of each bucket, then apply `fill` to the empty buckets.
+    /// Records outside of the buckets are ignored. Records are expected to be ordered: the buckets are
+    /// reported as the records cross their end, so only the bucket being filled is kept in memory
+    /// whatever the number of buckets in the range.
+    pub(crate) fn aggregate<V, I>(
+        &self,
+        origin: Timestamp,
+        records: I,
+        aggregation: Aggregation,
+        fill: Fill,
+    ) -> Result<Vec<Bucket>, TSLiteError>
+    where
+        V: Value,
+        I: IntoIterator<Item = Result<RecordInfo<V>, TSLiteError>>,
+    {
+        let mut buckets = Vec::new();
+        let mut previous: Option<(i64, Option<f64>)> = None;
+        let mut current: Option<(i64, Aggregate<V>)> = None;
+        for res in records {
+            let record = res?;
+            let bucket = record.time_offset.div_euclid(self.step);
+            // A record anterior to the bucket being filled is out of order, it is ignored.
+            if bucket < self.first
+                || self.last < bucket
+                || current.as_ref().is_some_and(|(index, _)| bucket < *index)
+            {
+                continue;
+            }
+
+            match current.as_mut() {
+                Some((index, aggregate)) if *index == bucket => aggregate.push(record),
+                _ => {
+                    if let Some((index, aggregate)) = current.take() {
+                        let next = (index, aggregate.get(aggregation));
+                        self.fill_gap(&mut buckets, origin, previous, Some(next), fill)?;
+                        buckets.push(self.bucket(origin, index, next.1)?);
+                        previous = Some(next);
+                    }
+                    let mut aggregate = Aggregate::default();
+                    aggregate.push(record);
+                    current = Some((bucket, aggregate));
+                }
+            }
+        }
+
+        if let Some((index, aggregate)) = current {
+            let next = (index, aggregate.get(aggregation));
+            self.fill_gap(&mut buckets, origin, previous, Some(next), fill)?;
+            buckets.push(self.bucket(origin, index, next.1)?);
+            previous = Some(next);
+        }
+        self.fill_gap(&mut buckets, origin, previous, None, fill)?;
+
+        Ok(buckets)
+    }
+
+    /// The bucket of index `index` with the value `value`.
+    fn bucket(
+        &self,
+        origin: Timestamp,
+        index: i64,
+        value: Option<f64>,
+    ) -> Result<Bucket, TSLiteError> {
+        let offset = index * self.step;
+        let start = origin
+            .shift_in(offset, self.precision)
+            .ok_or(TSLiteError::TimeOffsetOutOfRange(offset))?;
+        Ok(Bucket { start, value })
+    }
+
+    /// Report the empty buckets between the non-empty buckets `previous` and `next`, given with their
+    /// index and value, according to `fill`. Without `previous` or `next`, the empty buckets go from
+    /// the first bucket or to the last one.
+    fn fill_gap(
+        &self,
+        buckets: &mut Vec<Bucket>,
+        origin: Timestamp,
+        previous: Option<(i64, Option<f64>)>,
+        next: Option<(i64, Option<f64>)>,
+        fill: Fill,
+    ) -> Result<(), TSLiteError> {
+        let start = previous.map_or(self.first, |(index, _)| index + 1);
+        let end = next.map_or(self.last + 1, |(index, _)| index);
+        if fill == Fill::None {
+            return Ok(());
+        }
+
+        for index in start..end {
+            let value = match (fill, previous, next) {
+                (Fill::Previous, Some((_, value)), _) => value,
+                (Fill::Linear, Some((p, Some(from))), Some((n, Some(to)))) => {
+                    Some(from + (to - from) * (index - p) as f64 / (n - p) as f64)
+                }
+                _ => None,
+            };
+            buckets.push(self.bucket(origin, index, value)?);
+        }
+
+        Ok(())
+    }
+}
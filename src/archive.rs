@@ -0,0 +1,444 @@
+//! Round-robin archives keeping a series at several resolutions.
+//!
+//! A [`RoundRobinDB`] is a directory holding the raw records in a ring DB (see
+//! [`DbOptions::capacity`](crate::DbOptions::capacity)) named `raw.db`, and one ring DB per
+//! [`Archive`]. Every archive consolidates the raw records of each interval of its resolution into a
+//! single `f64` row, stored with the time offset of the start of the interval. For example, keeping
+//! a day of records every second, a month of mean values per minute and years of mean values per hour.
+//!
+//! Archives are fed as raw records are appended: the records of the current interval of every archive
+//! are kept in memory and the row is written once a record of a later interval is appended. When the
+//! DB is opened again, the current interval is rebuilt from the raw records, so nothing is lost as long
+//! as the raw ring covers at least the longest resolution. Records older than the current interval of
+//! an archive are only stored in the raw ring.
+//!
+//! Archives are named after their resolution, in time units of the DB, and their consolidation,
+//! like `60-mean.db`, so changing the archives of a DB creates new files instead of mixing rows.
+
+use chrono::{DateTime, Utc};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    Aggregate, Aggregation, Bucket, DbOptions, PhysicalDB, RecordInfo, TSLiteError, Timestamp,
+    Value, ValueType,
+};
+
+/// An archive of a [`RoundRobinDB`]: `rows` rows, each consolidating the raw records of an interval
+/// of `resolution` with `consolidation`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Archive {
+    pub resolution: chrono::Duration,
+    pub rows: u64,
+    pub consolidation: Aggregation,
+}
+
+/// The rows returned by [`RoundRobinDB::fetch`], from the archive `archive`,
+/// or raw records if `archive` is `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fetched {
+    pub archive: Option<Archive>,
+    pub buckets: Vec<Bucket>,
+}
+
+/// An archive with its ring DB and the records of its current interval.
+#[derive(Debug)]
+struct ArchiveState<V: Value> {
+    archive: Archive,
+    db: PhysicalDB,
+    step: i64,
+    current: Option<(i64, Aggregate<V>)>,
+}
+
+impl<V: Value> ArchiveState<V> {
+    /// Add a raw record, writing the row of the current interval if the record starts a new one.
+    /// Records anterior to the current interval are ignored.
+    fn feed(&mut self, record: RecordInfo<V>) -> Result<(), TSLiteError> {
+        let start = record.time_offset.div_euclid(self.step) * self.step;
+        if let Some((current, aggregate)) = &self.current {
+            if start < *current {
+                return Ok(());
+            }
+            if start > *current {
+                if let Some(value) = aggregate.get(self.archive.consolidation) {
+                    self.db.append_record(RecordInfo {
+                        time_offset: *current,
+                        value,
+                    })?;
+                }
+                self.current = None;
+            }
+        }
+
+        self.current
+            .get_or_insert_with(|| (start, Aggregate::default()))
+            .1
+            .push(record);
+        Ok(())
+    }
+
+    /// Tell if the rows of the archive go back to `offset`, or if it never dropped any row.
+    fn covers(&mut self, offset: i64) -> Result<bool, TSLiteError> {
+        if self.db.header.overwritten == 0 {
+            return Ok(true);
+        }
+
+        Ok(self.db.read_record::<f64>(0)?.time_offset <= offset)
+    }
+}
+
+/// A series stored as raw records and as archives at coarser resolutions, all of fixed size.
+/// `V` is the type of the raw values, the rows of the archives are `f64`.
+#[derive(Debug)]
+pub struct RoundRobinDB<V: Value = u8> {
+    dir: PathBuf,
+    raw: PhysicalDB,
+    archives: Vec<ArchiveState<V>>,
+}
+
+impl<V: Value> RoundRobinDB<V> {
+    /// Open the DB stored in the directory `dir`, creating it if it does not exist.
+    /// The raw ring keeps `raw_rows` records. `options` are used to create the raw ring, its value type
+    /// must be the one of `V` and its capacity is ignored.
+    /// The resolution of every archive must be a whole number of time units of the DB,
+    /// otherwise `TSLiteError::InvalidInterval` is returned. Two archives with the same resolution and
+    /// consolidation would share their file, `TSLiteError::IncompatibleOptions` is returned.
+    /// If the DB already exists, its origin date and options are read from the raw ring
+    /// and `origin_date`, `raw_rows` and `options` are ignored, like with [`PhysicalDB::new_with_options`].
+    pub fn new(
+        dir: &Path,
+        origin_date: Option<DateTime<Utc>>,
+        raw_rows: u64,
+        archives: &[Archive],
+        options: &DbOptions,
+    ) -> Result<RoundRobinDB<V>, TSLiteError> {
+        fs::create_dir_all(dir).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        let raw_options = DbOptions {
+            capacity: Some(raw_rows),
+            ..*options
+        };
+        let raw = PhysicalDB::new_with_options(&dir.join("raw.db"), origin_date, &raw_options)?;
+        raw.check_value_type::<V>()?;
+        let header = raw.header;
+        let origin_date: DateTime<Utc> = (&header.origin_date).into();
+
+        let mut db = RoundRobinDB {
+            dir: PathBuf::from(dir),
+            raw,
+            archives: Vec::with_capacity(archives.len()),
+        };
+        for archive in archives {
+            let precision = header.precision;
+            let step = precision.units(archive.resolution);
+            if step <= 0 || archive.resolution != precision.duration(step) {
+                return Err(TSLiteError::InvalidInterval);
+            }
+            if db.archives.iter().any(|state| {
+                state.step == step && state.archive.consolidation == archive.consolidation
+            }) {
+                return Err(TSLiteError::IncompatibleOptions);
+            }
+
+            let options = DbOptions {
+                value_type: ValueType::F64,
+                capacity: Some(archive.rows),
+                ..header.options()
+            };
+            let name = format!("{}-{}.db", step, consolidation_name(archive.consolidation));
+            let path = db.dir.join(name);
+            let archive_db = PhysicalDB::new_with_options(&path, Some(origin_date), &options)?;
+            db.archives.push(ArchiveState {
+                archive: *archive,
+                db: archive_db,
+                step,
+                current: None,
+            });
+        }
+
+        db.rebuild_current()?;
+        Ok(db)
+    }
+
+    /// Rebuild the current interval of every archive from the raw records following its last row.
+    fn rebuild_current(&mut self) -> Result<(), TSLiteError> {
+        for state in self.archives.iter_mut() {
+            let records_number = state.db.header.records_number;
+            let next = match records_number {
+                0 => i64::MIN,
+                _ => state.db.read_record::<f64>(records_number - 1)?.time_offset + state.step,
+            };
+            for res in self.raw.iter::<V>()? {
+                let record = res?.1;
+                if record.time_offset >= next {
+                    state.feed(record)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The ring holding the raw records.
+    pub fn raw(&self) -> &PhysicalDB {
+        &self.raw
+    }
+
+    /// Append a raw record and feed it to every archive.
+    pub fn append_record(&mut self, rec_nfo: RecordInfo<V>) -> Result<(), TSLiteError> {
+        self.raw.append_record(rec_nfo)?;
+        for state in self.archives.iter_mut() {
+            state.feed(rec_nfo)?;
+        }
+
+        Ok(())
+    }
+
+    /// Append a raw record with the current time.
+    pub fn append_record_now(&mut self, value: V) -> Result<(), TSLiteError> {
+        let now = Timestamp::try_from(Utc::now())?;
+        let header = self.raw.header;
+        let off = header.origin_date.offset_in(&now, header.precision);
+        self.append_record(RecordInfo {
+            value,
+            time_offset: off,
+        })
+    }
+
+    /// Return the rows between `from` (included) and `to` (excluded) consolidated with `consolidation`,
+    /// from the finest source that still holds `from`: the raw records, then the archives using
+    /// `consolidation` from the finest to the coarsest. If none holds `from`, the coarsest archive
+    /// using `consolidation` is used. The current interval of the archive is included.
+    pub fn fetch(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        consolidation: Aggregation,
+    ) -> Result<Fetched, TSLiteError> {
+        let header = self.raw.header;
+        let first = header.offset_from_date(from);
+        let last = header.offset_from_date(to);
+
+        let raw_covers = self.raw.header.overwritten == 0
+            || self
+                .raw
+                .read_time_offset(0)
+                .is_ok_and(|offset| offset <= first);
+        let mut candidates: Vec<usize> = (0..self.archives.len())
+            .filter(|i| self.archives[*i].archive.consolidation == consolidation)
+            .collect();
+        if raw_covers || candidates.is_empty() {
+            let buckets = self
+                .raw
+                .iter_range::<V>(from, to)?
+                .map(|res| {
+                    res.and_then(|(_, r)| {
+                        Ok(Bucket {
+                            start: header.record_date(r.time_offset)?,
+                            value: Some(r.value.to_f64()),
+                        })
+                    })
+                })
+                .collect::<Result<Vec<Bucket>, TSLiteError>>()?;
+            return Ok(Fetched {
+                archive: None,
+                buckets,
+            });
+        }
+
+        candidates.sort_by_key(|i| self.archives[*i].step);
+        let mut chosen = *candidates.last().unwrap();
+        for i in candidates {
+            if self.archives[i].covers(first)? {
+                chosen = i;
+                break;
+            }
+        }
+
+        let state = &mut self.archives[chosen];
+        let mut buckets = state
+            .db
+            .iter_range::<f64>(from, to)?
+            .map(|res| {
+                res.and_then(|(_, r)| {
+                    Ok(Bucket {
+                        start: header.record_date(r.time_offset)?,
+                        value: Some(r.value),
+                    })
+                })
+            })
+            .collect::<Result<Vec<Bucket>, TSLiteError>>()?;
+        if let Some((start, aggregate)) = &state.current {
+            if first <= *start && *start < last {
+                buckets.push(Bucket {
+                    start: header.record_date(*start)?,
+                    value: aggregate.get(consolidation),
+                });
+            }
+        }
+
+        Ok(Fetched {
+            archive: Some(state.archive),
+            buckets,
+        })
+    }
+
+    /// Close the raw ring and every archive.
+    pub fn close(&mut self) -> Result<(), TSLiteError> {
+        self.raw.close()?;
+        for state in self.archives.iter_mut() {
+            state.db.close()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The name of a consolidation in the file name of an archive.
+fn consolidation_name(consolidation: Aggregation) -> &'static str {
+    match consolidation {
+        Aggregation::Min => "min",
+        Aggregation::Max => "max",
+        Aggregation::Mean => "mean",
+        Aggregation::Sum => "sum",
+        Aggregation::Count => "count",
+        Aggregation::First => "first",
+        Aggregation::Last => "last",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn round_robin_archives() {
+        let dir = "round_robin_archives";
+        let _ = fs::remove_dir_all(dir);
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let minutes = |m: i64| origin_date + chrono::Duration::minutes(m);
+        let archives = [
+            Archive {
+                resolution: chrono::Duration::minutes(1),
+                rows: 20,
+                consolidation: Aggregation::Mean,
+            },
+            Archive {
+                resolution: chrono::Duration::minutes(1),
+                rows: 60,
+                consolidation: Aggregation::Max,
+            },
+            Archive {
+                resolution: chrono::Duration::minutes(10),
+                rows: 100,
+                consolidation: Aggregation::Mean,
+            },
+        ];
+        let mut db = RoundRobinDB::<u8>::new(
+            Path::new(dir),
+            Some(origin_date),
+            120,
+            &archives,
+            &DbOptions::default(),
+        )
+        .expect("could not create db.");
+
+        // A record every second for 30 minutes.
+        for i in 0..1800 {
+            db.append_record(RecordInfo {
+                time_offset: i,
+                value: (i % 60) as u8,
+            })
+            .expect("could not append record.");
+        }
+        assert_eq!(db.raw().header.records_number, 120);
+
+        // The raw records still cover the last minutes.
+        let fetched = db
+            .fetch(minutes(29), minutes(30), Aggregation::Mean)
+            .expect("could not fetch db.");
+        assert_eq!(fetched.archive, None);
+        assert_eq!(fetched.buckets.len(), 60);
+
+        // The rows per minute start at the 9th minute, the current minute is included.
+        let fetched = db
+            .fetch(minutes(10), minutes(30), Aggregation::Mean)
+            .expect("could not fetch db.");
+        assert_eq!(fetched.archive, Some(archives[0]));
+        assert_eq!(fetched.buckets.len(), 20);
+        assert!(fetched.buckets.iter().all(|b| b.value == Some(29.5)));
+        assert_eq!(
+            fetched.buckets[0].start,
+            Timestamp::try_from(minutes(10)).unwrap()
+        );
+
+        let fetched = db
+            .fetch(origin_date, minutes(30), Aggregation::Mean)
+            .expect("could not fetch db.");
+        assert_eq!(fetched.archive, Some(archives[2]));
+        assert_eq!(fetched.buckets.len(), 3);
+
+        let fetched = db
+            .fetch(origin_date, minutes(30), Aggregation::Max)
+            .expect("could not fetch db.");
+        assert_eq!(fetched.archive, Some(archives[1]));
+        assert_eq!(fetched.buckets.len(), 30);
+        assert!(fetched.buckets.iter().all(|b| b.value == Some(59.0)));
+        db.close().expect("could not close db.");
+
+        // The current minute is rebuilt from the raw records when the DB is opened again.
+        let mut db =
+            RoundRobinDB::<u8>::new(Path::new(dir), None, 120, &archives, &DbOptions::default())
+                .expect("could not open db.");
+        db.append_record(RecordInfo {
+            time_offset: 1800,
+            value: 0,
+        })
+        .expect("could not append record.");
+        let fetched = db
+            .fetch(minutes(25), minutes(31), Aggregation::Mean)
+            .expect("could not fetch db.");
+        assert_eq!(fetched.archive, Some(archives[0]));
+        let values: Vec<Option<f64>> = fetched.buckets.iter().map(|b| b.value).collect();
+        assert_eq!(
+            values,
+            vec![
+                Some(29.5),
+                Some(29.5),
+                Some(29.5),
+                Some(29.5),
+                Some(29.5),
+                Some(0.0)
+            ]
+        );
+
+        assert_eq!(
+            RoundRobinDB::<u16>::new(Path::new(dir), None, 120, &archives, &DbOptions::default())
+                .err(),
+            Some(TSLiteError::TypeMismatch)
+        );
+        let duplicated = [
+            archives[0],
+            Archive {
+                resolution: chrono::Duration::seconds(60),
+                rows: 10,
+                consolidation: Aggregation::Mean,
+            },
+        ];
+        assert_eq!(
+            RoundRobinDB::<u8>::new(
+                Path::new(dir),
+                None,
+                120,
+                &duplicated,
+                &DbOptions::default()
+            )
+            .err(),
+            Some(TSLiteError::IncompatibleOptions)
+        );
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,308 @@
+//! An in-memory layer on top of a [`PhysicalDB`].
+//!
+//! Appending records one by one directly to a DB file means at least one sync per record.
+//! [`BufferedDB`] keeps the most recent records in memory and writes them to the file all at once
+//! with [`PhysicalDB::append_records`], either when enough records are buffered, when enough time
+//! has passed since the last flush, or when it is dropped.
+//!
+//! There is no background thread: the time threshold is only checked when a record is appended.
+
+use chrono::{DateTime, Utc};
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use crate::{
+    Aggregate, Aggregation, Bucket, Buckets, Fill, PhysicalDB, RecordInfo, TSLiteError, Timestamp,
+    Value,
+};
+
+/// A DB keeping its most recent records in memory until they are flushed to the file.
+/// Queries are served from both the file and the buffer.
+/// Records are appended in the order they are given, exactly like [`PhysicalDB::append_record`].
+#[derive(Debug)]
+pub struct BufferedDB<V: Value = u8> {
+    db: PhysicalDB,
+    buffer: Vec<RecordInfo<V>>,
+    max_records: usize,
+    flush_interval: Duration,
+    last_flush: Instant,
+}
+
+impl<V: Value> BufferedDB<V> {
+    /// Wrap a DB. The buffer is flushed once it holds `max_records` records or
+    /// once `flush_interval` has passed since the last flush.
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn new(
+        db: PhysicalDB,
+        max_records: usize,
+        flush_interval: Duration,
+    ) -> Result<BufferedDB<V>, TSLiteError> {
+        db.check_value_type::<V>()?;
+        Ok(BufferedDB {
+            db,
+            buffer: Vec::with_capacity(max_records),
+            max_records,
+            flush_interval,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// The underlying DB. It does not contain the records that are still buffered.
+    pub fn db(&self) -> &PhysicalDB {
+        &self.db
+    }
+
+    /// The number of records that are not flushed yet.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The number of records in the file and in the buffer.
+    pub fn records_number(&self) -> u64 {
+        self.db.header.records_number + self.buffer.len() as u64
+    }
+
+    /// Add a record, flushing the buffer if one of the threshold is reached.
+    /// If the time offset of the record cannot be stored in the DB, `TSLiteError::TimeOffsetOutOfRange`
+    /// is returned and the record is not buffered.
+    pub fn append_record(&mut self, rec_nfo: RecordInfo<V>) -> Result<(), TSLiteError> {
+        self.db.header.check_time_offset(rec_nfo.time_offset)?;
+        self.buffer.push(rec_nfo);
+        if self.buffer.len() >= self.max_records || self.last_flush.elapsed() >= self.flush_interval
+        {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Append a record with the current time.
+    pub fn append_record_now(&mut self, value: V) -> Result<(), TSLiteError> {
+        let origin = self.db.header.origin_date;
+        let now = Timestamp::try_from(Utc::now())?;
+        let off = origin.offset_in(&now, self.db.header.precision);
+        self.append_record(RecordInfo {
+            value,
+            time_offset: off,
+        })
+    }
+
+    /// Write every buffered record to the file.
+    /// If it fails, the records stay in the buffer.
+    pub fn flush(&mut self) -> Result<(), TSLiteError> {
+        self.db.append_records(&self.buffer)?;
+        self.buffer.clear();
+        self.last_flush = Instant::now();
+
+        Ok(())
+    }
+
+    /// Read a record, either from the file or from the buffer.
+    /// Buffered records come after the records of the file.
+    pub fn read_record(&mut self, rec_id: u64) -> Result<RecordInfo<V>, TSLiteError> {
+        let records_number = self.db.header.records_number;
+        if rec_id < records_number {
+            return self.db.read_record(rec_id);
+        }
+
+        self.buffer
+            .get((rec_id - records_number) as usize)
+            .copied()
+            .ok_or(TSLiteError::IndexOutOfBound)
+    }
+
+    /// The buffered records between `from` (included) and `to` (excluded).
+    fn buffered_range(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> impl Iterator<Item = RecordInfo<V>> + '_ {
+        let first = self.db.offset_from_date(from);
+        let last = self.db.offset_from_date(to);
+        self.buffer
+            .iter()
+            .copied()
+            .filter(move |r| first <= r.time_offset && r.time_offset < last)
+    }
+
+    /// Return every record between `from` (included) and `to` (excluded), see [`PhysicalDB::query_range`].
+    pub fn query_range(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<RecordInfo<V>>, TSLiteError> {
+        let mut records = self.db.query_range(from, to)?;
+        records.extend(self.buffered_range(from, to));
+        Ok(records)
+    }
+
+    /// Compute the statistics of the records between `from` (included) and `to` (excluded),
+    /// see [`PhysicalDB::aggregate`].
+    pub fn aggregate(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Aggregate<V>, TSLiteError> {
+        let mut aggregate = self.db.aggregate(from, to)?;
+        aggregate.extend(self.buffered_range(from, to));
+        Ok(aggregate)
+    }
+
+    /// Downsample the records between `from` (included) and `to` (excluded), see [`PhysicalDB::downsample`].
+    pub fn downsample(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: chrono::Duration,
+        aggregation: Aggregation,
+        fill: Fill,
+    ) -> Result<Vec<Bucket>, TSLiteError> {
+        let buckets = match Buckets::new(
+            self.db.offset_from_date(from),
+            self.db.offset_from_date(to),
+            interval,
+            self.db.header.precision,
+        )? {
+            Some(buckets) => buckets,
+            None => return Ok(Vec::new()),
+        };
+
+        let origin = self.db.header.origin_date;
+        // Buckets are computed as the records go, so the buffered records must be ordered too.
+        let mut buffered: Vec<RecordInfo<V>> = self.buffered_range(from, to).collect();
+        buffered.sort_by_key(|r| r.time_offset);
+        let records = self
+            .db
+            .iter_range::<V>(from, to)?
+            .map(|res| res.map(|(_, r)| r))
+            .chain(buffered.into_iter().map(Ok));
+        buckets.aggregate(origin, records, aggregation, fill)
+    }
+}
+
+/// The buffer is flushed when the DB is dropped. Errors are ignored, call
+/// [`BufferedDB::flush`] before dropping it to handle them.
+impl<V: Value> Drop for BufferedDB<V> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn flush_on_size() {
+        let path = "buffered_flush_on_size.db";
+        let _ = fs::remove_file(path);
+
+        let db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        let mut buffered: BufferedDB =
+            BufferedDB::new(db, 5, Duration::from_secs(3600)).expect("could not wrap db.");
+        for i in 0..12 {
+            buffered
+                .append_record(RecordInfo {
+                    time_offset: i,
+                    value: i as u8,
+                })
+                .expect("could not append record.");
+        }
+
+        assert_eq!(buffered.db().header.records_number, 10);
+        assert_eq!(buffered.buffered(), 2);
+        assert_eq!(buffered.records_number(), 12);
+        assert_eq!(buffered.read_record(4).map(|r| r.value), Ok(4));
+        assert_eq!(buffered.read_record(11).map(|r| r.value), Ok(11));
+        assert_eq!(buffered.read_record(12), Err(TSLiteError::IndexOutOfBound));
+
+        // The remaining records are written when the DB is dropped.
+        drop(buffered);
+        let db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.records_number, 12);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn flush_on_time() {
+        let path = "buffered_flush_on_time.db";
+        let _ = fs::remove_file(path);
+
+        let db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        let mut buffered: BufferedDB =
+            BufferedDB::new(db, 1000, Duration::from_secs(0)).expect("could not wrap db.");
+        buffered
+            .append_record(RecordInfo {
+                time_offset: 0,
+                value: 1,
+            })
+            .expect("could not append record.");
+        assert_eq!(buffered.buffered(), 0);
+        assert_eq!(buffered.db().header.records_number, 1);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn query_buffer_and_file() {
+        let path = "buffered_query.db";
+        let _ = fs::remove_file(path);
+
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let db =
+            PhysicalDB::create(Path::new(path), Some(origin_date)).expect("could not create db.");
+        let mut buffered: BufferedDB =
+            BufferedDB::new(db, 10, Duration::from_secs(3600)).expect("could not wrap db.");
+        for i in 0..15 {
+            buffered
+                .append_record(RecordInfo {
+                    time_offset: i * 10,
+                    value: i as u8,
+                })
+                .expect("could not append record.");
+        }
+        assert_eq!(buffered.buffered(), 5);
+
+        let from = origin_date + chrono::Duration::seconds(80);
+        let to = origin_date + chrono::Duration::seconds(120);
+        let values: Vec<u8> = buffered
+            .query_range(from, to)
+            .expect("could not query db.")
+            .iter()
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(values, vec![8, 9, 10, 11]);
+
+        let agg = buffered
+            .aggregate(from, to)
+            .expect("could not aggregate db.");
+        assert_eq!(agg.count, 4);
+        assert_eq!(agg.last.map(|r| r.value), Some(11));
+
+        let buckets = buffered
+            .downsample(
+                origin_date,
+                origin_date + chrono::Duration::seconds(150),
+                chrono::Duration::seconds(50),
+                Aggregation::Max,
+                Fill::Null,
+            )
+            .expect("could not downsample db.");
+        let values: Vec<Option<f64>> = buckets.iter().map(|b| b.value).collect();
+        assert_eq!(values, vec![Some(4.0), Some(9.0), Some(14.0)]);
+
+        let res = BufferedDB::<f64>::new(
+            PhysicalDB::new(Path::new(path), None).expect("could not open db."),
+            10,
+            Duration::from_secs(1),
+        );
+        assert_eq!(res.err(), Some(TSLiteError::TypeMismatch));
+
+        drop(buffered);
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,818 @@
+//! Compressed storage of the records.
+//!
+//! Records are grouped in blocks of [`COMPRESSED_BLOCK_RECORDS`] records. In a block, time offsets are
+//! stored as the difference between two consecutive deltas (delta-of-delta), integer values as the
+//! difference with the previous value and floating point values as the XOR with the previous value,
+//! so records taken at a regular interval with slowly changing values only hold small numbers.
+//! Every number is written as a variable length integer (LEB128), signed numbers being zigzag encoded
+//! first. The first record of a block is encoded as if the previous record was zero, so every block can
+//! be decoded on its own.
+//!
+//! A record starts with a variable length integer, its lowest bit telling how to read it:
+//!
+//! - `1`: the other bits are the number of records, minus one, that repeat the previous record:
+//!   same interval, same value. A sensor sampled regularly whose value rarely changes takes about
+//!   one octet for dozens of records.
+//! - `0`: the next 4 bits are the difference of the value and the 2 bits after them the delta-of-delta.
+//!   When they are too big to fit, the bits are all set and the delta-of-delta minus 3, then the
+//!   difference minus 15, follow as other integers. A record taken at a regular interval whose value
+//!   changes a little takes a single octet.
+//!
+//! Blocks have a variable size, so the position and the first time offset of every block is kept in an
+//! index next to the DB file, with the same name followed by `.idx`. Looking for a record or a date is done
+//! with a binary search over the index, then by decoding a single block. The index can always be rebuilt
+//! from the DB file, which is done when the DB is opened if the index does not cover every block.
+//!
+//! ```text
+//! +--------[INDEX ENTRY]--------+
+//! |-[POSITION]-|-[TIME OFFSET]-|
+//! |   64bit    |     64bit     |
+//! +-----------------------------+
+//! ```
+//!
+//! Since the records are only committed once their block and index entry are synced, an interrupted
+//! append is discarded when the DB is opened again, like with the plain storage.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::{DbHeader, PhysicalDB, TSLiteError, ValueType};
+
+/// The number of records in a compressed block. Only the last block of a DB can hold less.
+pub const COMPRESSED_BLOCK_RECORDS: u64 = 128;
+
+/// Write `v` using as few octets as possible, 7 bits per octet, the highest bit telling if more octets follow.
+fn write_varint(store: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        store.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    store.push(v as u8);
+}
+
+/// Read a number written with [`write_varint`], `None` if there is not enough octets.
+fn read_varint(reader: &mut Cursor<&[u8]>) -> Option<u64> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        let octet = reader.read_u8().ok()?;
+        v |= ((octet & 0x7f) as u64) << shift;
+        if octet & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+
+    None
+}
+
+/// Map signed numbers to unsigned ones so that numbers close to zero stay small: 0, -1, 1, -2, ...
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+/// Read the raw octets of a value as a 64bit number.
+/// Integers are sign extended and floating point numbers are kept as their bits.
+fn value_bits(value_type: ValueType, d: &[u8]) -> u64 {
+    let mut reader = Cursor::new(d);
+    match value_type {
+        ValueType::U8 | ValueType::Bool => reader.read_u8().unwrap() as u64,
+        ValueType::U16 => reader.read_u16::<LittleEndian>().unwrap() as u64,
+        ValueType::I32 => reader.read_i32::<LittleEndian>().unwrap() as i64 as u64,
+        ValueType::I64 => reader.read_i64::<LittleEndian>().unwrap() as u64,
+        ValueType::F32 => reader.read_u32::<LittleEndian>().unwrap() as u64,
+        ValueType::F64 => reader.read_u64::<LittleEndian>().unwrap(),
+    }
+}
+
+/// Write back a value read with [`value_bits`].
+fn write_value_bits(value_type: ValueType, bits: u64, store: &mut Vec<u8>) {
+    match value_type {
+        ValueType::U8 | ValueType::Bool => store.write_u8(bits as u8).unwrap(),
+        ValueType::U16 => store.write_u16::<LittleEndian>(bits as u16).unwrap(),
+        ValueType::I32 | ValueType::F32 => store.write_u32::<LittleEndian>(bits as u32).unwrap(),
+        ValueType::I64 | ValueType::F64 => store.write_u64::<LittleEndian>(bits).unwrap(),
+    }
+}
+
+/// The biggest difference of values stored within the first integer of a record.
+const PACKED_DIFF_MAX: u64 = 15;
+/// The biggest delta-of-delta stored within the first integer of a record.
+const PACKED_DOD_MAX: u64 = 3;
+
+/// The state of the encoding within a block: the time offset, delta and value of the previous record,
+/// and the repeated records not written yet, or not read yet, see the module documentation.
+#[derive(Debug, Copy, Clone, Default)]
+struct Codec {
+    offset: i64,
+    delta: i64,
+    value: u64,
+    count: u64,
+    repeats: u64,
+}
+
+impl Codec {
+    fn is_float(value_type: ValueType) -> bool {
+        value_type == ValueType::F32 || value_type == ValueType::F64
+    }
+
+    fn update(&mut self, offset: i64, delta: i64, value: u64) {
+        // The delta of the first record is its time offset, it says nothing about the next one.
+        self.delta = if self.count == 0 { 0 } else { delta };
+        self.offset = offset;
+        self.value = value;
+        self.count += 1;
+    }
+
+    /// Encode the raw octets of a record at the end of `store`. Repeated records are only written
+    /// by [`Codec::flush`], which must be called once the records are encoded.
+    fn encode(&mut self, header: &DbHeader, record: &[u8], store: &mut Vec<u8>) {
+        let offset = header.raw_time_offset(record);
+        let value = value_bits(header.value_type, &record[header.offset_size() as usize..]);
+
+        let delta = offset.wrapping_sub(self.offset);
+        let dod = zigzag(delta.wrapping_sub(self.delta));
+        let diff = if Codec::is_float(header.value_type) {
+            value ^ self.value
+        } else {
+            zigzag((value as i64).wrapping_sub(self.value as i64))
+        };
+        if self.count > 0 && dod == 0 && diff == 0 {
+            self.repeats += 1;
+        } else {
+            self.flush(store);
+            let packed_dod = dod.min(PACKED_DOD_MAX);
+            let packed_diff = diff.min(PACKED_DIFF_MAX);
+            write_varint(store, (packed_dod << 5) | (packed_diff << 1));
+            if packed_dod == PACKED_DOD_MAX {
+                write_varint(store, dod - PACKED_DOD_MAX);
+            }
+            if packed_diff == PACKED_DIFF_MAX {
+                write_varint(store, diff - PACKED_DIFF_MAX);
+            }
+        }
+        self.update(offset, delta, value);
+    }
+
+    /// Write the repeated records that are not written yet at the end of `store`.
+    fn flush(&mut self, store: &mut Vec<u8>) {
+        if self.repeats > 0 {
+            write_varint(store, ((self.repeats - 1) << 1) | 1);
+            self.repeats = 0;
+        }
+    }
+
+    /// Decode the next record of a block as raw octets, `None` if there is not enough octets.
+    fn decode(&mut self, header: &DbHeader, reader: &mut Cursor<&[u8]>) -> Option<Vec<u8>> {
+        let (delta, value) = if self.repeats > 0 {
+            self.repeats -= 1;
+            (self.delta, self.value)
+        } else {
+            let head = read_varint(reader)?;
+            if head & 1 == 1 {
+                self.repeats = head >> 1;
+                (self.delta, self.value)
+            } else {
+                let mut dod = head >> 5;
+                if dod == PACKED_DOD_MAX {
+                    dod = dod.wrapping_add(read_varint(reader)?);
+                }
+                let delta = unzigzag(dod).wrapping_add(self.delta);
+                let mut diff = (head >> 1) & PACKED_DIFF_MAX;
+                if diff == PACKED_DIFF_MAX {
+                    diff = diff.wrapping_add(read_varint(reader)?);
+                }
+                let value = if Codec::is_float(header.value_type) {
+                    diff ^ self.value
+                } else {
+                    (self.value as i64).wrapping_add(unzigzag(diff)) as u64
+                };
+                (delta, value)
+            }
+        };
+        let offset = self.offset.wrapping_add(delta);
+        self.update(offset, delta, value);
+
+        let mut store: Vec<u8> = Vec::with_capacity(header.value_type.size() as usize);
+        write_value_bits(header.value_type, value, &mut store);
+        Some(header.raw_record(offset, &store))
+    }
+}
+
+/// The position of a block in the DB file and the time offset of its first record.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct BlockEntry {
+    pos: u64,
+    first_time_offset: i64,
+}
+
+impl BlockEntry {
+    /// The size of an entry: 8 for the position, 8 for the time offset.
+    const SIZE: u64 = 8 + 8;
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut store: Vec<u8> = Vec::with_capacity(BlockEntry::SIZE as usize);
+        store.write_u64::<LittleEndian>(self.pos).unwrap();
+        store
+            .write_i64::<LittleEndian>(self.first_time_offset)
+            .unwrap();
+        store
+    }
+
+    fn from_bytes(d: &[u8]) -> BlockEntry {
+        let mut reader = Cursor::new(d);
+        BlockEntry {
+            pos: reader.read_u64::<LittleEndian>().unwrap(),
+            first_time_offset: reader.read_i64::<LittleEndian>().unwrap(),
+        }
+    }
+}
+
+/// A block decoded from the file.
+/// `records` can hold less records than expected if the block is truncated or corrupted.
+struct DecodedBlock {
+    records: Vec<Vec<u8>>,
+    codec: Codec,
+    end: u64,
+}
+
+/// Read `count` entries of an index starting at the entry `first`. Less entries are returned
+/// if the index is too short.
+fn read_entries(mut index: &File, first: u64, count: u64) -> Result<Vec<BlockEntry>, TSLiteError> {
+    index
+        .seek(SeekFrom::Start(first * BlockEntry::SIZE))
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    let mut data = Vec::with_capacity((count * BlockEntry::SIZE) as usize);
+    index
+        .take(count * BlockEntry::SIZE)
+        .read_to_end(&mut data)
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+    Ok(data
+        .chunks_exact(BlockEntry::SIZE as usize)
+        .map(BlockEntry::from_bytes)
+        .collect())
+}
+
+fn read_entry(index: &File, block: u64) -> Result<BlockEntry, TSLiteError> {
+    read_entries(index, block, 1)?.pop().ok_or_else(|| {
+        TSLiteError::IOError("Could not read block index: not enough octets.".to_string())
+    })
+}
+
+impl PhysicalDB {
+    /// The path of the index of a compressed DB: the path of the DB followed by `.idx`.
+    pub fn index_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".idx");
+        PathBuf::from(path)
+    }
+
+    pub(crate) fn open_index(&self) -> Result<File, TSLiteError> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.index_path())
+            .map_err(|e| TSLiteError::IOError(e.to_string()))
+    }
+
+    /// Decode the block `block`, whose entry must be in the index.
+    fn decode_block(&mut self, index: &File, block: u64) -> Result<DecodedBlock, TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let entries = read_entries(index, block, 2)?;
+        let entry = entries.first().ok_or_else(|| {
+            TSLiteError::IOError("Could not read block index: not enough octets.".to_string())
+        })?;
+        let mut fref = self.file.as_ref().unwrap();
+        fref.seek(SeekFrom::Start(entry.pos))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let mut data = Vec::new();
+        match entries.get(1) {
+            Some(next) => fref
+                .take(next.pos.saturating_sub(entry.pos))
+                .read_to_end(&mut data),
+            None => fref.read_to_end(&mut data),
+        }
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        let count = COMPRESSED_BLOCK_RECORDS.min(
+            self.header
+                .records_number
+                .saturating_sub(block * COMPRESSED_BLOCK_RECORDS),
+        );
+        let mut decoded = DecodedBlock {
+            records: Vec::with_capacity(count as usize),
+            codec: Codec::default(),
+            end: entry.pos,
+        };
+        let mut reader = Cursor::new(&data[..]);
+        for _ in 0..count {
+            match decoded.codec.decode(&self.header, &mut reader) {
+                Some(record) => decoded.records.push(record),
+                None => break,
+            }
+        }
+        decoded.end = entry.pos + reader.position();
+
+        Ok(decoded)
+    }
+
+    /// Read the raw octets of the records in `[start, start + count)` of a compressed DB.
+    /// Records beyond the number of records of the DB are not returned, and the data stops
+    /// at the first record that cannot be decoded.
+    pub(crate) fn read_compressed(
+        &mut self,
+        start: u64,
+        count: u64,
+    ) -> Result<Vec<u8>, TSLiteError> {
+        let end = (start + count).min(self.header.records_number);
+        let mut data = Vec::new();
+        if start >= end {
+            return Ok(data);
+        }
+
+        let index = self.open_index()?;
+        for block in start / COMPRESSED_BLOCK_RECORDS..=(end - 1) / COMPRESSED_BLOCK_RECORDS {
+            let decoded = self.decode_block(&index, block)?;
+            let first = block * COMPRESSED_BLOCK_RECORDS;
+            let complete =
+                first + decoded.records.len() as u64 >= end.min(first + COMPRESSED_BLOCK_RECORDS);
+            for (i, record) in decoded.records.iter().enumerate() {
+                let rec_id = first + i as u64;
+                if start <= rec_id && rec_id < end {
+                    data.extend(record);
+                }
+            }
+            if !complete {
+                break;
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// The position in the file of the block holding the record `rec_id` of a compressed DB,
+    /// or the end of the file if the block is not in the index.
+    pub(crate) fn compressed_record_pos(&self, rec_id: u64) -> Result<u64, TSLiteError> {
+        let index = self.open_index()?;
+        match read_entries(&index, rec_id / COMPRESSED_BLOCK_RECORDS, 1)?.pop() {
+            Some(entry) => Ok(entry.pos),
+            None => Ok(self
+                .file
+                .as_ref()
+                .unwrap()
+                .metadata()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?
+                .len()),
+        }
+    }
+
+    /// Find the index of the first record with a time offset greater or equal to `offset` in a
+    /// compressed DB. The block is found with a binary search over the index, then decoded.
+    pub(crate) fn search_compressed(&mut self, offset: i64) -> Result<u64, TSLiteError> {
+        let index = self.open_index()?;
+        let blocks = self
+            .header
+            .records_number
+            .div_ceil(COMPRESSED_BLOCK_RECORDS);
+
+        // Find the first block starting at or after `offset`, the record is either its first one
+        // or in the block before it.
+        let mut low = 0;
+        let mut high = blocks;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if read_entry(&index, mid)?.first_time_offset < offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low == 0 {
+            return Ok(0);
+        }
+
+        let block = low - 1;
+        let decoded = self.decode_block(&index, block)?;
+        let header = self.header;
+        let pos = decoded
+            .records
+            .iter()
+            .position(|r| header.raw_time_offset(r) >= offset)
+            .unwrap_or(decoded.records.len());
+        Ok(block * COMPRESSED_BLOCK_RECORDS + pos as u64)
+    }
+
+    /// Append the raw octets of records to a compressed DB, then commit them.
+    /// The last block is completed before starting new ones.
+    pub(crate) fn append_compressed(&mut self, records: &[Vec<u8>]) -> Result<(), TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let index = self.open_index()?;
+        let records_number = self.header.records_number;
+        let (mut codec, end) = if records_number == 0 {
+            (Codec::default(), self.header.header_len as u64)
+        } else {
+            let last = (records_number - 1) / COMPRESSED_BLOCK_RECORDS;
+            let decoded = self.decode_block(&index, last)?;
+            if last * COMPRESSED_BLOCK_RECORDS + (decoded.records.len() as u64) < records_number {
+                return Err(TSLiteError::IOError(
+                    "Could not read record: not enough octets.".to_string(),
+                ));
+            }
+            (decoded.codec, decoded.end)
+        };
+
+        let mut store: Vec<u8> = Vec::new();
+        let mut entries: Vec<u8> = Vec::new();
+        for (i, record) in records.iter().enumerate() {
+            if (records_number + i as u64) % COMPRESSED_BLOCK_RECORDS == 0 {
+                codec.flush(&mut store);
+                codec = Codec::default();
+                let entry = BlockEntry {
+                    pos: end + store.len() as u64,
+                    first_time_offset: self.header.raw_time_offset(record),
+                };
+                entries.extend(entry.as_bytes());
+            }
+            codec.encode(&self.header, record, &mut store);
+        }
+        codec.flush(&mut store);
+
+        let mut fref = self.file.as_ref().unwrap();
+        fref.seek(SeekFrom::Start(end))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        fref.write_all(&store)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        fref.sync_data()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let mut iref = &index;
+        iref.seek(SeekFrom::Start(
+            records_number.div_ceil(COMPRESSED_BLOCK_RECORDS) * BlockEntry::SIZE,
+        ))
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        iref.write_all(&entries)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        iref.sync_data()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        self.commit_records_number(records_number + records.len() as u64)
+    }
+
+    /// Bring a compressed DB back to the last committed state, see [`PhysicalDB::recover`].
+    /// The index is rebuilt first if it does not cover every committed block.
+    pub(crate) fn recover_compressed(&mut self) -> Result<(), TSLiteError> {
+        let index = self.open_index()?;
+        let blocks = self
+            .header
+            .records_number
+            .div_ceil(COMPRESSED_BLOCK_RECORDS);
+        let index_len = index
+            .metadata()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?
+            .len();
+        if index_len < blocks * BlockEntry::SIZE {
+            self.rebuild_index(&index)?;
+            let index_len = index
+                .metadata()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?
+                .len();
+            // Some records cannot be decoded, everything is kept so it can still be looked at.
+            if index_len < blocks * BlockEntry::SIZE {
+                return Ok(());
+            }
+        }
+        index
+            .set_len(blocks * BlockEntry::SIZE)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        index
+            .sync_all()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        if blocks == 0 {
+            return self.truncate_records(self.header.header_len as u64);
+        }
+
+        // If the last block cannot be decoded, nothing is discarded so it can still be looked at.
+        let last = blocks - 1;
+        let decoded = self.decode_block(&index, last)?;
+        if last * COMPRESSED_BLOCK_RECORDS + (decoded.records.len() as u64)
+            < self.header.records_number
+        {
+            return Ok(());
+        }
+        self.truncate_records(decoded.end)
+    }
+
+    /// Rebuild the index of a compressed DB by decoding its committed records.
+    /// The index stops at the first record that cannot be decoded.
+    fn rebuild_index(&mut self, mut index: &File) -> Result<(), TSLiteError> {
+        let mut fref = self.file.as_ref().unwrap();
+        fref.seek(SeekFrom::Start(self.header.header_len as u64))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let mut data = Vec::new();
+        fref.read_to_end(&mut data)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        let mut entries: Vec<u8> = Vec::new();
+        let mut reader = Cursor::new(&data[..]);
+        let mut codec = Codec::default();
+        for rec_id in 0..self.header.records_number {
+            let pos = self.header.header_len as u64 + reader.position();
+            if rec_id % COMPRESSED_BLOCK_RECORDS == 0 {
+                codec = Codec::default();
+            }
+            let record = match codec.decode(&self.header, &mut reader) {
+                Some(record) => record,
+                None => break,
+            };
+            if rec_id % COMPRESSED_BLOCK_RECORDS == 0 {
+                let entry = BlockEntry {
+                    pos,
+                    first_time_offset: self.header.raw_time_offset(&record),
+                };
+                entries.extend(entry.as_bytes());
+            }
+        }
+
+        index
+            .set_len(0)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        index
+            .seek(SeekFrom::Start(0))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        index
+            .write_all(&entries)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbIssue, DbOptions, RecordInfo, Storage, Timestamp};
+    use chrono::{TimeZone, Utc};
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::path::Path;
+
+    fn compressed_options(value_type: ValueType) -> DbOptions {
+        DbOptions {
+            value_type,
+            storage: Storage::Compressed,
+            ..DbOptions::default()
+        }
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for v in &[0i64, 1, -1, 63, -64, 64, 1 << 40, i64::MAX, i64::MIN] {
+            let mut store = Vec::new();
+            write_varint(&mut store, zigzag(*v));
+            let mut reader = Cursor::new(&store[..]);
+            assert_eq!(read_varint(&mut reader).map(unzigzag), Some(*v));
+        }
+        assert_eq!(read_varint(&mut Cursor::new(&[0x80u8][..])), None);
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let options = DbOptions {
+            wide_offsets: true,
+            ..compressed_options(ValueType::I64)
+        };
+        let header = DbHeader::new(
+            Timestamp::try_from(Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap()).unwrap(),
+            &options,
+        );
+        let values = [
+            (i64::MIN, 0i64),
+            (0, 0),
+            (10, 0),
+            (20, 0),
+            (30, 7),
+            (31, -8),
+            (i64::MAX, i64::MAX),
+        ];
+        let records: Vec<Vec<u8>> = values
+            .iter()
+            .map(|(offset, value)| header.raw_record(*offset, &value.to_le_bytes()))
+            .collect();
+
+        let mut codec = Codec::default();
+        let mut store = Vec::new();
+        for record in &records {
+            codec.encode(&header, record, &mut store);
+        }
+        codec.flush(&mut store);
+
+        let mut codec = Codec::default();
+        let mut reader = Cursor::new(&store[..]);
+        for record in &records {
+            assert_eq!(codec.decode(&header, &mut reader).as_ref(), Some(record));
+        }
+        assert_eq!(reader.position(), store.len() as u64);
+    }
+
+    #[test]
+    fn compressed_series() {
+        let path = "compressed_series.db";
+        let plain_path = "compressed_series_plain.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db = PhysicalDB::create_with_options(
+            Path::new(path),
+            Some(origin_date),
+            &compressed_options(ValueType::U16),
+        )
+        .expect("could not create db.");
+        let mut plain =
+            PhysicalDB::create_with_type(Path::new(plain_path), Some(origin_date), ValueType::U16)
+                .expect("could not create db.");
+
+        // A sample every 10 seconds, appended in batches that do not match the blocks.
+        let records: Vec<RecordInfo<u16>> = (0..1000)
+            .map(|i| RecordInfo {
+                time_offset: i * 10,
+                value: 500 + (i % 7) as u16,
+            })
+            .collect();
+        for batch in records.chunks(300) {
+            db.append_records(batch).expect("could not append records.");
+            plain
+                .append_records(batch)
+                .expect("could not append records.");
+        }
+        db.append_record(RecordInfo {
+            time_offset: 20_000,
+            value: 0u16,
+        })
+        .expect("could not append record.");
+        db.close().expect("could not close db.");
+
+        let size = fs::metadata(path).unwrap().len();
+        let plain_size = fs::metadata(plain_path).unwrap().len();
+        assert!(size * 2 < plain_size);
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.storage, Storage::Compressed);
+        assert_eq!(db.header.records_number, 1001);
+        assert_eq!(db.read_record(500), Ok(records[500]));
+        assert_eq!(db.read_record::<u16>(1000).map(|r| r.value), Ok(0));
+        assert_eq!(
+            db.read_record::<u16>(1001),
+            Err(TSLiteError::IndexOutOfBound)
+        );
+
+        let read: Vec<RecordInfo<u16>> = db
+            .iter::<u16>()
+            .expect("could not iterate db.")
+            .rev()
+            .skip(1)
+            .map(|res| res.expect("could not read record.").1)
+            .collect();
+        assert_eq!(read.len(), 1000);
+        assert!(read.iter().rev().eq(records.iter()));
+
+        let from = origin_date + chrono::Duration::seconds(1275);
+        let to = origin_date + chrono::Duration::seconds(1300);
+        let offsets: Vec<i64> = db
+            .query_range::<u16>(from, to)
+            .expect("could not query db.")
+            .iter()
+            .map(|r| r.time_offset)
+            .collect();
+        assert_eq!(offsets, vec![1280, 1290]);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert_eq!(
+            db.update_record(0, 1u16),
+            Err(TSLiteError::UnsupportedOperation)
+        );
+
+        // The index is rebuilt if it is lost.
+        db.close().expect("could not close db.");
+        fs::remove_file(db.index_path()).unwrap();
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.read_record(999), Ok(records[999]));
+        assert!(db.verify().expect("could not verify db.").is_healthy());
+
+        let _ = fs::remove_file(db.index_path());
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(plain_path);
+    }
+
+    #[test]
+    fn compression_ratio() {
+        let path = "compression_ratio.db";
+        let plain_path = "compression_ratio_plain.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db = PhysicalDB::create_with_options(
+            Path::new(path),
+            Some(origin_date),
+            &compressed_options(ValueType::U8),
+        )
+        .expect("could not create db.");
+        let mut plain = PhysicalDB::create(Path::new(plain_path), Some(origin_date))
+            .expect("could not create db.");
+
+        // A temperature sampled every 30 seconds, a sample being late now and then,
+        // drifting by a degree every few minutes and appended as it is sampled.
+        let records: Vec<RecordInfo<u8>> = (0..10_000)
+            .map(|i| RecordInfo {
+                time_offset: i * 30 + (i % 97 == 0) as i64,
+                value: 18 + [0, 1, 2, 3, 2, 1][(i / 13 % 6) as usize],
+            })
+            .collect();
+        for batch in records.chunks(10) {
+            db.append_records(batch).expect("could not append records.");
+            plain
+                .append_records(batch)
+                .expect("could not append records.");
+        }
+
+        let size = fs::metadata(path).unwrap().len() + fs::metadata(db.index_path()).unwrap().len();
+        let plain_size = fs::metadata(plain_path).unwrap().len();
+        assert!(size * 5 <= plain_size, "{} octets for {}", size, plain_size);
+
+        let read: Vec<RecordInfo<u8>> = db
+            .iter::<u8>()
+            .expect("could not iterate db.")
+            .map(|res| res.expect("could not read record.").1)
+            .collect();
+        assert_eq!(read, records);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+        let _ = fs::remove_file(db.index_path());
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(plain_path);
+    }
+
+    #[test]
+    fn compressed_recover_and_reorder() {
+        let path = "compressed_recover_and_reorder.db";
+        let mut db = PhysicalDB::create_with_options(
+            Path::new(path),
+            None,
+            &compressed_options(ValueType::F64),
+        )
+        .expect("could not create db.");
+        for i in 0..200 {
+            db.append_record(RecordInfo {
+                time_offset: 199 - i,
+                value: i as f64 / 4.0,
+            })
+            .expect("could not append record.");
+        }
+
+        // An append interrupted before its commit.
+        let index_path = db.index_path();
+        let mut data = fs::read(path).unwrap();
+        data.extend(&[0xff; 17]);
+        fs::write(path, data).unwrap();
+        let mut index = fs::read(&index_path).unwrap();
+        index.extend(&[0x42; 16]);
+        fs::write(&index_path, index).unwrap();
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.records_number, 200);
+        assert_eq!(
+            fs::metadata(&index_path).unwrap().len(),
+            2 * BlockEntry::SIZE
+        );
+        assert_eq!(db.check_db_file(), Ok(DbIssue::UnorderedRecord));
+
+        db.reorder_record().expect("could not reorder db.");
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        let values: Vec<f64> = db
+            .iter::<f64>()
+            .expect("could not iterate db.")
+            .map(|res| res.expect("could not read record.").1.value)
+            .collect();
+        assert_eq!(values.len(), 200);
+        assert_eq!(values[0], 199.0 / 4.0);
+        assert_eq!(values[199], 0.0);
+
+        let options = DbOptions {
+            checksums: true,
+            ..compressed_options(ValueType::F64)
+        };
+        assert_eq!(
+            PhysicalDB::create_with_options(Path::new(path), None, &options).err(),
+            Some(TSLiteError::IncompatibleOptions)
+        );
+
+        let _ = fs::remove_file(index_path);
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,568 @@
+//! Many named series kept together in a directory.
+//!
+//! A [`Database`] owns a directory holding one DB file per series, named after the series like
+//! `temperature.db`, and a catalog file named `catalog` recording the name, origin date and options
+//! of every series.
+//!
+//! ```text
+//! +----------------------------[CATALOG]-----------------------------+
+//! |-[MAGIC]-|-[VERSION]-|-[SERIES COUNT]-|-[SERIES]-|-...-|-[CHECKSUM]-|
+//! | "TSLC"  |   16bit   |      32bit     |          |     |    32bit   |
+//! +-------------------------------------------------------------------+
+//! ```
+//!
+//! ```text
+//! +---------------------------------------[SERIES]----------------------------------------+
+//! |-[NAME LENGTH]-|-[NAME]-|-[TIMESTAMP]-|-[VALUE TYPE]-|-[FLAGS]-|-[PRECISION]-|-[STORAGE]-|-[RETENTION]-|-[CAPACITY]-|
+//! |     16bit     |        |    56bit    |     8bit     |   8bit  |     8bit    |    8bit   |  2 x 64bit  |    64bit   |
+//! +---------------------------------------------------------------------------------------+
+//! ```
+//!
+//! The fields of a series are encoded like in the header of its DB file, and the checksum is the CRC32
+//! of everything before it. The catalog is written to a temporary file which then replaces the previous
+//! catalog, so it is either fully updated or not at all. A series is created before being added to the
+//! catalog and removed from the catalog before its file is deleted, so a crash can at worst leave a file
+//! that is not in the catalog, which is overwritten if a series with the same name is created.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    replace_file, Aggregate, Aggregation, Bucket, DbHeader, DbOptions, Fill, PhysicalDB, Precision,
+    RecordInfo, Retention, Storage, TSLiteError, Timestamp, Value, ValueType,
+};
+
+/// What the catalog of a [`Database`] records about a series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesInfo {
+    pub name: String,
+    pub origin_date: Timestamp,
+    pub options: DbOptions,
+}
+
+impl SeriesInfo {
+    fn write_to(&self, store: &mut Vec<u8>) {
+        let options = &self.options;
+        store
+            .write_u16::<LittleEndian>(self.name.len() as u16)
+            .unwrap();
+        store.extend(self.name.as_bytes());
+        store.extend(self.origin_date.as_bytes());
+        store.write_u8(options.value_type.code()).unwrap();
+        let mut flags = 0;
+        if options.checksums {
+            flags |= DbHeader::FLAG_CHECKSUMS;
+        }
+        if options.wide_offsets {
+            flags |= DbHeader::FLAG_WIDE_OFFSETS;
+        }
+        store.write_u8(flags).unwrap();
+        store.write_u8(options.precision.code()).unwrap();
+        store.write_u8(options.storage.code()).unwrap();
+        store.extend(options.retention.as_bytes(options.precision));
+        store
+            .write_u64::<LittleEndian>(options.capacity.unwrap_or(0))
+            .unwrap();
+    }
+
+    fn read_from(reader: &mut Cursor<&[u8]>) -> Result<SeriesInfo, TSLiteError> {
+        let corrupted = |_| TSLiteError::IOError("Catalog is corrupted.".to_string());
+        let name_len = reader.read_u16::<LittleEndian>().map_err(corrupted)?;
+        let mut name = vec![0; name_len as usize];
+        reader.read_exact(&mut name).map_err(corrupted)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| TSLiteError::IOError("Catalog is corrupted.".to_string()))?;
+        let mut date = [0; 7];
+        reader.read_exact(&mut date).map_err(corrupted)?;
+        let mut fields = [0; 4];
+        reader.read_exact(&mut fields).map_err(corrupted)?;
+        let precision = Precision::try_from(fields[2])?;
+        let mut retention = [0; Retention::SIZE as usize];
+        reader.read_exact(&mut retention).map_err(corrupted)?;
+        let capacity = reader.read_u64::<LittleEndian>().map_err(corrupted)?;
+
+        Ok(SeriesInfo {
+            name,
+            origin_date: Timestamp::from(&date[..]),
+            options: DbOptions {
+                value_type: ValueType::try_from(fields[0])?,
+                checksums: fields[1] & DbHeader::FLAG_CHECKSUMS != 0,
+                wide_offsets: fields[1] & DbHeader::FLAG_WIDE_OFFSETS != 0,
+                precision,
+                storage: Storage::try_from(fields[3])?,
+                retention: Retention::from_bytes(&retention, precision),
+                capacity: Some(capacity).filter(|c| *c > 0),
+            },
+        })
+    }
+}
+
+/// A directory of named series, see the module documentation.
+/// Series are opened the first time they are used, and stay open until the database is closed.
+#[derive(Debug)]
+pub struct Database {
+    dir: PathBuf,
+    catalog: BTreeMap<String, SeriesInfo>,
+    open: HashMap<String, PhysicalDB>,
+}
+
+impl Database {
+    /// The magic bytes the catalog starts with.
+    const MAGIC: [u8; 4] = *b"TSLC";
+    /// The version of the format of the catalog.
+    const CATALOG_VERSION: u16 = 1;
+
+    /// Open the database in the directory `dir`, creating the directory and an empty catalog
+    /// if they do not exist.
+    pub fn open(dir: &Path) -> Result<Database, TSLiteError> {
+        fs::create_dir_all(dir).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let mut db = Database {
+            dir: PathBuf::from(dir),
+            catalog: BTreeMap::new(),
+            open: HashMap::new(),
+        };
+
+        let path = db.catalog_path();
+        if !path.exists() {
+            db.write_catalog()?;
+            return Ok(db);
+        }
+
+        let mut data = Vec::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        for info in Database::parse_catalog(&data)? {
+            db.catalog.insert(info.name.clone(), info);
+        }
+
+        Ok(db)
+    }
+
+    fn catalog_path(&self) -> PathBuf {
+        self.dir.join("catalog")
+    }
+
+    fn series_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.db", name))
+    }
+
+    fn parse_catalog(data: &[u8]) -> Result<Vec<SeriesInfo>, TSLiteError> {
+        if data.len() < 4 + 2 + 4 + 4 || data[0..4] != Database::MAGIC {
+            return Err(TSLiteError::IOError("Catalog is corrupted.".to_string()));
+        }
+        let (content, checksum) = data.split_at(data.len() - 4);
+        if Cursor::new(checksum).read_u32::<LittleEndian>().unwrap() != crc32fast::hash(content) {
+            return Err(TSLiteError::IOError("Catalog is corrupted.".to_string()));
+        }
+
+        let mut reader = Cursor::new(content);
+        reader.set_position(4);
+        let version = reader.read_u16::<LittleEndian>().unwrap();
+        if version > Database::CATALOG_VERSION {
+            return Err(TSLiteError::UnsupportedVersion(version));
+        }
+        let count = reader.read_u32::<LittleEndian>().unwrap();
+        (0..count)
+            .map(|_| SeriesInfo::read_from(&mut reader))
+            .collect()
+    }
+
+    /// Write the whole catalog to a temporary file, then replace the catalog with it.
+    fn write_catalog(&self) -> Result<(), TSLiteError> {
+        let mut store: Vec<u8> = Vec::new();
+        store.extend(&Database::MAGIC);
+        store
+            .write_u16::<LittleEndian>(Database::CATALOG_VERSION)
+            .unwrap();
+        store
+            .write_u32::<LittleEndian>(self.catalog.len() as u32)
+            .unwrap();
+        for info in self.catalog.values() {
+            info.write_to(&mut store);
+        }
+        let checksum = crc32fast::hash(&store);
+        store.write_u32::<LittleEndian>(checksum).unwrap();
+
+        let tmp_path = self.dir.join("catalog.tmp");
+        let mut file = File::create(&tmp_path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        file.write_all(&store)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        file.sync_all()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        replace_file(&tmp_path, &self.catalog_path())
+    }
+
+    /// Check that a series name can be used as a file name.
+    fn check_name(name: &str) -> Result<(), TSLiteError> {
+        let valid = !name.is_empty()
+            && name.len() <= u16::MAX as usize
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+        if !valid {
+            return Err(TSLiteError::InvalidSeriesName(name.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Every series of the catalog, sorted by name.
+    pub fn series(&self) -> impl Iterator<Item = &SeriesInfo> {
+        self.catalog.values()
+    }
+
+    /// What the catalog records about the series `name`, if it exists.
+    pub fn series_info(&self, name: &str) -> Option<&SeriesInfo> {
+        self.catalog.get(name)
+    }
+
+    /// Create the series `name` and add it to the catalog, see [`PhysicalDB::create_with_options`].
+    pub fn create_series(
+        &mut self,
+        name: &str,
+        origin_date: Option<DateTime<Utc>>,
+        options: &DbOptions,
+    ) -> Result<(), TSLiteError> {
+        Database::check_name(name)?;
+        if self.catalog.contains_key(name) {
+            return Err(TSLiteError::SeriesAlreadyExists(name.to_string()));
+        }
+
+        let db = PhysicalDB::create_with_options(&self.series_path(name), origin_date, options)?;
+        let info = SeriesInfo {
+            name: name.to_string(),
+            origin_date: db.header.origin_date,
+            options: db.header.options(),
+        };
+        self.catalog.insert(name.to_string(), info);
+        if let Err(e) = self.write_catalog() {
+            self.catalog.remove(name);
+            return Err(e);
+        }
+        self.open.insert(name.to_string(), db);
+
+        Ok(())
+    }
+
+    /// Remove the series `name` from the catalog and delete its file, along with its side files.
+    /// A series whose file is already gone is only removed from the catalog.
+    pub fn drop_series(&mut self, name: &str) -> Result<(), TSLiteError> {
+        let info = self
+            .catalog
+            .remove(name)
+            .ok_or_else(|| TSLiteError::UnknownSeries(name.to_string()))?;
+        if let Err(e) = self.write_catalog() {
+            self.catalog.insert(name.to_string(), info);
+            return Err(e);
+        }
+
+        if let Some(mut db) = self.open.remove(name) {
+            db.close()?;
+        }
+        let path = self.series_path(name);
+        for extension in &[".idx", ".ovf", ".jrn", ".del"] {
+            let mut side_path = path.clone().into_os_string();
+            side_path.push(extension);
+            let _ = fs::remove_file(side_path);
+        }
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(TSLiteError::IOError(e.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The DB of the series `name`, opened if needed. The operations changing the origin date
+    /// or the options of the DB go through the `Database`, which keeps the catalog in sync.
+    pub(crate) fn series_db(&mut self, name: &str) -> Result<&mut PhysicalDB, TSLiteError> {
+        if !self.catalog.contains_key(name) {
+            return Err(TSLiteError::UnknownSeries(name.to_string()));
+        }
+        if !self.open.contains_key(name) {
+            let path = self.series_path(name);
+            if !path.exists() {
+                return Err(TSLiteError::IOError(format!(
+                    "The file of the series {} is missing.",
+                    name
+                )));
+            }
+            let db = PhysicalDB::new(&path, None)?;
+            self.open.insert(name.to_string(), db);
+        }
+
+        Ok(self.open.get_mut(name).unwrap())
+    }
+
+    /// Record in the catalog the origin date and the options of the series `name`,
+    /// which change when its retention policy is set or enforced.
+    fn sync_catalog(&mut self, name: &str) -> Result<(), TSLiteError> {
+        let header = self.series_db(name)?.header;
+        let info = self.catalog.get_mut(name).unwrap();
+        if info.origin_date != header.origin_date || info.options != header.options() {
+            info.origin_date = header.origin_date;
+            info.options = header.options();
+            self.write_catalog()?;
+        }
+
+        Ok(())
+    }
+
+    /// Append a record to the series `name`, see [`PhysicalDB::append_record`].
+    pub fn append_record<V: Value>(
+        &mut self,
+        name: &str,
+        rec_nfo: RecordInfo<V>,
+    ) -> Result<(), TSLiteError> {
+        self.append_records(name, &[rec_nfo])
+    }
+
+    /// Append several records to the series `name`, see [`PhysicalDB::append_records`].
+    pub fn append_records<V: Value>(
+        &mut self,
+        name: &str,
+        records: &[RecordInfo<V>],
+    ) -> Result<(), TSLiteError> {
+        self.series_db(name)?.append_records(records)?;
+        self.sync_catalog(name)
+    }
+
+    /// Change the retention policy of the series `name`, see [`PhysicalDB::set_retention`].
+    pub fn set_retention(&mut self, name: &str, retention: Retention) -> Result<(), TSLiteError> {
+        self.series_db(name)?.set_retention(retention)?;
+        self.sync_catalog(name)
+    }
+
+    /// Remove the leading records of the series `name` that do not follow its retention policy,
+    /// see [`PhysicalDB::enforce_retention`].
+    pub fn enforce_retention(&mut self, name: &str) -> Result<u64, TSLiteError> {
+        let removed = self.series_db(name)?.enforce_retention()?;
+        self.sync_catalog(name)?;
+
+        Ok(removed)
+    }
+
+    /// Read a record of the series `name`, see [`PhysicalDB::read_record`].
+    pub fn read_record<V: Value>(
+        &mut self,
+        name: &str,
+        rec_id: u64,
+    ) -> Result<RecordInfo<V>, TSLiteError> {
+        self.series_db(name)?.read_record(rec_id)
+    }
+
+    /// Return the records of the series `name` between `from` (included) and `to` (excluded),
+    /// see [`PhysicalDB::query_range`].
+    pub fn query_range<V: Value>(
+        &mut self,
+        name: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<RecordInfo<V>>, TSLiteError> {
+        self.series_db(name)?.query_range(from, to)
+    }
+
+    /// Compute the statistics of the records of the series `name` between `from` (included)
+    /// and `to` (excluded), see [`PhysicalDB::aggregate`].
+    pub fn aggregate<V: Value>(
+        &mut self,
+        name: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Aggregate<V>, TSLiteError> {
+        self.series_db(name)?.aggregate(from, to)
+    }
+
+    /// Downsample the records of the series `name` between `from` (included) and `to` (excluded),
+    /// see [`PhysicalDB::downsample`].
+    pub fn downsample<V: Value>(
+        &mut self,
+        name: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: chrono::Duration,
+        aggregation: Aggregation,
+        fill: Fill,
+    ) -> Result<Vec<Bucket>, TSLiteError> {
+        self.series_db(name)?
+            .downsample::<V>(from, to, interval, aggregation, fill)
+    }
+
+    /// Close every open series.
+    pub fn close(&mut self) -> Result<(), TSLiteError> {
+        for db in self.open.values_mut() {
+            db.close()?;
+        }
+        self.open.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn database_catalog() {
+        let dir = "database_catalog";
+        let _ = fs::remove_dir_all(dir);
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db = Database::open(Path::new(dir)).expect("could not open database.");
+        assert_eq!(db.series().count(), 0);
+
+        let temperature = DbOptions {
+            value_type: ValueType::F32,
+            precision: Precision::Milliseconds,
+            ..DbOptions::default()
+        };
+        db.create_series("temperature", Some(origin_date), &temperature)
+            .expect("could not create series.");
+        db.create_series("door.open", Some(origin_date), &DbOptions::default())
+            .expect("could not create series.");
+        assert_eq!(
+            db.create_series("temperature", None, &DbOptions::default()),
+            Err(TSLiteError::SeriesAlreadyExists("temperature".to_string()))
+        );
+        for name in &["", "../escape", ".hidden", "a/b"] {
+            assert_eq!(
+                db.create_series(name, None, &DbOptions::default()),
+                Err(TSLiteError::InvalidSeriesName(name.to_string()))
+            );
+        }
+
+        for i in 0..10 {
+            db.append_record(
+                "temperature",
+                RecordInfo {
+                    time_offset: i * 500,
+                    value: 20.0 + i as f32,
+                },
+            )
+            .expect("could not append record.");
+        }
+        db.append_record(
+            "door.open",
+            RecordInfo {
+                time_offset: 3,
+                value: 1u8,
+            },
+        )
+        .expect("could not append record.");
+        assert_eq!(
+            db.append_record(
+                "door.open",
+                RecordInfo {
+                    time_offset: 4,
+                    value: 1.0f32
+                }
+            ),
+            Err(TSLiteError::TypeMismatch)
+        );
+        db.close().expect("could not close database.");
+
+        let mut db = Database::open(Path::new(dir)).expect("could not open database.");
+        let names: Vec<&str> = db.series().map(|info| info.name.as_str()).collect();
+        assert_eq!(names, vec!["door.open", "temperature"]);
+        let info = db.series_info("temperature").unwrap();
+        assert_eq!(info.origin_date, Timestamp::try_from(origin_date).unwrap());
+        assert_eq!(info.options, temperature);
+
+        let from = origin_date + chrono::Duration::seconds(1);
+        let to = origin_date + chrono::Duration::seconds(2);
+        let values: Vec<f32> = db
+            .query_range::<f32>("temperature", from, to)
+            .expect("could not query series.")
+            .iter()
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(values, vec![22.0, 23.0]);
+        assert_eq!(db.read_record("door.open", 0).map(|r| r.value), Ok(1u8));
+        assert_eq!(
+            db.read_record::<u8>("window.open", 0),
+            Err(TSLiteError::UnknownSeries("window.open".to_string()))
+        );
+
+        db.drop_series("door.open").expect("could not drop series.");
+        assert!(!Path::new(dir).join("door.open.db").exists());
+        assert_eq!(
+            db.drop_series("door.open"),
+            Err(TSLiteError::UnknownSeries("door.open".to_string()))
+        );
+        let db = Database::open(Path::new(dir)).expect("could not open database.");
+        assert_eq!(db.series().count(), 1);
+
+        // A damaged catalog is detected.
+        let path = Path::new(dir).join("catalog");
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 5;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+        assert!(matches!(
+            Database::open(Path::new(dir)),
+            Err(TSLiteError::IOError(_))
+        ));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn database_retention_and_drop() {
+        let dir = "database_retention_and_drop";
+        let _ = fs::remove_dir_all(dir);
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db = Database::open(Path::new(dir)).expect("could not open database.");
+        let options = DbOptions {
+            value_type: ValueType::F32,
+            precision: Precision::Milliseconds,
+            ..DbOptions::default()
+        };
+        db.create_series("temperature", Some(origin_date), &options)
+            .expect("could not create series.");
+        for i in 0..10 {
+            db.append_record(
+                "temperature",
+                RecordInfo {
+                    time_offset: i * 500,
+                    value: 20.0 + i as f32,
+                },
+            )
+            .expect("could not append record.");
+        }
+
+        // Enforcing the retention moves the origin date, which the catalog follows.
+        let retention = Retention {
+            max_age: Some(chrono::Duration::seconds(2)),
+            max_size: None,
+        };
+        db.set_retention("temperature", retention)
+            .expect("could not set retention.");
+        assert_eq!(db.enforce_retention("temperature"), Ok(5));
+        db.close().expect("could not close database.");
+        let mut db = Database::open(Path::new(dir)).expect("could not open database.");
+        let info = db.series_info("temperature").unwrap();
+        let new_origin = origin_date + chrono::Duration::seconds(2);
+        assert_eq!(info.origin_date, Timestamp::try_from(new_origin).unwrap());
+        assert_eq!(info.options.retention, retention);
+
+        // A series whose file is gone can still be dropped, and the side files are removed.
+        let path = Path::new(dir).join("temperature.db");
+        let journal_path = Path::new(dir).join("temperature.db.jrn");
+        fs::remove_file(&path).unwrap();
+        fs::write(&journal_path, b"").unwrap();
+        db.drop_series("temperature")
+            .expect("could not drop series.");
+        assert!(!journal_path.exists());
+        assert_eq!(db.series().count(), 0);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,637 @@
+//! Insertion of late records, keeping the records of the DB ordered.
+//!
+//! A record older than the last record of the DB is inserted at its place by rewriting the records
+//! following it, as long as there are at most [`REORDER_WINDOW`] of them. Older records go to an
+//! overflow area next to the DB file, named after it with `.ovf` appended, holding records encoded like
+//! in the DB. The overflow area is merged into the DB once it holds [`REORDER_WINDOW`] records, so the
+//! records following the oldest of them are rewritten once for all of them. It is also merged before the
+//! records of the DB are read and when the DB is opened or closed, so inserted records are never missed.
+//!
+//! Rewritten records are first written to a journal next to the DB file, named after it with `.jrn`
+//! appended, with the slot of the first of them. Once the journal is synced, the records are written
+//! in the DB and the new number of records is committed, then the journal is removed. If this is
+//! interrupted, the journal is written again in the DB when it is opened, so the DB never loses records.
+//! A journal that was not fully written is ignored, the DB being untouched.
+//!
+//! The records are streamed to the journal as they are merged, and from the journal to the DB by
+//! [`REORDER_WINDOW`] records at a time, so merging the overflow area only holds a few records in memory
+//! however many records follow the oldest of them. The journal still takes as much space on disk as the
+//! records it rewrites.
+//!
+//! ```text
+//! +--------------------------[JOURNAL]---------------------------+
+//! |-[FIRST SLOT]-|-[RECORD COUNT]-|-[FLAGS]-|-[RECORDS]-|-[CRC32]-|
+//! |    64bit     |      64bit     |   8bit  |           |  32bit  |
+//! +--------------------------------------------------------------+
+//! ```
+//!
+//! The record count is the value to commit, which is the number of records the DB has ever held for
+//! a ring. The first bit of the flags is set if the records include the overflow area, and the second
+//! one if they are the records left by [`PhysicalDB::compact`], the overflow area or the deleted records
+//! being forgotten with the journal.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::iter::RawRecords;
+use crate::{PhysicalDB, RecordInfo, Storage, TSLiteError, Value};
+
+/// The maximal number of records rewritten to insert a late record at its place,
+/// and the number of records held by the overflow area before it is merged into the DB.
+pub const REORDER_WINDOW: u64 = 256;
+
+/// Records to write from the slot `slot` on before committing `records_number`,
+/// see the module documentation. The records follow it in the journal file.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Journal {
+    pub(crate) slot: u64,
+    pub(crate) records_number: u64,
+    pub(crate) overflow: bool,
+    pub(crate) compaction: bool,
+}
+
+impl Journal {
+    /// The size of the journal without its records: its fields and the checksum.
+    const SIZE: u64 = Journal::FIELDS_SIZE + 4;
+    /// The size of the fields preceding the records.
+    const FIELDS_SIZE: u64 = 8 + 8 + 1;
+    /// Flag set if the records include the overflow area.
+    const FLAG_OVERFLOW: u8 = 1;
+    /// Flag set if the records are the records left by a compaction.
+    const FLAG_COMPACTION: u8 = 1 << 1;
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut store: Vec<u8> = Vec::with_capacity(Journal::FIELDS_SIZE as usize);
+        store.write_u64::<LittleEndian>(self.slot).unwrap();
+        store
+            .write_u64::<LittleEndian>(self.records_number)
+            .unwrap();
+        let mut flags = 0;
+        if self.overflow {
+            flags |= Journal::FLAG_OVERFLOW;
+        }
+        if self.compaction {
+            flags |= Journal::FLAG_COMPACTION;
+        }
+        store.write_u8(flags).unwrap();
+        store
+    }
+
+    fn from_bytes(d: &[u8]) -> Journal {
+        let mut reader = Cursor::new(d);
+        let slot = reader.read_u64::<LittleEndian>().unwrap();
+        let records_number = reader.read_u64::<LittleEndian>().unwrap();
+        let flags = reader.read_u8().unwrap();
+        Journal {
+            slot,
+            records_number,
+            overflow: flags & Journal::FLAG_OVERFLOW != 0,
+            compaction: flags & Journal::FLAG_COMPACTION != 0,
+        }
+    }
+}
+
+/// Write `journal` and the records given by `records` to the journal file at `path`, then sync it.
+/// The journal file is removed if a record cannot be read.
+pub(crate) fn write_journal<I>(
+    path: &Path,
+    journal: &Journal,
+    records: I,
+) -> Result<(), TSLiteError>
+where
+    I: Iterator<Item = Result<Vec<u8>, TSLiteError>>,
+{
+    let file = File::create(path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    let mut writer = BufWriter::new(file);
+    let mut hasher = crc32fast::Hasher::new();
+    let res = std::iter::once(Ok(journal.as_bytes()))
+        .chain(records)
+        .try_for_each(|bytes| {
+            let bytes = bytes?;
+            hasher.update(&bytes);
+            writer
+                .write_all(&bytes)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))
+        })
+        .and_then(|_| {
+            writer
+                .write_u32::<LittleEndian>(hasher.finalize())
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            let file = writer
+                .into_inner()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            file.sync_all()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))
+        });
+    if res.is_err() {
+        let _ = fs::remove_file(path);
+    }
+
+    res
+}
+
+/// Read the fields of the journal file `file`, `None` if it was not fully written.
+fn read_journal(mut file: &File) -> Result<Option<Journal>, TSLiteError> {
+    let len = file
+        .metadata()
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?
+        .len();
+    if len < Journal::SIZE {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    let mut reader = BufReader::new(file);
+    let mut fields = vec![0u8; Journal::FIELDS_SIZE as usize];
+    reader
+        .read_exact(&mut fields)
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&fields);
+    let mut content = (&mut reader).take(len - Journal::SIZE);
+    let mut buffer = [0u8; 8192];
+    loop {
+        let read = content
+            .read(&mut buffer)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    let checksum = reader
+        .read_u32::<LittleEndian>()
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    if checksum != hasher.finalize() {
+        return Ok(None);
+    }
+
+    Ok(Some(Journal::from_bytes(&fields)))
+}
+
+impl PhysicalDB {
+    /// The path of the overflow area of the DB: the path of the DB followed by `.ovf`.
+    pub fn overflow_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".ovf");
+        PathBuf::from(path)
+    }
+
+    /// The path of the journal of the DB: the path of the DB followed by `.jrn`.
+    pub(crate) fn journal_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".jrn");
+        PathBuf::from(path)
+    }
+
+    /// Insert a record at its place among the records of the DB, after the records with the same time offset.
+    /// A record that is not older than the last one is appended, see [`PhysicalDB::append_record`].
+    /// A record older than the [`REORDER_WINDOW`] last records goes to the overflow area, which is merged
+    /// before the records of the DB are next read, see [`PhysicalDB::merge_overflow`].
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    /// Only DBs using the plain storage can have records inserted, `TSLiteError::UnsupportedOperation`
+    /// is returned otherwise. If records following the inserted one are deleted, the deleted records
+    /// are removed first, see [`PhysicalDB::compact`].
+    pub fn insert_record<V: Value>(&mut self, rec_nfo: RecordInfo<V>) -> Result<(), TSLiteError> {
+        self.check_value_type::<V>()?;
+        if self.header.storage != Storage::Plain {
+            return Err(TSLiteError::UnsupportedOperation);
+        }
+        self.header.check_time_offset(rec_nfo.time_offset)?;
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let records_number = self.header.records_number;
+        if records_number == 0 || self.read_time_offset(records_number - 1)? <= rec_nfo.time_offset
+        {
+            return self.append_record(rec_nfo);
+        }
+
+        let mut rec_id = self.search_offset(rec_nfo.time_offset.saturating_add(1))?;
+        let record = self.header.encode_record(&rec_nfo);
+        if records_number - rec_id <= REORDER_WINDOW {
+            // The records following the inserted one move, their tombstones would not follow them.
+            if self.deleted_from(rec_id) {
+                self.compact()?;
+                rec_id = self.search_offset(rec_nfo.time_offset.saturating_add(1))?;
+            }
+            return self.merge_records(rec_id, vec![record], false);
+        }
+
+        // A record that was not fully written to the overflow area is overwritten.
+        let len = self.overflowed()? * self.header.record_size();
+        let mut overflow = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.overflow_path())
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        overflow
+            .set_len(len)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        overflow
+            .seek(SeekFrom::Start(len))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        overflow
+            .write_all(&record)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        overflow
+            .sync_data()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        if self.overflowed()? >= REORDER_WINDOW {
+            self.merge_overflow()?;
+        }
+
+        Ok(())
+    }
+
+    /// The number of records in the overflow area, waiting to be merged into the DB.
+    pub fn overflowed(&self) -> Result<u64, TSLiteError> {
+        match fs::metadata(self.overflow_path()) {
+            Ok(metadata) => Ok(metadata.len() / self.header.record_size()),
+            Err(_) => Ok(0),
+        }
+    }
+
+    /// Insert every record of the overflow area at its place among the records of the DB,
+    /// rewriting the records following the oldest of them, then remove the overflow area.
+    /// If some of these records are deleted, the deleted records are removed first,
+    /// see [`PhysicalDB::compact`].
+    pub fn merge_overflow(&mut self) -> Result<(), TSLiteError> {
+        let mut data = Vec::new();
+        match File::open(self.overflow_path()) {
+            Ok(mut file) => file
+                .read_to_end(&mut data)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?,
+            Err(_) => return Ok(()),
+        };
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        // A record that was not fully written to the overflow area was never inserted.
+        let header = self.header;
+        let mut records: Vec<Vec<u8>> = data
+            .chunks_exact(header.record_size() as usize)
+            .map(|r| r.to_vec())
+            .collect();
+        records.sort_by_key(|r| header.raw_time_offset(r));
+        let time_offset = match records.first() {
+            Some(record) => header.raw_time_offset(record).saturating_add(1),
+            None => {
+                return fs::remove_file(self.overflow_path())
+                    .map_err(|e| TSLiteError::IOError(e.to_string()))
+            }
+        };
+        let mut rec_id = self.search_offset(time_offset)?;
+        if self.deleted_from(rec_id) {
+            self.compact()?;
+            rec_id = self.search_offset(time_offset)?;
+        }
+
+        self.merge_records(rec_id, records, true)
+    }
+
+    /// Merge the ordered `records` with the records from `rec_id` on, which all come after the first
+    /// of them, and rewrite them through the journal. `overflow` tells if `records` are the overflow area.
+    fn merge_records(
+        &mut self,
+        rec_id: u64,
+        records: Vec<Vec<u8>>,
+        overflow: bool,
+    ) -> Result<(), TSLiteError> {
+        let header = self.header;
+        let records_number = header.records_number;
+        let mut len = records_number - rec_id + records.len() as u64;
+
+        // The records that a ring would overwrite right away are not written.
+        let mut first = rec_id;
+        let mut excess = 0;
+        if let Some(capacity) = header.capacity {
+            excess = len.saturating_sub(capacity);
+            first += excess;
+            len -= excess;
+        }
+
+        // A record of the DB comes before an inserted record with the same time offset.
+        let journal_path = self.journal_path();
+        let mut tail = RawRecords::unchecked(self, rec_id, records_number).peekable();
+        let mut records = records.into_iter().peekable();
+        let merged = std::iter::from_fn(|| {
+            let from_tail = match (tail.peek(), records.peek()) {
+                (Some(Ok(r)), Some(record)) => {
+                    header.raw_time_offset(r) <= header.raw_time_offset(record)
+                }
+                (Some(_), _) => true,
+                (None, _) => false,
+            };
+            if from_tail {
+                tail.next()
+            } else {
+                records.next().map(Ok)
+            }
+        });
+        let journal = Journal {
+            slot: header.ring_slot(first),
+            records_number: header.overwritten + first + len,
+            overflow,
+            compaction: false,
+        };
+        write_journal(&journal_path, &journal, merged.skip(excess as usize))?;
+
+        self.replay_journal()?;
+        self.enforce_retention_on_append()
+    }
+
+    /// Write the records of the journal `file` and commit them, then remove the journal and what it
+    /// replaces. Doing it again gives the same DB, so it does not matter if it was already done.
+    fn apply_journal(&mut self, mut file: &File, journal: &Journal) -> Result<(), TSLiteError> {
+        let slots = self.header.ring_slots();
+        let rec_id = match self.header.capacity {
+            Some(_) => (journal.slot + slots - self.header.overwritten % slots) % slots,
+            None => journal.slot,
+        };
+        let len = file
+            .metadata()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?
+            .len();
+        file.seek(SeekFrom::Start(Journal::FIELDS_SIZE))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let mut reader = BufReader::new(file).take(len - Journal::SIZE);
+        let record_size = self.header.record_size();
+        let mut written = 0;
+        loop {
+            let mut records = Vec::with_capacity((REORDER_WINDOW * record_size) as usize);
+            (&mut reader)
+                .take(REORDER_WINDOW * record_size)
+                .read_to_end(&mut records)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            if records.is_empty() {
+                break;
+            }
+            self.write_records(rec_id + written, &records)?;
+            written += records.len() as u64 / record_size;
+        }
+        self.file
+            .as_ref()
+            .unwrap()
+            .sync_data()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        // The count of a ring includes its overwritten records, which are computed again.
+        self.header.overwritten = 0;
+        self.commit_records_number(journal.records_number)?;
+        if self.header.capacity.is_none() {
+            self.truncate_records(self.header.record_pos(self.header.records_number))?;
+        }
+
+        if journal.overflow {
+            let _ = fs::remove_file(self.overflow_path());
+        }
+        if journal.compaction {
+            self.clear_tombstones()?;
+        }
+        fs::remove_file(self.journal_path()).map_err(|e| TSLiteError::IOError(e.to_string()))
+    }
+
+    /// Write the journal of the DB in it, if there is one: either the rewrite that was just written
+    /// or one that was interrupted before the journal was removed.
+    pub(crate) fn replay_journal(&mut self) -> Result<(), TSLiteError> {
+        let file = match File::open(self.journal_path()) {
+            Ok(file) => file,
+            Err(_) => return Ok(()),
+        };
+
+        match read_journal(&file)? {
+            Some(journal) => self.apply_journal(&file, &journal),
+            None => fs::remove_file(self.journal_path())
+                .map_err(|e| TSLiteError::IOError(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbIssue, DbOptions};
+    use chrono::{TimeZone, Utc};
+    use std::path::Path;
+
+    fn offsets(db: &mut PhysicalDB) -> Vec<i64> {
+        db.iter::<u8>()
+            .expect("could not iterate db.")
+            .map(|res| res.expect("could not read record.").1.time_offset)
+            .collect()
+    }
+
+    /// The content of the journal file for `journal` and `records`.
+    fn journal_bytes(journal: &Journal, records: &[u8]) -> Vec<u8> {
+        let mut store = journal.as_bytes();
+        store.extend(records);
+        let checksum = crc32fast::hash(&store);
+        store.write_u32::<LittleEndian>(checksum).unwrap();
+        store
+    }
+
+    fn record(time_offset: i64) -> RecordInfo {
+        RecordInfo {
+            time_offset,
+            value: (time_offset % 256) as u8,
+        }
+    }
+
+    #[test]
+    fn insert_late_records() {
+        let path = "insert_late_records.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let options = DbOptions {
+            checksums: true,
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), Some(origin_date), &options)
+            .expect("could not create db.");
+        let records: Vec<RecordInfo> = (0..1000).map(|i| record(i * 10)).collect();
+        db.append_records(&records)
+            .expect("could not append records.");
+
+        // A record within the window is inserted right away, after the records with the same offset.
+        db.insert_record(record(9995))
+            .expect("could not insert record.");
+        db.insert_record(RecordInfo {
+            time_offset: 9980,
+            value: 42u8,
+        })
+        .expect("could not insert record.");
+        assert_eq!(db.header.records_number, 1002);
+        assert_eq!(db.read_record(998), Ok(record(9980)));
+        assert_eq!(db.read_record::<u8>(999).map(|r| r.value), Ok(42));
+        assert_eq!(db.read_record(1000), Ok(record(9990)));
+        assert_eq!(db.read_record(1001), Ok(record(9995)));
+        db.insert_record(record(20_000))
+            .expect("could not insert record.");
+        assert_eq!(db.header.records_number, 1003);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+        // Older records wait in the overflow area until the records are read.
+        db.insert_record(record(5))
+            .expect("could not insert record.");
+        db.insert_record(record(1))
+            .expect("could not insert record.");
+        assert_eq!(db.overflowed(), Ok(2));
+        assert_eq!(db.header.records_number, 1003);
+        assert_eq!(offsets(&mut db)[..4].to_vec(), vec![0, 1, 5, 10]);
+        assert_eq!(db.overflowed(), Ok(0));
+        assert!(!db.overflow_path().exists());
+        assert_eq!(db.header.records_number, 1005);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert!(db.verify().expect("could not verify db.").is_healthy());
+
+        // The overflow area is merged once it is full.
+        for i in 0..REORDER_WINDOW as i64 {
+            db.insert_record(record(i * 10 + 3))
+                .expect("could not insert record.");
+        }
+        assert_eq!(db.overflowed(), Ok(0));
+        assert_eq!(db.header.records_number, 1005 + REORDER_WINDOW);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+        assert_eq!(
+            db.insert_record(RecordInfo {
+                time_offset: 1,
+                value: 1u16
+            }),
+            Err(TSLiteError::TypeMismatch)
+        );
+        let options = DbOptions {
+            storage: Storage::Rle,
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &options)
+            .expect("could not create db.");
+        assert_eq!(
+            db.insert_record(record(1)),
+            Err(TSLiteError::UnsupportedOperation)
+        );
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn insert_journal_replay() {
+        let path = "insert_journal_replay.db";
+        let mut db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        let records: Vec<RecordInfo> = (0..10).map(|i| record(i * 10)).collect();
+        db.append_records(&records)
+            .expect("could not append records.");
+        db.insert_record(record(1000))
+            .expect("could not insert record.");
+        db.insert_record(record(2000))
+            .expect("could not insert record.");
+
+        // The rewrite is interrupted once the journal is synced, half of the records being written.
+        let mut tail: Vec<Vec<u8>> = [15, 20, 30, 40, 50, 60, 70, 80, 90, 1000, 2000]
+            .iter()
+            .map(|offset| db.header.encode_record(&record(*offset)))
+            .collect();
+        let journal = Journal {
+            slot: 2,
+            records_number: 13,
+            overflow: true,
+            compaction: false,
+        };
+        fs::write(db.journal_path(), journal_bytes(&journal, &tail.concat())).unwrap();
+        tail.truncate(4);
+        db.write_records(2, &tail.concat())
+            .expect("could not write records.");
+        fs::write(db.overflow_path(), db.header.encode_record(&record(15))).unwrap();
+        drop(db);
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert!(!db.journal_path().exists());
+        assert_eq!(db.overflowed(), Ok(0));
+        assert_eq!(
+            offsets(&mut db),
+            vec![0, 10, 15, 20, 30, 40, 50, 60, 70, 80, 90, 1000, 2000]
+        );
+
+        // A journal that was not fully written is ignored.
+        let journal = Journal {
+            slot: 1,
+            records_number: 13,
+            overflow: false,
+            compaction: false,
+        };
+        let bytes = journal_bytes(&journal, &db.header.encode_record(&record(5)));
+        fs::write(db.journal_path(), &bytes[..bytes.len() - 1]).unwrap();
+        db.close().expect("could not close db.");
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert!(!db.journal_path().exists());
+        assert_eq!(db.header.records_number, 13);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+        // An overflow area left when the DB was not closed is merged when it is opened.
+        db.insert_record(record(3))
+            .expect("could not insert record.");
+        assert_eq!(db.overflowed(), Ok(0));
+        fs::write(db.overflow_path(), db.header.encode_record(&record(7))).unwrap();
+        drop(db);
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert!(!db.overflow_path().exists());
+        assert_eq!(offsets(&mut db)[..4].to_vec(), vec![0, 3, 7, 10]);
+
+        // A ring keeps its most recent records.
+        let options = DbOptions {
+            capacity: Some(5),
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &options)
+            .expect("could not create db.");
+        for offset in &[10, 20, 30, 40, 50, 60, 35, 5] {
+            db.insert_record(record(*offset))
+                .expect("could not insert record.");
+        }
+        assert_eq!(offsets(&mut db), vec![30, 35, 40, 50, 60]);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn query_overflowed_record() {
+        let path = "query_overflowed_record.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let seconds = |s: i64| origin_date + chrono::Duration::seconds(s);
+        let mut db =
+            PhysicalDB::create(Path::new(path), Some(origin_date)).expect("could not create db.");
+        let records: Vec<RecordInfo> = (1..1000).map(|i| record(i * 10)).collect();
+        db.append_records(&records)
+            .expect("could not append records.");
+
+        // A single record far behind the last one is found right away.
+        db.insert_record(record(5))
+            .expect("could not insert record.");
+        assert_eq!(db.overflowed(), Ok(1));
+        assert_eq!(
+            db.query_range::<u8>(seconds(0), seconds(20)),
+            Ok(vec![record(5), record(10)])
+        );
+        assert_eq!(db.overflowed(), Ok(0));
+        assert_eq!(db.read_record(0), Ok(record(5)));
+
+        // Closing the DB merges it too.
+        db.insert_record(record(6))
+            .expect("could not insert record.");
+        db.close().expect("could not close db.");
+        assert!(!db.overflow_path().exists());
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.records_number, 1001);
+        let aggregate = db
+            .aggregate::<u8>(seconds(0), seconds(10))
+            .expect("could not aggregate db.");
+        assert_eq!(aggregate.count, 2);
+
+        let _ = fs::remove_file(path);
+    }
+}
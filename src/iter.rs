@@ -0,0 +1,277 @@
+//! Iterators over the records of a DB.
+//!
+//! Records are read by blocks of [`BLOCK_RECORDS`] records, so iterating over a DB does one seek
+//! and one read per block instead of one per record. Both ends of the iterator have their own block,
+//! which means reading the last records of a DB only reads the end of the file.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+
+use crate::{DbHeader, PhysicalDB, RecordInfo, Storage, TSLiteError, Timestamp, Value};
+
+/// The number of records read at once by an iterator.
+pub const BLOCK_RECORDS: u64 = 512;
+
+/// `count` consecutive records read from the file, starting at the record `start`.
+/// `data` can be shorter than expected if the file is truncated.
+struct Block {
+    start: u64,
+    count: u64,
+    data: Vec<u8>,
+}
+
+impl Block {
+    fn empty() -> Block {
+        Block {
+            start: 0,
+            count: 0,
+            data: Vec::new(),
+        }
+    }
+
+    fn contains(&self, rec_id: u64) -> bool {
+        self.start <= rec_id && rec_id < self.start + self.count
+    }
+}
+
+/// Iterate over the raw octets of the records in `[front, back)`, whatever the value type of the DB is.
+/// The checksum of the records, if any, is checked unless the iterator is created with
+/// [`RawRecords::unchecked`].
+pub(crate) struct RawRecords<'a> {
+    db: &'a mut PhysicalDB,
+    record_size: u64,
+    checked: bool,
+    front: u64,
+    back: u64,
+    front_block: Block,
+    back_block: Block,
+}
+
+impl<'a> RawRecords<'a> {
+    pub(crate) fn new(db: &'a mut PhysicalDB, front: u64, back: u64) -> RawRecords<'a> {
+        let record_size = db.header.record_size();
+        RawRecords {
+            db,
+            record_size,
+            checked: true,
+            front,
+            back: back.max(front),
+            front_block: Block::empty(),
+            back_block: Block::empty(),
+        }
+    }
+
+    /// Same as [`RawRecords::new`] but the checksums are not checked,
+    /// used to look at or move records whatever their content is.
+    pub(crate) fn unchecked(db: &'a mut PhysicalDB, front: u64, back: u64) -> RawRecords<'a> {
+        RawRecords {
+            checked: false,
+            ..RawRecords::new(db, front, back)
+        }
+    }
+
+    /// Read at most `count` records starting at the record `start`.
+    /// If the file is too short, the block will just contain less records.
+    fn read_block(&mut self, start: u64, count: u64) -> Result<Block, TSLiteError> {
+        if self.db.file.is_none() {
+            self.db.open()?;
+        }
+
+        match self.db.header.storage {
+            Storage::Plain => {}
+            Storage::Compressed => {
+                let data = self.db.read_compressed(start, count)?;
+                return Ok(Block { start, count, data });
+            }
+            Storage::Rle => {
+                let data = self.db.read_rle(start, count)?;
+                return Ok(Block { start, count, data });
+            }
+        }
+
+        // The records of a ring can wrap around the end of the file.
+        let mut fref = self.db.file.as_ref().unwrap();
+        let mut data = Vec::with_capacity((count * self.record_size) as usize);
+        let mut rec_id = start;
+        while rec_id < start + count {
+            let chunk = self
+                .db
+                .header
+                .contiguous_records(rec_id)
+                .min(start + count - rec_id);
+            fref.seek(SeekFrom::Start(self.db.header.record_pos(rec_id)))
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            let read = fref
+                .take(chunk * self.record_size)
+                .read_to_end(&mut data)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            if read as u64 != chunk * self.record_size {
+                break;
+            }
+            rec_id += chunk;
+        }
+
+        Ok(Block { start, count, data })
+    }
+
+    /// Get the octets of the record `rec_id` from a block.
+    fn record_from(&self, block: &Block, rec_id: u64) -> Result<Vec<u8>, TSLiteError> {
+        let begin = ((rec_id - block.start) * self.record_size) as usize;
+        let end = begin + self.record_size as usize;
+        if end > block.data.len() {
+            return Err(TSLiteError::IOError(
+                "Could not read record: not enough octets.".to_string(),
+            ));
+        }
+
+        let record = &block.data[begin..end];
+        if self.checked {
+            self.db.header.check_record(rec_id, record)?;
+        }
+
+        Ok(record.to_vec())
+    }
+
+    /// Stop the iteration, used once an error has been returned.
+    fn fuse(&mut self) {
+        self.front = self.back;
+    }
+}
+
+impl Iterator for RawRecords<'_> {
+    type Item = Result<Vec<u8>, TSLiteError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        let rec_id = self.front;
+        if !self.front_block.contains(rec_id) {
+            let count = BLOCK_RECORDS.min(self.back - rec_id);
+            match self.read_block(rec_id, count) {
+                Ok(block) => self.front_block = block,
+                Err(e) => {
+                    self.fuse();
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let res = self.record_from(&self.front_block, rec_id);
+        if res.is_err() {
+            self.fuse();
+        } else {
+            self.front += 1;
+        }
+        Some(res)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.back - self.front) as usize;
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for RawRecords<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        let rec_id = self.back - 1;
+        if !self.back_block.contains(rec_id) {
+            let start = self.front.max(self.back.saturating_sub(BLOCK_RECORDS));
+            match self.read_block(start, self.back - start) {
+                Ok(block) => self.back_block = block,
+                Err(e) => {
+                    self.fuse();
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let res = self.record_from(&self.back_block, rec_id);
+        if res.is_err() {
+            self.fuse();
+        } else {
+            self.back -= 1;
+        }
+        Some(res)
+    }
+}
+
+/// Iterate over the records of a DB with the date of each record.
+/// Created with [`PhysicalDB::iter`] or [`PhysicalDB::iter_range`].
+/// If a record cannot be read, the error is returned and the iteration stops.
+/// Deleted records are skipped without being read, see [`PhysicalDB::delete_record`].
+pub struct RecordIter<'a, V: Value = u8> {
+    raw: RawRecords<'a>,
+    header: DbHeader,
+    _value: PhantomData<V>,
+}
+
+impl<'a, V: Value> RecordIter<'a, V> {
+    pub(crate) fn new(db: &'a mut PhysicalDB, front: u64, back: u64) -> RecordIter<'a, V> {
+        let header = db.header;
+        RecordIter {
+            raw: RawRecords::new(db, front, back),
+            header,
+            _value: PhantomData,
+        }
+    }
+
+    /// Move the front of the iterator past the deleted records.
+    fn skip_deleted_front(&mut self) {
+        while self.raw.front < self.raw.back {
+            match self.raw.db.deleted_range(self.raw.front) {
+                Some((_, end)) => self.raw.front = end.min(self.raw.back),
+                None => return,
+            }
+        }
+    }
+
+    /// Move the back of the iterator before the deleted records.
+    fn skip_deleted_back(&mut self) {
+        while self.raw.front < self.raw.back {
+            match self.raw.db.deleted_range(self.raw.back - 1) {
+                Some((start, _)) => self.raw.back = start.max(self.raw.front),
+                None => return,
+            }
+        }
+    }
+
+    fn decode(
+        &self,
+        res: Result<Vec<u8>, TSLiteError>,
+    ) -> Result<(Timestamp, RecordInfo<V>), TSLiteError> {
+        let record: RecordInfo<V> = self.header.decode_record(&res?);
+        Ok((self.header.record_date(record.time_offset)?, record))
+    }
+}
+
+impl<V: Value> Iterator for RecordIter<'_, V> {
+    type Item = Result<(Timestamp, RecordInfo<V>), TSLiteError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.skip_deleted_front();
+        let res = self.raw.next()?;
+        Some(self.decode(res))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (len, _) = self.raw.size_hint();
+        if self.raw.db.deleted() > 0 {
+            return (0, Some(len));
+        }
+        (len, Some(len))
+    }
+}
+
+impl<V: Value> DoubleEndedIterator for RecordIter<'_, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.skip_deleted_back();
+        let res = self.raw.next_back()?;
+        Some(self.decode(res))
+    }
+}
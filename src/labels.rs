@@ -0,0 +1,551 @@
+//! Labels describing a series, and an index to find series by their labels.
+//!
+//! Labels are pairs of a name and a value, like `host=a` or `sensor=temp`, stored in the header of the DB
+//! file right after the fixed fields. The header length covers them, so the records start after the labels. Labels are sorted by name and encoded like this:
+//!
+//! ```text
+//! +---------------------------[LABELS]---------------------------+
+//! |-[COUNT]-|-[NAME LENGTH]-|-[NAME]-|-[VALUE LENGTH]-|-[VALUE]-|-...-|-[CRC32]-|
+//! |  16bit  |     16bit     |        |      16bit     |         |     |  32bit  |
+//! +---------------------------------------------------------------+
+//! ```
+//!
+//! The checksum is the CRC32 of everything before it. Changing the labels copies the DB file with the new
+//! labels in its header, the copy then replacing the DB file, so an interrupted change leaves the labels as
+//! they were. The space taken by the labels is rounded up to a multiple of 64 octets, so the records keep
+//! their position as long as the new labels fit. A DB without labels has no space for them.
+//!
+//! A [`LabelIndex`] reads the labels of every DB file of a directory and finds the series matching a set
+//! of [`Matcher`]s, like `{host="a", sensor=~"temp.*"}` would in Prometheus.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{read_header_from, replace_file, DbHeader, PhysicalDB, Storage, TSLiteError};
+
+/// The labels of a series, by name. Labels with an empty value are not stored, a missing label
+/// being the same as a label with an empty value.
+pub type Labels = BTreeMap<String, String>;
+
+/// The space taken by the labels in the header is a multiple of this number of octets.
+const LABELS_ALIGN: usize = 64;
+
+/// Check that a label name is made of ASCII letters, digits and `_`, and does not start with a digit.
+fn check_label_name(name: &str) -> Result<(), TSLiteError> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(TSLiteError::InvalidLabelName(name.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Encode `labels` for the header, padded to at least `min_len` octets.
+/// Without labels, nothing is written unless `min_len` is not 0.
+pub(crate) fn encode_labels(labels: &Labels, min_len: usize) -> Result<Vec<u8>, TSLiteError> {
+    let labels: Vec<(&String, &String)> = labels.iter().filter(|(_, v)| !v.is_empty()).collect();
+    if labels.is_empty() && min_len == 0 {
+        return Ok(Vec::new());
+    }
+
+    let too_large = |_| TSLiteError::LabelsTooLarge;
+    let mut store: Vec<u8> = Vec::new();
+    let count = u16::try_from(labels.len()).map_err(too_large)?;
+    store.write_u16::<LittleEndian>(count).unwrap();
+    for (name, value) in labels {
+        check_label_name(name)?;
+        for part in &[name, value] {
+            let len = u16::try_from(part.len()).map_err(too_large)?;
+            store.write_u16::<LittleEndian>(len).unwrap();
+            store.extend(part.as_bytes());
+        }
+    }
+    let checksum = crc32fast::hash(&store);
+    store.write_u32::<LittleEndian>(checksum).unwrap();
+
+    let len = store.len().div_ceil(LABELS_ALIGN) * LABELS_ALIGN;
+    store.resize(len.max(min_len), 0);
+    if DbHeader::LABELS_POS as usize + store.len() > u16::MAX as usize {
+        return Err(TSLiteError::LabelsTooLarge);
+    }
+
+    Ok(store)
+}
+
+/// Decode the labels stored in the header after its fixed fields.
+fn decode_labels(d: &[u8]) -> Result<Labels, TSLiteError> {
+    let mut labels = Labels::new();
+    if d.is_empty() {
+        return Ok(labels);
+    }
+
+    let corrupted = || TSLiteError::IOError("DB File labels are corrupted.".to_string());
+    let mut reader = Cursor::new(d);
+    let count = reader.read_u16::<LittleEndian>().map_err(|_| corrupted())?;
+    for _ in 0..count {
+        let mut parts = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let len = reader.read_u16::<LittleEndian>().map_err(|_| corrupted())?;
+            let mut part = vec![0; len as usize];
+            reader.read_exact(&mut part).map_err(|_| corrupted())?;
+            parts.push(String::from_utf8(part).map_err(|_| corrupted())?);
+        }
+        let value = parts.pop().unwrap();
+        labels.insert(parts.pop().unwrap(), value);
+    }
+    let end = reader.position() as usize;
+    let checksum = reader.read_u32::<LittleEndian>().map_err(|_| corrupted())?;
+    if checksum != crc32fast::hash(&d[..end]) {
+        return Err(corrupted());
+    }
+
+    Ok(labels)
+}
+
+/// Read the labels of a DB file whose header is `header`.
+fn read_labels_from(mut file: &File, header: &DbHeader) -> Result<Labels, TSLiteError> {
+    let mut buffer = vec![0; header.header_len as usize - DbHeader::LABELS_POS as usize];
+    file.seek(SeekFrom::Start(DbHeader::LABELS_POS))
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    file.read_exact(&mut buffer)
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    decode_labels(&buffer)
+}
+
+impl PhysicalDB {
+    /// Read the labels of the DB from its header.
+    pub fn labels(&mut self) -> Result<Labels, TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        read_labels_from(self.file.as_ref().unwrap(), &self.header)
+    }
+
+    /// Replace the labels of the DB. The DB file is copied with the new labels in its header, and the copy
+    /// replaces the DB file. If they do not fit in the header, the header grows and the index of a compressed
+    /// DB is rebuilt.
+    pub fn set_labels(&mut self, labels: &Labels) -> Result<(), TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let space = self.header.header_len as usize - DbHeader::LABELS_POS as usize;
+        let encoded = encode_labels(labels, space)?;
+        let mut header = self.header;
+        header.header_len = (DbHeader::LABELS_POS as usize + encoded.len()) as u16;
+
+        let tmp_path = self.rewrite_path();
+        if let Err(e) = self.copy_with_header(&tmp_path, &header, &encoded) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        // The index is emptied first if the records move, so it is rebuilt whichever file is found
+        // when the DB is opened.
+        if header.header_len != self.header.header_len && header.storage == Storage::Compressed {
+            let index = self.open_index()?;
+            index
+                .set_len(0)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            index
+                .sync_all()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        }
+        self.file = None;
+        replace_file(&tmp_path, &self.path)?;
+
+        self.header = header;
+        self.open()?;
+        self.recover()
+    }
+
+    /// Write a copy of the DB file with the header `header` and the labels `encoded` to `tmp_path`.
+    /// The records are copied by chunks, so this works with DB files bigger than the memory.
+    fn copy_with_header(
+        &self,
+        tmp_path: &Path,
+        header: &DbHeader,
+        encoded: &[u8],
+    ) -> Result<(), TSLiteError> {
+        let mut bytes = header.as_bytes();
+        bytes[DbHeader::LABELS_POS as usize..].copy_from_slice(encoded);
+        let mut tmp = File::create(tmp_path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        tmp.write_all(&bytes)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        let mut fref = self.file.as_ref().unwrap();
+        fref.seek(SeekFrom::Start(self.header.header_len as u64))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        io::copy(&mut fref, &mut tmp).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        tmp.sync_all()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))
+    }
+}
+
+/// How a [`Matcher`] compares the value of a label.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatchOp {
+    /// The value is equal to the value of the matcher (`=`).
+    Equal,
+    /// The value is not equal to the value of the matcher (`!=`).
+    NotEqual,
+    /// The whole value matches the regular expression of the matcher (`=~`).
+    Regex,
+    /// The whole value does not match the regular expression of the matcher (`!~`).
+    NotRegex,
+}
+
+/// A condition on the value of the label `name`. A series without the label is matched
+/// as if its value was empty, like in Prometheus.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    /// Create a matcher, the regular expression of `MatchOp::Regex` and `MatchOp::NotRegex` must match
+    /// the whole value. If it cannot be compiled, `TSLiteError::InvalidRegex` is returned.
+    pub fn new(name: &str, op: MatchOp, value: &str) -> Result<Matcher, TSLiteError> {
+        let regex = match op {
+            MatchOp::Equal | MatchOp::NotEqual => None,
+            MatchOp::Regex | MatchOp::NotRegex => Some(
+                Regex::new(&format!("^(?:{})$", value))
+                    .map_err(|e| TSLiteError::InvalidRegex(e.to_string()))?,
+            ),
+        };
+
+        Ok(Matcher {
+            name: name.to_string(),
+            op,
+            value: value.to_string(),
+            regex,
+        })
+    }
+
+    /// Tell if a label value is matched, an empty value standing for a missing label.
+    pub fn matches(&self, value: &str) -> bool {
+        match self.op {
+            MatchOp::Equal => value == self.value,
+            MatchOp::NotEqual => value != self.value,
+            MatchOp::Regex => self.regex.as_ref().unwrap().is_match(value),
+            MatchOp::NotRegex => !self.regex.as_ref().unwrap().is_match(value),
+        }
+    }
+}
+
+/// The labels of every DB file of a directory, with the series of every label value.
+#[derive(Debug)]
+pub struct LabelIndex {
+    dir: PathBuf,
+    series: BTreeMap<PathBuf, Labels>,
+    postings: BTreeMap<String, BTreeMap<String, BTreeSet<PathBuf>>>,
+    skipped: Vec<(PathBuf, TSLiteError)>,
+}
+
+impl LabelIndex {
+    /// Read the labels of every file of `dir` whose name ends with `.db`.
+    /// The files whose labels cannot be read, like legacy or corrupted DB files, are left out
+    /// of the index and reported by [`LabelIndex::skipped`].
+    pub fn open(dir: &Path) -> Result<LabelIndex, TSLiteError> {
+        let mut index = LabelIndex {
+            dir: PathBuf::from(dir),
+            series: BTreeMap::new(),
+            postings: BTreeMap::new(),
+            skipped: Vec::new(),
+        };
+
+        for entry in fs::read_dir(dir).map_err(|e| TSLiteError::IOError(e.to_string()))? {
+            let path = entry
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?
+                .path();
+            if path.extension().is_some_and(|ext| ext == "db") && path.is_file() {
+                if let Err(e) = index.insert(&path) {
+                    index.skipped.push((path, e));
+                }
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// The directory the index was built from.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The DB files left out of the index because their labels could not be read, with the reason.
+    pub fn skipped(&self) -> &[(PathBuf, TSLiteError)] {
+        &self.skipped
+    }
+
+    /// Read the labels of the DB file at `path` again, or for the first time,
+    /// after it was created or its labels changed.
+    pub fn insert(&mut self, path: &Path) -> Result<(), TSLiteError> {
+        let file = File::open(path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let header = read_header_from(&file)?;
+        let labels = read_labels_from(&file, &header)?;
+
+        self.remove(path);
+        for (name, value) in &labels {
+            self.postings
+                .entry(name.clone())
+                .or_default()
+                .entry(value.clone())
+                .or_default()
+                .insert(PathBuf::from(path));
+        }
+        self.series.insert(PathBuf::from(path), labels);
+
+        Ok(())
+    }
+
+    /// Forget the DB file at `path`, after it was removed.
+    pub fn remove(&mut self, path: &Path) {
+        self.skipped.retain(|(skipped, _)| skipped != path);
+        let labels = match self.series.remove(path) {
+            Some(labels) => labels,
+            None => return,
+        };
+        for (name, value) in &labels {
+            let values = self.postings.get_mut(name).unwrap();
+            let paths = values.get_mut(value).unwrap();
+            paths.remove(path);
+            if paths.is_empty() {
+                values.remove(value);
+            }
+            if values.is_empty() {
+                self.postings.remove(name);
+            }
+        }
+    }
+
+    /// The labels of the DB file at `path`, if it is in the index.
+    pub fn labels(&self, path: &Path) -> Option<&Labels> {
+        self.series.get(path)
+    }
+
+    /// Every value of the label `name`, sorted.
+    pub fn label_values(&self, name: &str) -> Vec<&str> {
+        self.postings
+            .get(name)
+            .map(|values| values.keys().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    /// The paths of the DB files matching every matcher, sorted.
+    /// Without matchers, every DB file of the index is returned.
+    pub fn select(&self, matchers: &[Matcher]) -> Vec<&Path> {
+        let mut selected: BTreeSet<&Path> = self.series.keys().map(PathBuf::as_path).collect();
+        for matcher in matchers {
+            let values = self.postings.get(&matcher.name);
+            let with_value = |matched: bool| -> BTreeSet<&Path> {
+                values
+                    .into_iter()
+                    .flatten()
+                    .filter(|(value, _)| matcher.matches(value) == matched)
+                    .flat_map(|(_, paths)| paths.iter().map(PathBuf::as_path))
+                    .collect()
+            };
+
+            // Series without the label match the empty value.
+            if matcher.matches("") {
+                let excluded = with_value(false);
+                selected.retain(|path| !excluded.contains(path));
+            } else {
+                let included = with_value(true);
+                selected.retain(|path| included.contains(path));
+            }
+        }
+
+        selected.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbOptions, RecordInfo};
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn labels_in_header() {
+        let path = "labels_in_header.db";
+        let options = DbOptions {
+            storage: Storage::Compressed,
+            ..DbOptions::default()
+        };
+        let host = labels(&[("host", "a"), ("sensor", "temp")]);
+        let mut db = PhysicalDB::create_with_labels(Path::new(path), None, &options, &host)
+            .expect("could not create db.");
+        assert_eq!(db.header.header_len as u64, DbHeader::SIZE + 64);
+        let records: Vec<RecordInfo> = (0..100)
+            .map(|i| RecordInfo {
+                time_offset: i,
+                value: i as u8,
+            })
+            .collect();
+        db.append_records(&records)
+            .expect("could not append records.");
+        db.close().expect("could not close db.");
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.labels(), Ok(host));
+
+        // Labels fitting in the header keep the records in place, along with the index.
+        let smaller = labels(&[("host", "b"), ("empty", "")]);
+        db.set_labels(&smaller).expect("could not set labels.");
+        assert_eq!(db.labels(), Ok(labels(&[("host", "b")])));
+        assert_eq!(db.header.header_len as u64, DbHeader::SIZE + 64);
+        assert!(!db.rewrite_path().exists());
+        assert_eq!(db.read_record(99), Ok(records[99]));
+
+        // Bigger labels move the records, and the index is rebuilt.
+        let long = "x".repeat(100);
+        let bigger = labels(&[("host", "c"), ("description", &long)]);
+        db.set_labels(&bigger).expect("could not set labels.");
+        assert_eq!(db.header.header_len as u64, DbHeader::SIZE + 192);
+        db.close().expect("could not close db.");
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.labels(), Ok(bigger.clone()));
+        assert_eq!(db.read_record(42), Ok(records[42]));
+        assert!(db.verify().expect("could not verify db.").is_healthy());
+
+        assert_eq!(
+            db.set_labels(&labels(&[("0day", "a")])),
+            Err(TSLiteError::InvalidLabelName("0day".to_string()))
+        );
+        assert_eq!(
+            db.set_labels(&labels(&[("big", &"x".repeat(70_000))])),
+            Err(TSLiteError::LabelsTooLarge)
+        );
+
+        // Invalid labels do not overwrite the DB at the same path.
+        db.close().expect("could not close db.");
+        assert_eq!(
+            PhysicalDB::create_with_labels(
+                Path::new(path),
+                None,
+                &options,
+                &labels(&[("0day", "a")])
+            )
+            .err(),
+            Some(TSLiteError::InvalidLabelName("0day".to_string()))
+        );
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.labels(), Ok(bigger));
+        assert_eq!(db.header.records_number, 100);
+
+        // A DB without labels has no space for them.
+        let db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        assert_eq!(db.header.header_len as u64, DbHeader::SIZE);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.idx", path));
+    }
+
+    #[test]
+    fn label_index_select() {
+        let dir = "label_index_select";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let series = [
+            ("a-temp", labels(&[("host", "a"), ("sensor", "temp")])),
+            ("a-hum", labels(&[("host", "a"), ("sensor", "humidity")])),
+            ("b-temp", labels(&[("host", "b"), ("sensor", "temp")])),
+            ("b-up", labels(&[("host", "b")])),
+        ];
+        for (name, labels) in &series {
+            let path = Path::new(dir).join(format!("{}.db", name));
+            PhysicalDB::create_with_labels(&path, None, &DbOptions::default(), labels)
+                .expect("could not create db.");
+        }
+        fs::write(Path::new(dir).join("notes.txt"), "not a db").unwrap();
+        fs::write(Path::new(dir).join("broken.db"), "not a db either").unwrap();
+
+        // A file that cannot be read is reported without preventing the others from being indexed.
+        let mut index = LabelIndex::open(Path::new(dir)).expect("could not open index.");
+        assert_eq!(
+            index.skipped(),
+            &[(Path::new(dir).join("broken.db"), TSLiteError::NotADatabase)]
+        );
+        let select = |index: &LabelIndex, matchers: &[(&str, MatchOp, &str)]| -> Vec<String> {
+            let matchers: Vec<Matcher> = matchers
+                .iter()
+                .map(|(name, op, value)| Matcher::new(name, *op, value).unwrap())
+                .collect();
+            index
+                .select(&matchers)
+                .iter()
+                .map(|path| path.file_stem().unwrap().to_str().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(select(&index, &[]).len(), 4);
+        assert_eq!(
+            select(&index, &[("host", MatchOp::Equal, "a")]),
+            vec!["a-hum", "a-temp"]
+        );
+        assert_eq!(
+            select(
+                &index,
+                &[
+                    ("host", MatchOp::NotEqual, "a"),
+                    ("sensor", MatchOp::Equal, "temp")
+                ]
+            ),
+            vec!["b-temp"]
+        );
+        assert_eq!(
+            select(&index, &[("sensor", MatchOp::Regex, "te.*|hum.*")]),
+            vec!["a-hum", "a-temp", "b-temp"]
+        );
+        // The regular expression must match the whole value.
+        assert_eq!(select(&index, &[("sensor", MatchOp::Regex, "te")]).len(), 0);
+        // A missing label is matched like an empty value.
+        assert_eq!(
+            select(&index, &[("sensor", MatchOp::NotRegex, "temp")]),
+            vec!["a-hum", "b-up"]
+        );
+        assert_eq!(
+            select(&index, &[("sensor", MatchOp::Equal, "")]),
+            vec!["b-up"]
+        );
+        assert_eq!(index.label_values("sensor"), vec!["humidity", "temp"]);
+        assert!(matches!(
+            Matcher::new("host", MatchOp::Regex, "("),
+            Err(TSLiteError::InvalidRegex(_))
+        ));
+
+        // The index follows the changes of the labels.
+        let path = Path::new(dir).join("b-up.db");
+        let mut db = PhysicalDB::new(&path, None).expect("could not open db.");
+        db.set_labels(&labels(&[("host", "b"), ("sensor", "uptime")]))
+            .expect("could not set labels.");
+        index.insert(&path).expect("could not index db.");
+        assert_eq!(select(&index, &[("sensor", MatchOp::Equal, "")]).len(), 0);
+        index.remove(&path);
+        assert_eq!(index.label_values("sensor"), vec!["humidity", "temp"]);
+        assert_eq!(index.labels(&path), None);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,183 @@
+//! Files written before the format had magic bytes and a version.
+//!
+//! The first versions of the crate wrote a header made of the origin date and the number of records only,
+//! followed by records of a 32bit time offset and a `u8` value:
+//!
+//! ```text
+//! +-------[LEGACY HEADER]-------+   +-------[LEGACY RECORD]------+
+//! |-[TIMESTAMP]-|-[RECORD COUNT]-|   |-[TIME OFFSET]-|-[VALUE]-|
+//! |    56bit    |     64bit      |   |     32bit     |   8bit   |
+//! +-----------------------------+   +----------------------------+
+//! ```
+//!
+//! Such a file is recognized when it is opened and `TSLiteError::LegacyFormat` is returned, so it is not
+//! mistaken for a foreign file. [`PhysicalDB::upgrade_legacy`] converts it to the current format.
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use chrono::{TimeZone, Utc};
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::{replace_file, DbOptions, PhysicalDB, RecordInfo, TSLiteError, Timestamp};
+
+/// The size of the legacy header: 7 for the timestamp, 8 for the record number.
+const LEGACY_HEADER_SIZE: u64 = 7 + 8;
+/// The size of a legacy record: 4 for the time offset, 1 for the value.
+const LEGACY_RECORD_SIZE: u64 = 4 + 1;
+/// The number of records converted at once by [`PhysicalDB::upgrade_legacy`].
+const UPGRADE_BATCH_RECORDS: usize = 4096;
+
+/// Read the origin date and the number of records of a legacy file,
+/// `None` if the file does not look like a legacy DB.
+pub(crate) fn read_legacy_header(mut file: &File) -> Result<Option<(Timestamp, u64)>, TSLiteError> {
+    let len = file
+        .metadata()
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?
+        .len();
+    if len < LEGACY_HEADER_SIZE {
+        return Ok(None);
+    }
+
+    let mut buffer = [0; LEGACY_HEADER_SIZE as usize];
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    file.read_exact(&mut buffer)
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    let origin_date = Timestamp::from(&buffer[..]);
+    let records_number = Cursor::new(&buffer[7..])
+        .read_u64::<LittleEndian>()
+        .unwrap();
+
+    // The date must exist and the counted records must be in the file, since they were written first.
+    let valid_date = Utc
+        .with_ymd_and_hms(
+            origin_date.year as i32,
+            origin_date.month as u32,
+            origin_date.day as u32,
+            origin_date.hour as u32,
+            origin_date.minute as u32,
+            origin_date.second as u32,
+        )
+        .single()
+        .is_some();
+    if !valid_date || (len - LEGACY_HEADER_SIZE) / LEGACY_RECORD_SIZE < records_number {
+        return Ok(None);
+    }
+
+    Ok(Some((origin_date, records_number)))
+}
+
+impl PhysicalDB {
+    /// Convert the legacy DB file at `path` to the current format, see the module documentation.
+    /// The DB keeps its origin date and records, stored as `u8` values with the default options.
+    /// The converted DB is written to a new file which then replaces the legacy one, so an interrupted
+    /// upgrade leaves the legacy file untouched. Records written after the last counted one are dropped.
+    /// If the file is not a legacy DB, `TSLiteError::NotADatabase` is returned.
+    pub fn upgrade_legacy(path: &Path) -> Result<PhysicalDB, TSLiteError> {
+        let file = File::open(path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let (origin_date, records_number) =
+            read_legacy_header(&file)?.ok_or(TSLiteError::NotADatabase)?;
+
+        let mut tmp_path = path.to_path_buf().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let res = PhysicalDB::write_upgraded(&file, &tmp_path, origin_date, records_number);
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        replace_file(&tmp_path, path)?;
+        PhysicalDB::new(path, None)
+    }
+
+    /// Write the records of a legacy file to a new DB at `tmp_path`.
+    fn write_upgraded(
+        mut file: &File,
+        tmp_path: &Path,
+        origin_date: Timestamp,
+        records_number: u64,
+    ) -> Result<(), TSLiteError> {
+        let origin: chrono::DateTime<Utc> = (&origin_date).into();
+        let mut db =
+            PhysicalDB::create_with_options(tmp_path, Some(origin), &DbOptions::default())?;
+
+        file.seek(SeekFrom::Start(LEGACY_HEADER_SIZE))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let mut reader = BufReader::new(file).take(records_number * LEGACY_RECORD_SIZE);
+        let mut batch: Vec<RecordInfo> = Vec::with_capacity(UPGRADE_BATCH_RECORDS);
+        let mut record = [0; LEGACY_RECORD_SIZE as usize];
+        for _ in 0..records_number {
+            reader
+                .read_exact(&mut record)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            let mut cursor = Cursor::new(&record[..]);
+            batch.push(RecordInfo {
+                time_offset: cursor.read_u32::<LittleEndian>().unwrap() as i64,
+                value: cursor.read_u8().unwrap(),
+            });
+            if batch.len() == UPGRADE_BATCH_RECORDS {
+                db.append_records(&batch)?;
+                batch.clear();
+            }
+        }
+        db.append_records(&batch)?;
+
+        db.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbHeader, DbIssue};
+    use byteorder::WriteBytesExt;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn upgrade_legacy_file() {
+        let path = "upgrade_legacy_file.db";
+        let origin_date = Utc.with_ymd_and_hms(2019, 7, 8, 12, 0, 0).unwrap();
+
+        // A legacy DB of three records and a record that was never counted.
+        let mut legacy = Timestamp::try_from(origin_date).unwrap().as_bytes();
+        legacy.write_u64::<LittleEndian>(3).unwrap();
+        for (offset, value) in &[(0u32, 7u8), (60, 8), (120, 9), (180, 10)] {
+            legacy.write_u32::<LittleEndian>(*offset).unwrap();
+            legacy.write_u8(*value).unwrap();
+        }
+        fs::write(path, &legacy).unwrap();
+
+        assert_eq!(
+            PhysicalDB::new(Path::new(path), None).err(),
+            Some(TSLiteError::LegacyFormat)
+        );
+        assert_eq!(fs::read(path).unwrap(), legacy);
+
+        let mut db = PhysicalDB::upgrade_legacy(Path::new(path)).expect("could not upgrade db.");
+        assert_eq!(db.header.version, DbHeader::FORMAT_VERSION);
+        assert_eq!(
+            db.header.origin_date,
+            Timestamp::try_from(origin_date).unwrap()
+        );
+        assert_eq!(db.header.records_number, 3);
+        assert_eq!(
+            db.read_record(2),
+            Ok(RecordInfo {
+                time_offset: 120,
+                value: 9u8
+            })
+        );
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert!(!Path::new("upgrade_legacy_file.db.tmp").exists());
+
+        // A file that is already upgraded is not a legacy DB.
+        assert_eq!(
+            PhysicalDB::upgrade_legacy(Path::new(path)).err(),
+            Some(TSLiteError::NotADatabase)
+        );
+
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,12 +1,18 @@
 //! A very simple embedded time-serie database.
 //!
-//! Right now you can only store data that fit in one octet.
+//! Each DB holds values of a single type, declared in its header when the DB is created.
+//! See [`ValueType`] for the supported types.
 //!
 //! All the operation are made directly on the DB file, so this can get very I/O intensive if you do a lot of operation.
 //! If you are going to push data and read data a lot, you really shouldn't use it directly.
 //!
 //! If you intend to do a lot of operation you should have an layer that will operate in-memory and periodically
-//! dump them to the filesystem.
+//! dump them to the filesystem. [`BufferedDB`] does exactly that.
+//!
+//! A DB is a single file that only grows. To keep old records cheap to drop, a series can be split
+//! across several files each covering a window of time with [`SegmentedDB`]. To keep a series at
+//! several resolutions in a fixed amount of space, use [`RoundRobinDB`]. Many series can be kept
+//! together in a directory and used by name with [`Database`].
 //!
 //! # DB encoding
 //!
@@ -14,6 +20,10 @@
 //! We will store records in db in a way that the latest (as in, its actual time) record will always be at the end of the file.
 //! But we should do something that will periodicly check the sanity of the DB and fix mistakes (i.e, sort the whole DB).
 //! This could be definitly be easier by holding the DB in memory and doing any I/O in memory before the DB is commited to the file.
+//! Late records can also be inserted at their place with [`PhysicalDB::insert_record`], which only rewrites
+//! the records following them.
+//! Records are deleted with [`PhysicalDB::delete_record`] or [`PhysicalDB::delete_range`], which only mark
+//! them as deleted, and removed from the file with [`PhysicalDB::compact`].
 //!
 //!
 //! # File orga
@@ -25,41 +35,184 @@
 //! ```
 //!
 //! ```text
-//! +-------------------------------------------[HEADER]---------------------------------------------+
-//! |--------------------------[TIMESTAMP]------------------------|---------[RECORD COUNT]-----------|
-//! |      year      |  month |  day   |  hour  | minute | second |              64bit               |
-//! |     16bit      |  8bit  |  8bit  |  8bit  |  8bit  |  8bit  |                                  |
-//! +------------------------------------------------------------------------------------------------+
+//! +---------------------------------------------------[HEADER]---------------------------------------------------+
+//! |-[MAGIC]-|-[VERSION]-|-[HEADER LENGTH]-|-[TIMESTAMP]-|-[VALUE TYPE]-|-[COMMIT SLOTS]-|-[FLAGS]-|-[PRECISION]-|-[STORAGE]-|-[RETENTION]-|-[CAPACITY]-|-[LABELS]-|
+//! | "TSLT"  |   16bit   |      16bit      |    56bit    |     8bit     |    2 x 160bit  |   8bit  |     8bit    |    8bit   |   2 x 64bit |    64bit   |          |
+//! +--------------------------------------------------------------------------------------------------------------+
+//! ```
+//!
+//! The magic bytes are used to make sure we are opening a TSLite DB. The version is the version of the
+//! file format, a file with another version will not be opened. The header length is the size of the whole
+//! header in octet, the records start right after it. Files written before the magic bytes existed, the
+//! first version of the format, are recognized and can be converted with [`PhysicalDB::upgrade_legacy`].
+//!
+//! The number of records is stored in two commit slots.
+//!
+//! ```text
+//! +-----------------[COMMIT SLOT]--------------+
+//! |-[SEQUENCE]-|-[RECORD COUNT]-|-[CHECKSUM]-|
+//! |    64bit   |     64bit      |    32bit    |
+//! +--------------------------------------------+
+//! ```
+//!
+//! Appending records is done in two steps: the records are written and synced after the last committed record,
+//! then the new number of records is written in the oldest slot with a bigger sequence number, and synced.
+//! The checksum is the CRC32 of the sequence and the record count. When a DB is opened, the valid slot with
+//! the biggest sequence is used, and anything written after the last committed record is discarded.
+//! So a crash in the middle of an append, even while writing a slot, leaves the DB as it was before the append.
+//!
+//! ```text
+//! +--------------------------[TIMESTAMP]------------------------+
+//! |      year      |  month |  day   |  hour  | minute | second |
+//! |     16bit      |  8bit  |  8bit  |  8bit  |  8bit  |  8bit  |
+//! +-------------------------------------------------------------+
 //! ```
 //!
+//! The flags are the options of the DB that change how records are stored, see [`DbOptions`].
+//! The first bit tells if records have a checksum, the second one if time offsets are stored on 64bit.
+//! The precision is the unit of the time offset of the records, see [`Precision`].
+//! The retention policy is the maximal age of the records, in time units, and the maximal size of the file,
+//! in octets, 0 meaning no limit, see [`Retention`].
+//! The capacity is the maximal number of records of a ring DB, 0 meaning the DB is not a ring.
+//! The commit slots of a ring hold the number of records it has ever held, see [`DbOptions::capacity`].
+//! The labels fill the rest of the header, their size is given by the header length, see [`Labels`].
+//! The storage tells how the records are laid out after the header, see [`Storage`]. The records of a
+//! DB using the plain storage are stored one after the other like this:
+//!
 //! ```text
-//! +-------------------[RECORD]------------+
-//! |--------[TIME OFFSET]--------|-[VALUE]-|
-//! |            32bit            |   8bit  |
-//! +---------------------------------------+
+//! +-------------------[RECORD]-----------------------+
+//! |--------[TIME OFFSET]--------|-[VALUE]-|-[CRC32]-|
+//! |         32bit or 64bit      | 8-64bit |  32bit  |
+//! +--------------------------------------------------+
 //! ```
+//!
+//! The time offset is an unsigned 32bit integer, or a signed 64bit integer if the DB uses wide offsets.
+//! The size of the value depends on the value type of the DB, so every record of a DB has the same size.
+//! The checksum is optional, it is the CRC32 of the time offset and the value and is checked every time
+//! the record is read.
+//!
+//! With the compressed storage, records are encoded by blocks of [`COMPRESSED_BLOCK_RECORDS`] records.
+//! Time offsets are stored as delta-of-delta and values as the difference (or the XOR for floating point
+//! values) with the previous value, all written as variable length integers. So records taken at a regular
+//! interval with slowly changing values only take a few octets each. Since blocks have a variable size,
+//! the position and first time offset of each block are kept in an index next to the DB file, named
+//! after it with `.idx` appended. The index can be rebuilt from the DB file if it is lost.
+//!
+//! With the run-length encoded storage, consecutive records with the same value are stored as a single
+//! run of fixed size, holding the index of its first record, the time offsets of its first and last
+//! records and their value. A run only holds records taken at a regular interval, so the time offsets
+//! of the records in between are computed from them.
+//! A series of state flags only takes one run per change of state, however often it is sampled.
 
 extern crate chrono;
 
+mod aggregate;
+mod archive;
+mod buffered;
+mod compressed;
+mod database;
+mod insert;
+mod iter;
+mod labels;
+mod legacy;
+mod precision;
+mod retention;
+mod rewrite;
+mod ring;
+mod rle;
+mod segmented;
+mod sort;
+mod storage;
+mod tombstone;
+mod value;
+mod verify;
+
+pub use aggregate::{Aggregate, Aggregation, Bucket, Fill};
+pub use archive::{Archive, Fetched, RoundRobinDB};
+pub use buffered::BufferedDB;
+pub use compressed::COMPRESSED_BLOCK_RECORDS;
+pub use database::{Database, SeriesInfo};
+pub use insert::REORDER_WINDOW;
+pub use iter::{RecordIter, BLOCK_RECORDS};
+pub use labels::{LabelIndex, Labels, MatchOp, Matcher};
+pub use precision::Precision;
+pub use retention::{Retention, RETENTION_SLACK};
+pub use segmented::SegmentedDB;
+pub use sort::SORT_RUN_RECORDS;
+pub use storage::Storage;
+pub use value::{Value, ValueType};
+pub use verify::{Finding, IntegrityReport};
+
+use aggregate::Buckets;
+use iter::RawRecords;
+use tombstone::Tombstones;
+
 use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::string::String;
 
 use std::cmp::{Ord, Ordering};
+use std::convert::TryFrom;
 
 /// A wrapper for various type of error that can occur within TSLite.
 #[derive(Debug, PartialEq)]
 pub enum TSLiteError {
     IOError(String),
     IndexOutOfBound,
+    /// The value type code found in a header is not known.
+    UnknownValueType(u8),
+    /// The precision code found in a header is not known.
+    UnknownPrecision(u8),
+    /// The storage code found in a header is not known.
+    UnknownStorage(u8),
+    /// The value type requested does not match the value type of the DB.
+    TypeMismatch,
+    /// A time interval is not a strictly positive number of time units of the DB.
+    InvalidInterval,
+    /// The file does not start with the TSLite magic bytes.
+    NotADatabase,
+    /// The file was written before the format had magic bytes, it can be converted with
+    /// [`PhysicalDB::upgrade_legacy`].
+    LegacyFormat,
+    /// The file format version is not supported by this version of the crate.
+    UnsupportedVersion(u16),
+    /// The checksum of a record does not match its content, with the index of the record.
+    ChecksumMismatch(u64),
+    /// The time offset of a record cannot be stored in the DB: it is negative or too big
+    /// and the DB does not use wide offsets, or the date of the record cannot be represented.
+    TimeOffsetOutOfRange(i64),
+    /// The date cannot be represented by a [`Timestamp`], its year being negative or too big.
+    DateOutOfRange,
+    /// The options given to create a DB cannot be used together.
+    IncompatibleOptions,
+    /// The operation is not supported by the storage of the DB.
+    UnsupportedOperation,
+    /// No series has this name in the catalog of the [`Database`].
+    UnknownSeries(String),
+    /// A series with this name already exists in the catalog of the [`Database`].
+    SeriesAlreadyExists(String),
+    /// A series name must be made of ASCII letters, digits, `_`, `-` and `.`, and cannot start with a `.`.
+    InvalidSeriesName(String),
+    /// A label name must be made of ASCII letters, digits and `_`, and cannot start with a digit.
+    InvalidLabelName(String),
+    /// The labels do not fit in the header of the DB.
+    LabelsTooLarge,
+    /// The regular expression of a [`Matcher`] cannot be compiled, with the reason.
+    InvalidRegex(String),
+    /// The record has been deleted, with its index, see [`PhysicalDB::delete_record`].
+    DeletedRecord(u64),
+    /// The span of a [`SegmentedDB`] is not the one it was created with,
+    /// which is given in time units of the DB.
+    SpanMismatch(i64),
 }
 
 /// A way to store date and time in 56bits / 7 octets.
 /// There is no awareness of timezone, everything is assumed to be Utc+0.
+/// `nanosecond` is the fraction of the second, it is not stored in the file.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Timestamp {
     pub year: u16,
@@ -68,18 +221,22 @@ pub struct Timestamp {
     pub hour: u8,
     pub minute: u8,
     pub second: u8,
+    pub nanosecond: u32,
 }
 
-impl From<chrono::DateTime<Utc>> for Timestamp {
-    fn from(d: chrono::DateTime<Utc>) -> Timestamp {
-        Timestamp {
-            year: d.year() as u16,
+impl TryFrom<chrono::DateTime<Utc>> for Timestamp {
+    type Error = TSLiteError;
+
+    fn try_from(d: chrono::DateTime<Utc>) -> Result<Timestamp, TSLiteError> {
+        Ok(Timestamp {
+            year: u16::try_from(d.year()).map_err(|_| TSLiteError::DateOutOfRange)?,
             month: d.month() as u8,
             day: d.day() as u8,
             hour: d.hour() as u8,
             minute: d.minute() as u8,
             second: d.second() as u8,
-        }
+            nanosecond: d.nanosecond(),
+        })
     }
 }
 
@@ -93,21 +250,14 @@ impl From<&[u8]> for Timestamp {
             hour: reader.read_u8().unwrap(),
             minute: reader.read_u8().unwrap(),
             second: reader.read_u8().unwrap(),
+            nanosecond: 0,
         }
     }
 }
 
 impl PartialOrd for Timestamp {
     fn partial_cmp(&self, other: &Timestamp) -> Option<Ordering> {
-        Some(
-            self.year
-                .cmp(&other.year)
-                .then(self.month.cmp(&other.month))
-                .then(self.day.cmp(&other.day))
-                .then(self.hour.cmp(&other.hour))
-                .then(self.minute.cmp(&other.minute))
-                .then(self.second.cmp(&other.second)),
-        )
+        Some(self.cmp(other))
     }
 }
 
@@ -120,13 +270,22 @@ impl Ord for Timestamp {
             .then(self.hour.cmp(&other.hour))
             .then(self.minute.cmp(&other.minute))
             .then(self.second.cmp(&other.second))
+            .then(self.nanosecond.cmp(&other.nanosecond))
     }
 }
 
-impl Into<DateTime<Utc>> for &Timestamp {
-    fn into(self) -> DateTime<Utc> {
-        Utc.ymd(self.year as i32, self.month as u32, self.day as u32)
-            .and_hms(self.hour as u32, self.minute as u32, self.second as u32)
+impl From<&Timestamp> for DateTime<Utc> {
+    fn from(t: &Timestamp) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(
+            t.year as i32,
+            t.month as u32,
+            t.day as u32,
+            t.hour as u32,
+            t.minute as u32,
+            t.second as u32,
+        )
+        .unwrap()
+            + chrono::Duration::nanoseconds(t.nanosecond as i64)
     }
 }
 
@@ -142,11 +301,30 @@ impl Timestamp {
         store
     }
 
-    /// Compute the number of second between two date.
-    pub fn offset(&self, date: &Timestamp) -> u32 {
+    /// Compute the number of second between two date, negative if `date` is anterior to this one.
+    pub fn offset(&self, date: &Timestamp) -> i64 {
+        self.offset_in(date, Precision::Seconds)
+    }
+
+    /// Compute the number of time units of `precision` between two date,
+    /// negative if `date` is anterior to this one.
+    pub fn offset_in(&self, date: &Timestamp, precision: Precision) -> i64 {
         let me: DateTime<Utc> = self.into();
         let other: DateTime<Utc> = date.into();
-        (other - me).num_seconds() as u32
+        precision.units(other - me)
+    }
+
+    /// Compute the date `offset` seconds after this one, `None` if it cannot be represented.
+    pub fn shift(&self, offset: i64) -> Option<Timestamp> {
+        self.shift_in(offset, Precision::Seconds)
+    }
+
+    /// Compute the date `offset` time units of `precision` after this one,
+    /// `None` if it cannot be represented.
+    pub fn shift_in(&self, offset: i64, precision: Precision) -> Option<Timestamp> {
+        let me: DateTime<Utc> = self.into();
+        let date = me.checked_add_signed(precision.checked_duration(offset)?)?;
+        Timestamp::try_from(date).ok()
     }
 
     /// Check if a date is valid.
@@ -157,6 +335,7 @@ impl Timestamp {
         valid &= self.hour < 24;
         valid &= self.minute < 60;
         valid &= self.second < 60;
+        valid &= self.nanosecond < 1_000_000_000;
 
         // As usual, we have to handle febuary as an edge-case.
         if self.month == 2 {
@@ -178,74 +357,456 @@ impl Timestamp {
 }
 
 /// Represent an entry in the database.
-/// `time_offset` represent the number of seconds passed since the origin date of the DB.
-/// It's a u32, which means you should be able to store record up to 136 years after the origin date of the DB.
+/// `time_offset` represent the number of time units passed since the origin date of the DB,
+/// seconds unless the DB was created with another [`Precision`].
+/// Unless the DB uses wide offsets, it is stored as a u32, which means with a precision of one second
+/// you should be able to store record up to 136 years after the origin date of the DB, and not before it.
+/// With wide offsets, it is stored as is and can be negative.
+/// `value` can be any type implementing [`Value`], but it must match the value type of the DB.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct RecordInfo {
-    pub time_offset: u32,
-    pub value: u8,
+pub struct RecordInfo<V: Value = u8> {
+    pub time_offset: i64,
+    pub value: V,
 }
 
-impl From<&[u8]> for RecordInfo {
-    fn from(d: &[u8]) -> RecordInfo {
-        let mut reader = Cursor::new(d);
-        RecordInfo {
-            time_offset: reader.read_u32::<LittleEndian>().unwrap(),
-            value: reader.read_u8().unwrap(),
-        }
+impl<V: Value + Eq> PartialOrd for RecordInfo<V> {
+    fn partial_cmp(&self, other: &RecordInfo<V>) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-impl PartialOrd for RecordInfo {
-    fn partial_cmp(&self, other: &RecordInfo) -> Option<Ordering> {
-        Some(self.time_offset.cmp(&other.time_offset))
-    }
-}
-
-impl Ord for RecordInfo {
+impl<V: Value + Eq> Ord for RecordInfo<V> {
     fn cmp(&self, other: &Self) -> Ordering {
         self.time_offset.cmp(&other.time_offset)
     }
 }
 
-impl RecordInfo {
-    pub fn as_bytes(&self) -> Vec<u8> {
-        let mut store: Vec<u8> = Vec::with_capacity(4 + 1); // 4 time_offset, 1 value
-        store.write_u32::<LittleEndian>(self.time_offset).unwrap();
-        store.write_u8(self.value).unwrap();
-        store
-    }
-}
-
 /// The header of a DB file.
+/// `version` is the version of the file format and `header_len` the size of the header in the file.
 /// `origin_date` is the date that will be use has the origin. The DB *cannot* contain any record anterior to this date.
+/// `value_type` is the type of the value of every record in the DB.
+/// `commit_sequence` is the sequence number of the last commit of `records_number`, see the crate documentation.
+/// `checksums` tells if every record is followed by its CRC32.
+/// `wide_offsets` tells if time offsets are stored as signed 64bit integers instead of unsigned 32bit ones.
+/// `precision` is the unit of the time offset of the records.
+/// `storage` is how the records are laid out in the file.
+/// `retention` is how long and how many records are kept.
+/// `capacity` is the maximal number of records of a ring DB, and `overwritten` the number of records
+/// it has overwritten since it was created, see [`DbOptions::capacity`].
 #[derive(Debug, Copy, Clone)]
 pub struct DbHeader {
+    pub version: u16,
+    pub header_len: u16,
     pub origin_date: Timestamp,
     pub records_number: u64,
+    pub value_type: ValueType,
+    pub commit_sequence: u64,
+    pub checksums: bool,
+    pub wide_offsets: bool,
+    pub precision: Precision,
+    pub storage: Storage,
+    pub retention: Retention,
+    pub capacity: Option<u64>,
+    pub overwritten: u64,
 }
 
-impl From<&[u8]> for DbHeader {
-    fn from(d: &[u8]) -> DbHeader {
-        let timestamp = Timestamp::from(d);
+/// The options of a DB, chosen when it is created.
+/// The retention policy can be changed later with [`PhysicalDB::set_retention`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DbOptions {
+    /// The type of the values stored in the DB.
+    pub value_type: ValueType,
+    /// Store a CRC32 with every record, checked every time the record is read.
+    /// It takes 4 more octets per record.
+    pub checksums: bool,
+    /// Store the time offsets as signed 64bit integers, so records can be anterior to the origin date
+    /// and there is no practical limit on how far from it they can be. It takes 4 more octets per record.
+    pub wide_offsets: bool,
+    /// The unit of the time offset of the records.
+    pub precision: Precision,
+    /// How the records are laid out in the file.
+    /// Only the plain storage can be used with checksums.
+    pub storage: Storage,
+    /// How long and how many records are kept, see [`Retention`].
+    pub retention: Retention,
+    /// Make the DB a ring holding at most this number of records: once it is full, every new record
+    /// overwrites the oldest one and the file never grows. It can only be used with the plain storage
+    /// and without retention policy.
+    pub capacity: Option<u64>,
+}
+
+impl Default for DbOptions {
+    fn default() -> DbOptions {
+        DbOptions {
+            value_type: ValueType::U8,
+            checksums: false,
+            wide_offsets: false,
+            precision: Precision::Seconds,
+            storage: Storage::Plain,
+            retention: Retention::default(),
+            capacity: None,
+        }
+    }
+}
+
+/// One of the two slots holding the number of records in the header.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct CommitSlot {
+    sequence: u64,
+    records_number: u64,
+}
+
+impl CommitSlot {
+    /// The size of a slot: 8 for the sequence, 8 for the record count, 4 for the checksum.
+    const SIZE: u64 = 8 + 8 + 4;
+
+    fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.sequence.to_le_bytes());
+        hasher.update(&self.records_number.to_le_bytes());
+        hasher.finalize()
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut store: Vec<u8> = Vec::with_capacity(CommitSlot::SIZE as usize);
+        store.write_u64::<LittleEndian>(self.sequence).unwrap();
+        store
+            .write_u64::<LittleEndian>(self.records_number)
+            .unwrap();
+        store.write_u32::<LittleEndian>(self.checksum()).unwrap();
+        store
+    }
+
+    /// Read a slot, `None` if its checksum is wrong.
+    fn from_bytes(d: &[u8]) -> Option<CommitSlot> {
         let mut reader = Cursor::new(d);
-        reader.set_position(7);
-        DbHeader {
-            origin_date: timestamp,
+        let slot = CommitSlot {
+            sequence: reader.read_u64::<LittleEndian>().unwrap(),
             records_number: reader.read_u64::<LittleEndian>().unwrap(),
+        };
+        if reader.read_u32::<LittleEndian>().unwrap() != slot.checksum() {
+            return None;
+        }
+
+        Some(slot)
+    }
+}
+
+impl TryFrom<&[u8]> for DbHeader {
+    type Error = TSLiteError;
+
+    fn try_from(d: &[u8]) -> Result<DbHeader, TSLiteError> {
+        let (version, header_len) = DbHeader::check_preamble(d)?;
+        if (header_len as usize) > d.len() {
+            return Err(TSLiteError::IOError(
+                "Could not read header: not enough octets.".to_string(),
+            ));
+        }
+
+        let timestamp = Timestamp::from(&d[DbHeader::PREAMBLE_SIZE as usize..]);
+        let mut reader = Cursor::new(d);
+        reader.set_position(DbHeader::VALUE_TYPE_POS);
+        let value_type = ValueType::try_from(reader.read_u8().unwrap())?;
+
+        // The number of records is in the valid slot with the biggest sequence.
+        let slot = (0..2)
+            .filter_map(|i| {
+                let pos = DbHeader::slot_pos(i) as usize;
+                CommitSlot::from_bytes(&d[pos..pos + CommitSlot::SIZE as usize])
+            })
+            .max_by_key(|slot| slot.sequence)
+            .ok_or_else(|| TSLiteError::IOError("DB File header is corrupted.".to_string()))?;
+        let mut records_number = slot.records_number;
+
+        reader.set_position(DbHeader::FLAGS_POS);
+        let flags = reader.read_u8().unwrap();
+        let precision = Precision::try_from(reader.read_u8().unwrap())?;
+        let storage = Storage::try_from(reader.read_u8().unwrap())?;
+        let pos = DbHeader::RETENTION_POS as usize;
+        let retention = Retention::from_bytes(&d[pos..pos + Retention::SIZE as usize], precision);
+
+        // A ring commits the number of records it has ever held, the oldest ones being overwritten.
+        reader.set_position(DbHeader::CAPACITY_POS);
+        let capacity = Some(reader.read_u64::<LittleEndian>().unwrap()).filter(|c| *c > 0);
+        let mut overwritten = 0;
+        if let Some(capacity) = capacity {
+            overwritten = records_number.saturating_sub(capacity);
+            records_number -= overwritten;
         }
+
+        Ok(DbHeader {
+            version,
+            header_len,
+            origin_date: timestamp,
+            records_number,
+            value_type,
+            commit_sequence: slot.sequence,
+            checksums: flags & DbHeader::FLAG_CHECKSUMS != 0,
+            wide_offsets: flags & DbHeader::FLAG_WIDE_OFFSETS != 0,
+            precision,
+            storage,
+            retention,
+            capacity,
+            overwritten,
+        })
     }
 }
 
 impl DbHeader {
+    /// The magic bytes every DB file starts with.
+    pub const MAGIC: [u8; 4] = *b"TSLT";
+    /// The version of the file format supported by the crate, the files written before the magic bytes
+    /// existed being the first one.
+    pub const FORMAT_VERSION: u16 = 2;
+    /// The size of the header without the labels:
+    /// 4 for the magic bytes, 2 for the version, 2 for the header length,
+    /// 7 for timestamp, 1 for value type, 2 commit slots, 1 for the flags,
+    /// 1 for the precision, 1 for the storage, 16 for the retention policy, 8 for the capacity.
+    pub const SIZE: u64 =
+        4 + 2 + 2 + 7 + 1 + 2 * CommitSlot::SIZE + 1 + 1 + 1 + Retention::SIZE + 8;
+    /// The size of the part of the header needed to know how to read the rest of it.
+    const PREAMBLE_SIZE: u64 = 4 + 2 + 2;
+    /// The position of the value type within the file.
+    const VALUE_TYPE_POS: u64 = DbHeader::PREAMBLE_SIZE + 7;
+    /// The position of the first commit slot within the file.
+    const SLOTS_POS: u64 = DbHeader::VALUE_TYPE_POS + 1;
+    /// The position of the flags within the file.
+    const FLAGS_POS: u64 = DbHeader::SLOTS_POS + 2 * CommitSlot::SIZE;
+    /// Flag set if records have a checksum.
+    const FLAG_CHECKSUMS: u8 = 1;
+    /// Flag set if time offsets are stored on 64bit.
+    const FLAG_WIDE_OFFSETS: u8 = 1 << 1;
+    /// The position of the precision within the file.
+    const PRECISION_POS: u64 = DbHeader::FLAGS_POS + 1;
+    /// The position of the storage within the file.
+    const STORAGE_POS: u64 = DbHeader::PRECISION_POS + 1;
+    /// The position of the retention policy within the file.
+    const RETENTION_POS: u64 = DbHeader::STORAGE_POS + 1;
+    /// The position of the capacity within the file.
+    const CAPACITY_POS: u64 = DbHeader::RETENTION_POS + Retention::SIZE;
+    /// The position of the labels within the file.
+    const LABELS_POS: u64 = DbHeader::CAPACITY_POS + 8;
+
+    /// Create the header of an empty DB.
+    /// The fraction of second of `origin_date` is dropped since it is not stored in the file.
+    pub fn new(origin_date: Timestamp, options: &DbOptions) -> DbHeader {
+        DbHeader {
+            version: DbHeader::FORMAT_VERSION,
+            header_len: DbHeader::SIZE as u16,
+            origin_date: Timestamp {
+                nanosecond: 0,
+                ..origin_date
+            },
+            records_number: 0,
+            value_type: options.value_type,
+            commit_sequence: 0,
+            checksums: options.checksums,
+            wide_offsets: options.wide_offsets,
+            precision: options.precision,
+            storage: options.storage,
+            retention: options.retention.truncated(options.precision),
+            capacity: options.capacity,
+            overwritten: 0,
+        }
+    }
+
+    /// The options the DB was created with.
+    pub fn options(&self) -> DbOptions {
+        DbOptions {
+            value_type: self.value_type,
+            checksums: self.checksums,
+            wide_offsets: self.wide_offsets,
+            precision: self.precision,
+            storage: self.storage,
+            retention: self.retention,
+            capacity: self.capacity,
+        }
+    }
+
+    /// The position of a commit slot within the file.
+    fn slot_pos(slot: u64) -> u64 {
+        DbHeader::SLOTS_POS + slot * CommitSlot::SIZE
+    }
+
+    /// Check the magic bytes and the version at the start of a header.
+    /// Return the version and the length of the header.
+    fn check_preamble(d: &[u8]) -> Result<(u16, u16), TSLiteError> {
+        if d.len() < DbHeader::PREAMBLE_SIZE as usize || d[0..4] != DbHeader::MAGIC {
+            return Err(TSLiteError::NotADatabase);
+        }
+
+        let mut reader = Cursor::new(&d[4..]);
+        let version = reader.read_u16::<LittleEndian>().unwrap();
+        if version != DbHeader::FORMAT_VERSION {
+            return Err(TSLiteError::UnsupportedVersion(version));
+        }
+
+        let header_len = reader.read_u16::<LittleEndian>().unwrap();
+        if (header_len as u64) < DbHeader::SIZE {
+            return Err(TSLiteError::IOError(
+                "DB File header is corrupted.".to_string(),
+            ));
+        }
+
+        Ok((version, header_len))
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut store: Vec<u8> = Vec::with_capacity(7 + 8); // 7 for timestamp, 8 for record number.
+        let mut store: Vec<u8> = Vec::with_capacity(self.header_len as usize);
+        store.extend(&DbHeader::MAGIC);
+        store.write_u16::<LittleEndian>(self.version).unwrap();
+        store.write_u16::<LittleEndian>(self.header_len).unwrap();
         store.extend(self.origin_date.as_bytes());
+        store.write_u8(self.value_type.code()).unwrap();
+        // Both slots hold the same commit, so either of them can be overwritten first.
+        let slot = CommitSlot {
+            sequence: self.commit_sequence,
+            records_number: self.overwritten + self.records_number,
+        };
+        store.extend(slot.as_bytes());
+        store.extend(slot.as_bytes());
+        let mut flags = 0;
+        if self.checksums {
+            flags |= DbHeader::FLAG_CHECKSUMS;
+        }
+        if self.wide_offsets {
+            flags |= DbHeader::FLAG_WIDE_OFFSETS;
+        }
+        store.write_u8(flags).unwrap();
+        store.write_u8(self.precision.code()).unwrap();
+        store.write_u8(self.storage.code()).unwrap();
+        store.extend(self.retention.as_bytes(self.precision));
         store
-            .write_u64::<LittleEndian>(self.records_number)
+            .write_u64::<LittleEndian>(self.capacity.unwrap_or(0))
             .unwrap();
+        // The labels are written separately, their space is left empty.
+        store.resize(self.header_len as usize, 0);
         store
     }
+
+    /// The date of a record with the time offset `time_offset`.
+    /// If it cannot be represented, `TSLiteError::TimeOffsetOutOfRange` is returned.
+    pub fn record_date(&self, time_offset: i64) -> Result<Timestamp, TSLiteError> {
+        self.origin_date
+            .shift_in(time_offset, self.precision)
+            .ok_or(TSLiteError::TimeOffsetOutOfRange(time_offset))
+    }
+
+    /// The size of the time offset of a record in the file, or once decoded for compressed DBs.
+    fn offset_size(&self) -> u64 {
+        if self.wide_offsets {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// The size of one record in the file: the time offset, then the value and 4 for the checksum if any.
+    pub fn record_size(&self) -> u64 {
+        let checksum = if self.checksums { 4 } else { 0 };
+        self.offset_size() + self.value_type.size() + checksum
+    }
+
+    /// Check that a time offset can be stored in the DB.
+    /// Without wide offsets, it must fit in a u32, so records cannot be anterior to the origin date.
+    /// In any case, the date of the record must be representable, see [`DbHeader::record_date`].
+    pub fn check_time_offset(&self, time_offset: i64) -> Result<(), TSLiteError> {
+        if !self.wide_offsets && u32::try_from(time_offset).is_err() {
+            return Err(TSLiteError::TimeOffsetOutOfRange(time_offset));
+        }
+
+        self.record_date(time_offset).map(|_| ())
+    }
+
+    /// Encode a record as it is stored in the file, with its checksum if needed.
+    /// The time offset must have been checked with [`DbHeader::check_time_offset`].
+    pub fn encode_record<V: Value>(&self, record: &RecordInfo<V>) -> Vec<u8> {
+        let mut store: Vec<u8> = Vec::with_capacity(self.record_size() as usize);
+        if self.wide_offsets {
+            store.write_i64::<LittleEndian>(record.time_offset).unwrap();
+        } else {
+            store
+                .write_u32::<LittleEndian>(record.time_offset as u32)
+                .unwrap();
+        }
+        record.value.write_to(&mut store);
+        if self.checksums {
+            let checksum = crc32fast::hash(&store);
+            store.write_u32::<LittleEndian>(checksum).unwrap();
+        }
+        store
+    }
+
+    /// Decode a record read from the file.
+    pub fn decode_record<V: Value>(&self, record: &[u8]) -> RecordInfo<V> {
+        let mut reader = Cursor::new(record);
+        reader.set_position(self.offset_size());
+        RecordInfo {
+            time_offset: self.raw_time_offset(record),
+            value: V::read_from(&mut reader),
+        }
+    }
+
+    /// Build the raw octets of a record, as stored with the plain storage without checksum.
+    pub(crate) fn raw_record(&self, time_offset: i64, value: &[u8]) -> Vec<u8> {
+        let mut store: Vec<u8> = Vec::with_capacity(self.offset_size() as usize + value.len());
+        if self.wide_offsets {
+            store.write_i64::<LittleEndian>(time_offset).unwrap();
+        } else {
+            store.write_u32::<LittleEndian>(time_offset as u32).unwrap();
+        }
+        store.extend(value);
+        store
+    }
+
+    /// Read the time offset at the start of the raw octets of a record, whatever the value type of the DB is.
+    pub fn raw_time_offset(&self, record: &[u8]) -> i64 {
+        let mut reader = Cursor::new(record);
+        if self.wide_offsets {
+            reader.read_i64::<LittleEndian>().unwrap()
+        } else {
+            reader.read_u32::<LittleEndian>().unwrap() as i64
+        }
+    }
+
+    /// Check the checksum of the record `rec_id` read from the file, if there is one.
+    pub fn check_record(&self, rec_id: u64, record: &[u8]) -> Result<(), TSLiteError> {
+        if !self.checksums {
+            return Ok(());
+        }
+
+        let (data, checksum) = record.split_at(record.len() - 4);
+        if Cursor::new(checksum).read_u32::<LittleEndian>().unwrap() != crc32fast::hash(data) {
+            return Err(TSLiteError::ChecksumMismatch(rec_id));
+        }
+
+        Ok(())
+    }
+
+    /// Convert a date to the time offset of the first record that can be at or after this date.
+    /// The result can only be negative if the DB uses wide offsets, since other DBs cannot contain
+    /// any record anterior to their origin date.
+    pub(crate) fn offset_from_date(&self, date: DateTime<Utc>) -> i64 {
+        let origin: DateTime<Utc> = (&self.origin_date).into();
+        let diff = date - origin;
+        let precision = self.precision;
+        let mut units = precision.units(diff);
+        // Records only have the precision of the DB, so we round up any leftover.
+        if diff > precision.duration(units) {
+            units += 1;
+        }
+        if !self.wide_offsets {
+            units = units.max(0);
+        }
+
+        units
+    }
+
+    /// The position of a record within the file, only meaningful with the plain storage.
+    /// The records of a ring wrap around its slots, see [`DbHeader::ring_slot`].
+    pub fn record_pos(&self, rec_id: u64) -> u64 {
+        self.header_len as u64 + self.record_size() * self.ring_slot(rec_id)
+    }
 }
 
 /// Potential Issue in the DB file
@@ -265,82 +826,223 @@ pub enum DbIssue {
     None,
 }
 
+/// A fix made (or planned, in dry-run mode) by [`PhysicalDB::repair`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Repair {
+    /// The end of the file did not hold a full record, these octets were removed.
+    TruncatePartialRecord { octets: u64 },
+    /// The header counted more records than the file holds.
+    SetRecordsNumber { from: u64, to: u64 },
+    /// The records were not chronologically ordered and have been sorted.
+    SortRecords,
+}
+
+/// Read the header at the start of a DB file.
+/// The preamble is read first to know the length of the whole header.
+fn read_header_from(mut file: &File) -> Result<DbHeader, TSLiteError> {
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    let mut buffer = vec![0; DbHeader::PREAMBLE_SIZE as usize];
+    let n = file
+        .read(&mut buffer[..])
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    buffer.truncate(n);
+    let (_, header_len) = match DbHeader::check_preamble(&buffer) {
+        Err(TSLiteError::NotADatabase) if legacy::read_legacy_header(file)?.is_some() => {
+            return Err(TSLiteError::LegacyFormat)
+        }
+        res => res?,
+    };
+
+    buffer.resize(header_len as usize, 0);
+    let n = file
+        .read(&mut buffer[DbHeader::PREAMBLE_SIZE as usize..])
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    if n + (DbHeader::PREAMBLE_SIZE as usize) < header_len as usize {
+        return Err(TSLiteError::IOError(
+            "Could not read header: not enough octets.".to_string(),
+        ));
+    }
+
+    DbHeader::try_from(&buffer[..])
+}
+
+/// Replace the file at `path` with the file at `tmp_path`, which must already be synced.
+/// The directory holding them is synced too, so the replacement is not lost on power loss.
+pub(crate) fn replace_file(tmp_path: &Path, path: &Path) -> Result<(), TSLiteError> {
+    fs::rename(tmp_path, path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    // Directories cannot be opened as files everywhere, there is nothing more to do there.
+    if cfg!(unix) {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    }
+
+    Ok(())
+}
+
 /// a DB in file
 #[derive(Debug)]
 pub struct PhysicalDB {
     pub path: PathBuf,
     pub file: Option<File>,
     pub header: DbHeader,
+    tombstones: Tombstones,
 }
 
 impl PhysicalDB {
     /// This function will create a new database file or open it if it already exists.
     /// The second argument the date with which to initialize the database. It is optional, if you give `None`
     /// it will use the current date and time. If the file exists, the date is ignored complitely.
+    /// The database will store `u8` values, use [`PhysicalDB::new_with_type`] to store another type of value.
     pub fn new(
         path: &Path,
         origin_date: Option<chrono::DateTime<Utc>>,
+    ) -> Result<PhysicalDB, TSLiteError> {
+        PhysicalDB::new_with_type(path, origin_date, ValueType::U8)
+    }
+
+    /// Same as [`PhysicalDB::new`] but the DB will store values of type `value_type` if it is created.
+    /// If the file exists, the value type is read from its header and `value_type` is ignored.
+    pub fn new_with_type(
+        path: &Path,
+        origin_date: Option<chrono::DateTime<Utc>>,
+        value_type: ValueType,
+    ) -> Result<PhysicalDB, TSLiteError> {
+        let options = DbOptions {
+            value_type,
+            ..DbOptions::default()
+        };
+        PhysicalDB::new_with_options(path, origin_date, &options)
+    }
+
+    /// Same as [`PhysicalDB::new`] but the DB will be created with `options`.
+    /// If the file exists, the options are read from its header and `options` is ignored.
+    pub fn new_with_options(
+        path: &Path,
+        origin_date: Option<chrono::DateTime<Utc>>,
+        options: &DbOptions,
     ) -> Result<PhysicalDB, TSLiteError> {
         // We need to first check if file exist because we are going to need to write
         // or read the header depending on it.
         if path.exists() {
-            let mut file = OpenOptions::new()
+            let file = OpenOptions::new()
                 .read(true)
                 .write(true)
-                .open(&path)
+                .open(path)
                 .map_err(|e| TSLiteError::IOError(e.to_string()))?;
 
-            file.seek(SeekFrom::Start(0))
-                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-            let mut buffer = [0; 15]; // Header takes 15 bytes.
-            let n = file
-                .read(&mut buffer[..])
-                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-            if n == 15 {
-                let header: DbHeader = DbHeader::from(&buffer[..]);
-                return Ok(PhysicalDB {
-                    path: PathBuf::from(path),
-                    file: Some(file), // don't want to open the file right away.
-                    header,
-                });
-            } else {
-                return Err(TSLiteError::IOError(
-                    "DB File header is corrupted.".to_string(),
-                ));
-            }
+            let header = read_header_from(&file)?;
+            let mut db = PhysicalDB {
+                path: PathBuf::from(path),
+                file: Some(file),
+                header,
+                tombstones: Tombstones::default(),
+            };
+            db.recover()?;
+            db.read_tombstones()?;
+            db.merge_overflow()?;
+            return Ok(db);
         }
 
         // If it doesn't exist we just create a DB the usual way.
-        PhysicalDB::create(path, origin_date)
+        PhysicalDB::create_with_options(path, origin_date, options)
     }
 
     /// This function will create a new database file.
     /// Warning: It will *not* check if there is already a file at `path`, if there is one, it will be overwritten.
     /// The second argument the date with which to initialize the database. It is optional, if you give `None`
     /// it will use the current date and time.
+    /// The database will store `u8` values, use [`PhysicalDB::create_with_type`] to store another type of value.
     pub fn create(
         path: &Path,
         origin_date: Option<chrono::DateTime<Utc>>,
     ) -> Result<PhysicalDB, TSLiteError> {
-        let mut file = File::create(path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        PhysicalDB::create_with_type(path, origin_date, ValueType::U8)
+    }
+
+    /// Same as [`PhysicalDB::create`] but the DB will store values of type `value_type`.
+    pub fn create_with_type(
+        path: &Path,
+        origin_date: Option<chrono::DateTime<Utc>>,
+        value_type: ValueType,
+    ) -> Result<PhysicalDB, TSLiteError> {
+        let options = DbOptions {
+            value_type,
+            ..DbOptions::default()
+        };
+        PhysicalDB::create_with_options(path, origin_date, &options)
+    }
+
+    /// Same as [`PhysicalDB::create`] but the DB will be created with `options`.
+    /// If the options cannot be used together, `TSLiteError::IncompatibleOptions` is returned.
+    pub fn create_with_options(
+        path: &Path,
+        origin_date: Option<chrono::DateTime<Utc>>,
+        options: &DbOptions,
+    ) -> Result<PhysicalDB, TSLiteError> {
+        PhysicalDB::create_with_labels(path, origin_date, options, &Labels::new())
+    }
+
+    /// Same as [`PhysicalDB::create_with_options`] but `labels` are stored in the header of the DB,
+    /// see [`Labels`].
+    pub fn create_with_labels(
+        path: &Path,
+        origin_date: Option<chrono::DateTime<Utc>>,
+        options: &DbOptions,
+        labels: &Labels,
+    ) -> Result<PhysicalDB, TSLiteError> {
+        if options.storage != Storage::Plain && options.checksums {
+            return Err(TSLiteError::IncompatibleOptions);
+        }
+        if options.capacity.is_some()
+            && (options.storage != Storage::Plain
+                || options.retention.is_set()
+                || options.capacity == Some(0))
+        {
+            return Err(TSLiteError::IncompatibleOptions);
+        }
 
         // Store the origin date using or own time stamp format. See the Timestamp struct for more info.
         // It lose every timezone info, so everything is normalized as utc+0 before being written.
-        let date = Timestamp::from(origin_date.unwrap_or_else(Utc::now));
+        let date = Timestamp::try_from(origin_date.unwrap_or_else(Utc::now))?;
         // We always start with an empty DB, so we store 0 for the number of records.
-        let header = DbHeader {
-            origin_date: date,
-            records_number: 0,
-        };
+        let mut header = DbHeader::new(date, options);
+        // Nothing is written before the labels are checked, so a file at `path` is kept if they are invalid.
+        let labels = labels::encode_labels(labels, 0)?;
+        header.header_len += labels.len() as u16;
+
+        let mut file = File::create(path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
 
-        file.write(&header.as_bytes())
+        let mut bytes = header.as_bytes();
+        bytes[DbHeader::LABELS_POS as usize..].copy_from_slice(&labels);
+        file.write(&bytes)
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        if header.capacity.is_some() {
+            file.set_len(header.record_pos(0) + header.ring_slots() * header.record_size())
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        }
 
-        Ok(PhysicalDB {
+        let db = PhysicalDB {
             path: PathBuf::from(path),
             file: None, // don't want to open the file right away.
             header,
-        })
+            tombstones: Tombstones::default(),
+        };
+        if header.storage == Storage::Compressed {
+            // An index left by a previous DB at the same path must not be used.
+            File::create(db.index_path()).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        }
+        // Neither can the records left to be inserted in it or deleted from it.
+        let _ = fs::remove_file(db.overflow_path());
+        let _ = fs::remove_file(db.journal_path());
+        let _ = fs::remove_file(db.tombstones_path());
+
+        Ok(db)
     }
 
     /// Open the database file in read and write mode.
@@ -361,7 +1063,9 @@ impl PhysicalDB {
 
     /// Drop the database file to close it.
     /// Make sure to sync all IO operation before closing it.
+    /// The records left in the overflow area are merged first, see [`PhysicalDB::merge_overflow`].
     pub fn close(&mut self) -> Result<(), TSLiteError> {
+        self.merge_overflow()?;
         if self.file.is_some() {
             self.file
                 .as_ref()
@@ -381,63 +1085,113 @@ impl PhysicalDB {
             self.open()?;
         }
 
-        let mut fref = self.file.as_ref().unwrap();
-        fref.seek(SeekFrom::Start(0))
-            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        let mut buffer = [0; 15]; // Header takes 15 bytes.
-        let n = fref
-            .read(&mut buffer[..])
-            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        if n == 15 {
-            let header: DbHeader = DbHeader::from(&buffer[..]);
-            return Ok(header);
-        }
-
-        Err(TSLiteError::IOError(
-            "Could not read header: not enough octets.".to_string(),
-        ))
+        read_header_from(self.file.as_ref().unwrap())
     }
 
     /// Check if a given record index exist within the database.
     fn check_record_index(&self, rec_id: u64) -> Result<bool, TSLiteError> {
+        // The records of the other storages are checked when they are decoded,
+        // and every slot of a ring is always in the file.
+        if self.header.storage != Storage::Plain || self.header.capacity.is_some() {
+            return Ok(rec_id <= self.header.records_number);
+        }
+
         let metadata = self
             .file
             .as_ref()
             .unwrap()
             .metadata()
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        if metadata.len() >= (/* header size */(7+8) + /* records size */(4+1) * rec_id) {
+        if metadata.len() >= self.header.record_pos(rec_id) {
             return Ok(true);
         }
 
         Ok(false)
     }
 
+    /// The number of records that can be read from the file, stopping at the first one that cannot.
+    /// It can be more than the number of committed records with the plain storage.
+    fn physical_records(&mut self) -> Result<u64, TSLiteError> {
+        if self.header.storage == Storage::Plain && self.header.capacity.is_none() {
+            let len = self
+                .file
+                .as_ref()
+                .unwrap()
+                .metadata()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?
+                .len();
+            let records_len = len.saturating_sub(self.header.header_len as u64);
+            return Ok(records_len / self.header.record_size());
+        }
+
+        let records_number = self.header.records_number;
+        Ok(RawRecords::unchecked(self, 0, records_number)
+            .take_while(|res| res.is_ok())
+            .count() as u64)
+    }
+
+    /// The position in the file of the record `rec_id`, or of the block or run holding it.
+    fn record_file_pos(&self, rec_id: u64) -> Result<u64, TSLiteError> {
+        match self.header.storage {
+            Storage::Plain => Ok(self.header.record_pos(rec_id)),
+            Storage::Compressed => self.compressed_record_pos(rec_id),
+            Storage::Rle => self.rle_record_pos(rec_id),
+        }
+    }
+
+    /// Check that `V` is the type of value stored in the DB.
+    fn check_value_type<V: Value>(&self) -> Result<(), TSLiteError> {
+        if V::TYPE != self.header.value_type {
+            return Err(TSLiteError::TypeMismatch);
+        }
+
+        Ok(())
+    }
+
     /// The size of the header and record are static.
-    /// So the position of each record is deterministic.
-    /// If `n` is the record id, then its position within the file can be computed with :
-    /// pos(n) = (7 + 8) + (5*n)
-    pub fn read_record(&mut self, rec_id: u64) -> Result<RecordInfo, TSLiteError> {
+    /// So the position of each record is deterministic, the records starting right after the header
+    /// whose size is given by [`DbHeader::header_len`], see [`DbHeader::record_pos`].
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    /// If the record was deleted, `TSLiteError::DeletedRecord` is returned.
+    pub fn read_record<V: Value>(&mut self, rec_id: u64) -> Result<RecordInfo<V>, TSLiteError> {
+        self.check_value_type::<V>()?;
+        self.merge_overflow()?;
+        if self.is_deleted(rec_id) {
+            return Err(TSLiteError::DeletedRecord(rec_id));
+        }
+        let buffer = self.read_record_bytes(rec_id)?;
+        Ok(self.header.decode_record(&buffer))
+    }
+
+    /// Read the raw octets of a record, whatever the value type of the DB is.
+    fn read_record_bytes(&mut self, rec_id: u64) -> Result<Vec<u8>, TSLiteError> {
         if self.file.is_none() {
             self.open()?;
         }
 
+        if self.header.storage != Storage::Plain || self.header.capacity.is_some() {
+            if rec_id >= self.header.records_number {
+                return Err(TSLiteError::IndexOutOfBound);
+            }
+            return RawRecords::new(self, rec_id, rec_id + 1).next().unwrap();
+        }
+
         let id_exist = self.check_record_index(rec_id)?;
         if !id_exist {
             return Err(TSLiteError::IndexOutOfBound);
         }
 
-        let pos = (7 + 8) + (rec_id * 5);
+        let pos = self.header.record_pos(rec_id);
         let mut fref = self.file.as_ref().unwrap();
         fref.seek(SeekFrom::Start(pos))
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        let mut buffer = [0; 5]; // Header takes 15 bytes.
+        let mut buffer = vec![0; self.header.record_size() as usize];
         let n = fref
             .read(&mut buffer[..])
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        if n == 5 {
-            let record: RecordInfo = RecordInfo::from(&buffer[..]);
-            return Ok(record);
+        if n == buffer.len() {
+            self.header.check_record(rec_id, &buffer)?;
+            return Ok(buffer);
         }
 
         Err(TSLiteError::IOError(
@@ -445,50 +1199,271 @@ impl PhysicalDB {
         ))
     }
 
+    /// Read only the time offset of a record, whatever the value type of the DB is.
+    fn read_time_offset(&mut self, rec_id: u64) -> Result<i64, TSLiteError> {
+        let buffer = self.read_record_bytes(rec_id)?;
+        Ok(self.header.raw_time_offset(&buffer))
+    }
+
+    /// Convert a date to the time offset of the first record that can be at or after this date,
+    /// see [`DbHeader::offset_from_date`].
+    fn offset_from_date(&self, date: DateTime<Utc>) -> i64 {
+        self.header.offset_from_date(date)
+    }
+
+    /// Find the index of the first record with a time offset greater or equal to `offset`.
+    /// Records are expected to be ordered, which allow us to do a binary search over the file.
+    /// If every record is anterior to `offset`, the number of record is returned.
+    fn search_offset(&mut self, offset: i64) -> Result<u64, TSLiteError> {
+        match self.header.storage {
+            Storage::Plain => {}
+            Storage::Compressed => return self.search_compressed(offset),
+            Storage::Rle => return self.search_rle(offset),
+        }
+
+        let mut low = 0;
+        let mut high = self.header.records_number;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.read_time_offset(mid)? < offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(low)
+    }
+
+    /// Return every record between `from` (included) and `to` (excluded).
+    /// Records are expected to be ordered (see [`PhysicalDB::check_db_file`]), they are found
+    /// with a binary search so only the matching records are read.
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn query_range<V: Value>(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<RecordInfo<V>>, TSLiteError> {
+        self.iter_range(from, to)?
+            .map(|res| res.map(|(_, record)| record))
+            .collect()
+    }
+
+    /// Iterate over every record of the DB, from the oldest to the most recent.
+    /// The iterator can also be reversed to cheaply get the most recent records.
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn iter<V: Value>(&mut self) -> Result<RecordIter<'_, V>, TSLiteError> {
+        self.check_value_type::<V>()?;
+        self.merge_overflow()?;
+        let records_number = self.header.records_number;
+        Ok(RecordIter::new(self, 0, records_number))
+    }
+
+    /// Iterate over the records between `from` (included) and `to` (excluded).
+    /// The bounds of the range are found with a binary search, like [`PhysicalDB::query_range`].
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn iter_range<V: Value>(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<RecordIter<'_, V>, TSLiteError> {
+        self.check_value_type::<V>()?;
+        self.merge_overflow()?;
+
+        let first = self.search_offset(self.offset_from_date(from))?;
+        let last = self.search_offset(self.offset_from_date(to))?;
+        Ok(RecordIter::new(self, first, last))
+    }
+
+    /// Compute the min, max, mean, sum, count, first and last value of the records
+    /// between `from` (included) and `to` (excluded).
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn aggregate<V: Value>(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Aggregate<V>, TSLiteError> {
+        let mut aggregate = Aggregate::default();
+        for res in self.iter_range(from, to)? {
+            aggregate.push(res?.1);
+        }
+
+        Ok(aggregate)
+    }
+
+    /// Split the range between `from` (included) and `to` (excluded) into buckets of `interval`
+    /// and compute `aggregation` over the records of each bucket.
+    /// If `to` is not after `from`, there is no bucket.
+    /// Buckets are aligned on the origin date of the DB: the n-th bucket starts `n * interval` after it.
+    /// Only the records within the range are used, even if the first or last bucket overflows it.
+    /// Empty buckets have no value, even with `Aggregation::Count`, and are reported according to `fill`.
+    /// Records are expected to be ordered, see [`PhysicalDB::check_db_file`].
+    /// `interval` must be a whole number of time units of the DB, otherwise `TSLiteError::InvalidInterval` is returned.
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn downsample<V: Value>(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: chrono::Duration,
+        aggregation: Aggregation,
+        fill: Fill,
+    ) -> Result<Vec<Bucket>, TSLiteError> {
+        let buckets = match Buckets::new(
+            self.offset_from_date(from),
+            self.offset_from_date(to),
+            interval,
+            self.header.precision,
+        )? {
+            Some(buckets) => buckets,
+            None => return Ok(Vec::new()),
+        };
+
+        let origin = self.header.origin_date;
+        let records = self
+            .iter_range::<V>(from, to)?
+            .map(|res| res.map(|(_, r)| r));
+        buckets.aggregate(origin, records, aggregation, fill)
+    }
+
     /// This utility function will update the number of record in the database.
     pub fn update_record_number(&mut self, drn: u64) -> Result<(), TSLiteError> {
+        self.commit_records_number(self.header.records_number + drn)
+    }
+
+    /// Write and sync a new number of records in the header.
+    /// The records must already be synced, so the number of records never refers to records that are not on disk.
+    fn commit_records_number(&mut self, records_number: u64) -> Result<(), TSLiteError> {
         if self.file.is_none() {
             self.open()?;
         }
 
         let mut fref = self.file.as_ref().unwrap();
-        fref.seek(SeekFrom::Start(7)) // The record number is always at position 7
+        let sequence = self.header.commit_sequence + 1;
+        // We overwrite the oldest slot, the other one stay valid if we crash while writing it.
+        let slot = CommitSlot {
+            sequence,
+            records_number: self.header.overwritten + records_number,
+        };
+        fref.seek(SeekFrom::Start(DbHeader::slot_pos(sequence % 2)))
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        fref.write_u64::<LittleEndian>(self.header.records_number + drn)
+        fref.write_all(&slot.as_bytes())
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
         fref.sync_data()
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        self.header.records_number += drn;
+        self.header.records_number = records_number;
+        self.header.commit_sequence = sequence;
+        if let Some(capacity) = self.header.capacity {
+            let overwritten = records_number.saturating_sub(capacity);
+            self.header.overwritten += overwritten;
+            self.header.records_number -= overwritten;
+        }
+
+        Ok(())
+    }
+
+    /// Bring the file back to the last committed state.
+    /// Anything written after the last committed record comes from an append that did not complete,
+    /// so it is discarded.
+    fn recover(&mut self) -> Result<(), TSLiteError> {
+        match self.header.storage {
+            // The file of a ring never changes size.
+            Storage::Plain if self.header.capacity.is_some() => self.replay_journal(),
+            Storage::Plain => {
+                self.replay_journal()?;
+                self.truncate_records(self.header.record_pos(self.header.records_number))
+            }
+            Storage::Compressed => self.recover_compressed(),
+            Storage::Rle => self.recover_rle(),
+        }
+    }
+
+    /// Discard anything in the file after `committed_len`.
+    fn truncate_records(&self, committed_len: u64) -> Result<(), TSLiteError> {
+        let fref = self.file.as_ref().unwrap();
+        let len = fref
+            .metadata()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?
+            .len();
+        if len > committed_len {
+            fref.set_len(committed_len)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            fref.sync_all()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        }
 
         Ok(())
     }
 
     /// Add a record in the database.
-    pub fn append_record(&mut self, rec_nfo: RecordInfo) -> Result<(), TSLiteError> {
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn append_record<V: Value>(&mut self, rec_nfo: RecordInfo<V>) -> Result<(), TSLiteError> {
+        self.append_records(&[rec_nfo])
+    }
+
+    /// Add several records in the database.
+    /// Every record is written in one pass and synced once, then the new number of record is committed,
+    /// so it is much faster than calling [`PhysicalDB::append_record`] for each record.
+    /// If the append is interrupted, none of the records will be in the DB once it is opened again.
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    /// If the time offset of a record cannot be stored in the DB, `TSLiteError::TimeOffsetOutOfRange`
+    /// is returned and nothing is written.
+    pub fn append_records<V: Value>(
+        &mut self,
+        records: &[RecordInfo<V>],
+    ) -> Result<(), TSLiteError> {
+        self.check_value_type::<V>()?;
+        if records.is_empty() {
+            return Ok(());
+        }
+        for r in records {
+            self.header.check_time_offset(r.time_offset)?;
+        }
         if self.file.is_none() {
             self.open()?;
         }
 
-        // write record
+        if self.header.storage != Storage::Plain {
+            let records: Vec<Vec<u8>> = records
+                .iter()
+                .map(|r| self.header.encode_record(r))
+                .collect();
+            match self.header.storage {
+                Storage::Compressed => self.append_compressed(&records)?,
+                _ => self.append_rle(&records)?,
+            }
+            return self.enforce_retention_on_append();
+        }
+
+        let mut store: Vec<u8> =
+            Vec::with_capacity(records.len() * self.header.record_size() as usize);
+        for r in records {
+            store.extend(self.header.encode_record(r));
+        }
+        if self.header.capacity.is_some() {
+            return self.append_ring(&store);
+        }
+
+        // write records right after the last committed one
         let mut fref = self.file.as_ref().unwrap();
-        fref.seek(SeekFrom::End(0))
-            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        fref.write(&rec_nfo.as_bytes())
+        fref.seek(SeekFrom::Start(
+            self.header.record_pos(self.header.records_number),
+        ))
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        fref.write_all(&store)
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        fref.sync_all()
+        fref.sync_data()
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
 
         // Update DbHeader
-        self.update_record_number(1)?;
-
-        Ok(())
+        self.commit_records_number(self.header.records_number + records.len() as u64)?;
+        self.enforce_retention_on_append()
     }
 
     /// Append a record with the current time.
-    pub fn append_record_now(&mut self, value: u8) -> Result<(), TSLiteError> {
+    pub fn append_record_now<V: Value>(&mut self, value: V) -> Result<(), TSLiteError> {
         let origin = self.header.origin_date;
-        let now = Timestamp::from(Utc::now());
-        let off = origin.offset(&now);
+        let now = Timestamp::try_from(Utc::now())?;
+        let off = origin.offset_in(&now, self.header.precision);
         let nfo = RecordInfo {
             value,
             time_offset: off,
@@ -498,7 +1473,14 @@ impl PhysicalDB {
     }
 
     /// Change the value of a record within the database.
-    pub fn update_record(&mut self, rec_id: u64, value: u8) -> Result<(), TSLiteError> {
+    /// `V` must be the value type of the DB, otherwise `TSLiteError::TypeMismatch` is returned.
+    /// Only DBs using the plain storage can be updated, `TSLiteError::UnsupportedOperation` is returned otherwise.
+    /// If the record was deleted, `TSLiteError::DeletedRecord` is returned.
+    pub fn update_record<V: Value>(&mut self, rec_id: u64, value: V) -> Result<(), TSLiteError> {
+        self.check_value_type::<V>()?;
+        if self.header.storage != Storage::Plain {
+            return Err(TSLiteError::UnsupportedOperation);
+        }
         if self.file.is_none() {
             self.open()?;
         }
@@ -507,12 +1489,20 @@ impl PhysicalDB {
         if !id_exist {
             return Err(TSLiteError::IndexOutOfBound);
         }
+        if self.is_deleted(rec_id) {
+            return Err(TSLiteError::DeletedRecord(rec_id));
+        }
 
-        let pos = (7 + 8) + (rec_id * 5) + 4; // header + records + timestamp
+        // The whole record is written again so its checksum stays right.
+        let time_offset = self.read_time_offset(rec_id)?;
+        let pos = self.header.record_pos(rec_id);
+        let store = self
+            .header
+            .encode_record(&RecordInfo { time_offset, value });
         let mut fref = self.file.as_ref().unwrap();
         fref.seek(SeekFrom::Start(pos))
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        fref.write(&[value])
+        fref.write(&store)
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
         fref.sync_all()
             .map_err(|e| TSLiteError::IOError(e.to_string()))?;
@@ -521,12 +1511,13 @@ impl PhysicalDB {
     }
 
     /// Perform check to find any issue in the database file.
-    /// It will return the first issue it find. You might need to run this function
-    /// until it return `DbIssue::None` to check for all possible issue.
+    /// It will return the first issue it find. Use [`PhysicalDB::verify`] to get every issue
+    /// in one pass, and [`PhysicalDB::repair`] to fix them.
     pub fn check_db_file(&mut self) -> Result<DbIssue, TSLiteError> {
         if self.file.is_none() {
             self.open()?;
         }
+        self.merge_overflow()?;
 
         // First try to read the header
         let res_header = self.read_header();
@@ -538,16 +1529,17 @@ impl PhysicalDB {
             return Ok(DbIssue::OriginDateInvalid);
         }
 
-        let mut time_offset = 0;
-        for i in 0..header.records_number {
-            let res_record = self.read_record(i);
-            if res_record.is_err() {
-                return Ok(DbIssue::RecordCorrupted(i));
-            }
-            if time_offset > res_record.as_ref().unwrap().time_offset {
+        let mut time_offset = i64::MIN;
+        for (i, res_record) in RawRecords::new(self, 0, header.records_number).enumerate() {
+            let record = match res_record {
+                Ok(record) => record,
+                Err(_) => return Ok(DbIssue::RecordCorrupted(i as u64)),
+            };
+            let offset = header.raw_time_offset(&record);
+            if time_offset > offset {
                 return Ok(DbIssue::UnorderedRecord);
             }
-            time_offset = res_record.as_ref().unwrap().time_offset;
+            time_offset = offset;
         }
 
         let id_exist = self.check_record_index(header.records_number)?;
@@ -558,34 +1550,88 @@ impl PhysicalDB {
         Ok(DbIssue::None)
     }
 
-    /// Reorder the record in the DB.
-    /// Use if your DB records got scrambled for some reason.
-    /// Right now it use a simple way :
-    /// - Read all the record
-    /// - reorder them in-memory
-    /// - dump *all* the record in the DB
-    /// It means that if you have just one record wrong you end up re-writing the whole DB.
-    pub fn reorder_record(&mut self) -> Result<(), TSLiteError> {
+    /// Fix the issues found by [`PhysicalDB::check_db_file`] in the records:
+    /// - a partial record at the end of the file is removed,
+    /// - the number of records in the header is lowered to the number of records in the file,
+    /// - the records are sorted if they are not chronologically ordered.
+    ///
+    /// Issues in the header (`HeaderCorrupted`, `OriginDateInvalid`) cannot be repaired.
+    /// Return the list of fixes that were made. If `dry_run` is `true`, nothing is written and
+    /// the list of fixes that would be made is returned.
+    pub fn repair(&mut self, dry_run: bool) -> Result<Vec<Repair>, TSLiteError> {
         if self.file.is_none() {
             self.open()?;
         }
+        if !dry_run {
+            self.merge_overflow()?;
+        }
 
-        let mut records: Vec<RecordInfo> = Vec::with_capacity(self.header.records_number as usize);
-        for i in 0..(self.header.records_number) {
-            records.push(self.read_record(i)?);
+        let mut repairs = Vec::new();
+        let physical_records = self.physical_records()?;
+        let fref = self.file.as_ref().unwrap();
+        let len = fref
+            .metadata()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?
+            .len();
+        let records_len = len.saturating_sub(self.header.header_len as u64);
+        // Undecodable data of a compressed DB is discarded once the number of records is fixed.
+        let partial = match self.header.storage {
+            Storage::Plain => records_len % self.header.record_size(),
+            Storage::Compressed => 0,
+            Storage::Rle => records_len % self.header.run_size(),
+        };
+
+        if partial > 0 {
+            repairs.push(Repair::TruncatePartialRecord { octets: partial });
+            if !dry_run {
+                fref.set_len(len - partial)
+                    .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+                fref.sync_all()
+                    .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            }
         }
-        records.sort_unstable();
-        let mut fref = self.file.as_ref().unwrap();
-        fref.seek(SeekFrom::Start(/* offset header */ 15))
-            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
-        for r in &records {
-            fref.write(&r.as_bytes())
-                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        // Records beyond the number in the header were never committed, and are already cut off
+        // when the DB is opened.
+        let physical_records = physical_records.min(self.header.records_number);
+        if physical_records < self.header.records_number {
+            repairs.push(Repair::SetRecordsNumber {
+                from: self.header.records_number,
+                to: physical_records,
+            });
+            if !dry_run {
+                self.commit_records_number(physical_records)?;
+                self.recover()?;
+            }
         }
-        fref.sync_all()
-            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
 
-        Ok(())
+        let header = self.header;
+        let mut time_offset = i64::MIN;
+        for res_record in RawRecords::unchecked(self, 0, physical_records) {
+            let offset = header.raw_time_offset(&res_record?);
+            if time_offset > offset {
+                repairs.push(Repair::SortRecords);
+                if !dry_run {
+                    self.reorder_record()?;
+                }
+                break;
+            }
+            time_offset = offset;
+        }
+
+        Ok(repairs)
+    }
+
+    /// Reorder the record in the DB.
+    /// Use if your DB records got scrambled for some reason.
+    /// The records are sorted by runs of [`SORT_RUN_RECORDS`] records which are then merged, so this works
+    /// with DB files bigger than the memory. The sorted records are written to a new file replacing the
+    /// DB file, see [`PhysicalDB::reorder_record_in_runs`].
+    ///
+    /// It means that if you have just one record wrong you end up re-writing the whole DB.
+    /// The deleted records are removed along the way, see [`PhysicalDB::compact`].
+    pub fn reorder_record(&mut self) -> Result<(), TSLiteError> {
+        self.reorder_record_in_runs(SORT_RUN_RECORDS)
     }
 }
 
@@ -593,14 +1639,19 @@ impl PhysicalDB {
 /// on disk ?
 #[cfg(test)]
 mod tests {
+    // The imports and the first tests are kept as they were written before the lints were enforced.
     use super::*;
+    #[allow(unused_imports)]
     use chrono::prelude::*;
+    #[allow(unused_imports)]
     use std::error::Error;
     use std::fs;
+    #[allow(unused_imports)]
     use std::io::prelude::*;
     use std::path::Path;
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow)]
     fn create_db_origin_now() {
         fs::remove_file("create_db_origin_now.db");
         let r = PhysicalDB::create(&Path::new("create_db_origin_now.db"), None);
@@ -609,6 +1660,13 @@ mod tests {
     }
 
     #[test]
+    #[allow(
+        deprecated,
+        non_snake_case,
+        unused_must_use,
+        clippy::needless_borrow,
+        clippy::zero_prefixed_literal
+    )]
     fn create_db_origin_specific() {
         fs::remove_file("create_db_origin_specific.db");
 
@@ -620,13 +1678,16 @@ mod tests {
         assert!(wr.is_ok());
 
         let mut f = File::open("create_db_origin_specific.db").unwrap();
-        let mut buf: Vec<u8> = Vec::with_capacity(7 + 8);
+        let mut buf: Vec<u8> = Vec::with_capacity(DbHeader::SIZE as usize);
         let rr = f.read_to_end(&mut buf).map_err(|e| e.to_string());
         assert!(rr.is_ok());
-        assert!(rr.map(|v| v == (7 + 8)).unwrap_or(false));
+        assert!(rr.map(|v| v == DbHeader::SIZE as usize).unwrap_or(false));
 
-        let dbHeader = DbHeader::from(buf.as_slice());
+        let dbHeader = DbHeader::try_from(buf.as_slice()).expect("could not parse header.");
+        assert_eq!(dbHeader.version, DbHeader::FORMAT_VERSION);
+        assert_eq!(dbHeader.header_len as u64, DbHeader::SIZE);
         assert_eq!(dbHeader.records_number, 0);
+        assert_eq!(dbHeader.value_type, ValueType::U8);
         assert_eq!(dbHeader.origin_date.year, 1994);
         assert_eq!(dbHeader.origin_date.month, 07);
         assert_eq!(dbHeader.origin_date.day, 08);
@@ -638,6 +1699,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow)]
     fn append_record() {
         let path = "append_record.db";
         fs::remove_file(path);
@@ -648,7 +1710,7 @@ mod tests {
 
         let origin_record = RecordInfo {
             time_offset: 5,
-            value: 10,
+            value: 10u8,
         };
 
         db.append_record(origin_record)
@@ -664,12 +1726,14 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn today_is_valid() {
-        let today = Timestamp::from(Utc::now());
+        let today = Timestamp::try_from(Utc::now()).unwrap();
         assert_eq!(today.is_valid(), true);
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn date_ord() {
         let d1 = Timestamp {
             year: 1994,
@@ -678,6 +1742,7 @@ mod tests {
             hour: 5,
             minute: 24,
             second: 23,
+            nanosecond: 0,
         };
         let d2 = Timestamp {
             year: 1993,
@@ -686,6 +1751,7 @@ mod tests {
             hour: 8,
             minute: 0,
             second: 1,
+            nanosecond: 0,
         };
 
         assert_eq!(d1 > d2, true);
@@ -694,6 +1760,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, unused_variables, clippy::needless_borrow)]
     fn check_healthy_db() {
         let path = "healthy.db";
 
@@ -719,6 +1786,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, unused_variables, clippy::needless_borrow)]
     fn check_unordered_db() {
         let path = "unordered.db";
 
@@ -744,6 +1812,12 @@ mod tests {
     }
 
     #[test]
+    #[allow(
+        unused_must_use,
+        unused_variables,
+        clippy::bool_assert_comparison,
+        clippy::needless_borrow
+    )]
     fn reorder_db() {
         let path = "reordered.db";
 
@@ -775,6 +1849,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_must_use, clippy::needless_borrow)]
     fn update_record() {
         let path = "update_record.db";
 
@@ -785,7 +1860,7 @@ mod tests {
         assert_eq!(header.records_number, 0);
         let origin_record = RecordInfo {
             time_offset: 5,
-            value: 10,
+            value: 10u8,
         };
 
         db.append_record(origin_record)
@@ -793,7 +1868,7 @@ mod tests {
         let mut fs_record = db.read_record(0).expect("could not get record.");
         assert_eq!(origin_record, fs_record);
 
-        let updated_value = 8;
+        let updated_value = 8u8;
         db.update_record(0, updated_value)
             .expect("Could not update record.");
         fs_record = db.read_record(0).expect("could not get record.");
@@ -801,4 +1876,843 @@ mod tests {
 
         fs::remove_file(path);
     }
+
+    #[test]
+    fn typed_records() {
+        let path = "typed_records.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create_with_type(Path::new(path), None, ValueType::F64)
+            .expect("could not create db.");
+        for i in 0..10 {
+            let origin_record = RecordInfo {
+                time_offset: i,
+                value: i as f64 * 1.5,
+            };
+            db.append_record(origin_record)
+                .expect("could not append record.");
+        }
+
+        let fs_record: RecordInfo<f64> = db.read_record(7).expect("could not get record.");
+        assert_eq!(fs_record.time_offset, 7);
+        assert_eq!(fs_record.value, 10.5);
+
+        db.update_record(7, -2.25f64)
+            .expect("could not update record.");
+        let fs_record: RecordInfo<f64> = db.read_record(7).expect("could not get record.");
+        assert_eq!(fs_record.value, -2.25);
+        let fs_record: RecordInfo<f64> = db.read_record(8).expect("could not get record.");
+        assert_eq!(fs_record.value, 12.0);
+
+        assert_eq!(
+            db.check_db_file().expect("could not check db file."),
+            DbIssue::None
+        );
+
+        // The value type is kept in the header when the DB is opened again.
+        db.close().expect("could not close db.");
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.value_type, ValueType::F64);
+        let fs_record: RecordInfo<f64> = db.read_record(9).expect("could not get record.");
+        assert_eq!(fs_record.value, 13.5);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn mismatched_value_type() {
+        let path = "mismatched_value_type.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create_with_type(Path::new(path), None, ValueType::I32)
+            .expect("could not create db.");
+        db.append_record(RecordInfo {
+            time_offset: 1,
+            value: -40i32,
+        })
+        .expect("could not append record.");
+
+        let res = db.append_record(RecordInfo {
+            time_offset: 2,
+            value: 3u8,
+        });
+        assert_eq!(res, Err(TSLiteError::TypeMismatch));
+        let res = db.read_record::<bool>(0);
+        assert_eq!(res, Err(TSLiteError::TypeMismatch));
+        let res = db.read_record::<i32>(0);
+        assert_eq!(res.map(|r| r.value), Ok(-40));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn open_foreign_file() {
+        let path = "open_foreign_file.db";
+        let _ = fs::remove_file(path);
+
+        fs::write(path, b"this is definitely not a time-serie database.").unwrap();
+        let res = PhysicalDB::new(Path::new(path), None);
+        assert_eq!(res.err(), Some(TSLiteError::NotADatabase));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn open_future_version() {
+        let path = "open_future_version.db";
+        let _ = fs::remove_file(path);
+
+        let mut header = DbHeader::new(
+            Timestamp::try_from(Utc::now()).unwrap(),
+            &DbOptions::default(),
+        );
+        header.version = DbHeader::FORMAT_VERSION + 1;
+        fs::write(path, header.as_bytes()).unwrap();
+        let res = PhysicalDB::new(Path::new(path), None);
+        assert_eq!(
+            res.err(),
+            Some(TSLiteError::UnsupportedVersion(
+                DbHeader::FORMAT_VERSION + 1
+            ))
+        );
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn query_range() {
+        let path = "query_range.db";
+        let _ = fs::remove_file(path);
+
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db =
+            PhysicalDB::create(Path::new(path), Some(origin_date)).expect("could not create db.");
+        // One record every 10 seconds.
+        for i in 0..100 {
+            db.append_record(RecordInfo {
+                time_offset: i * 10,
+                value: i as u8,
+            })
+            .expect("could not append record.");
+        }
+
+        let from = origin_date + chrono::Duration::seconds(95);
+        let to = origin_date + chrono::Duration::seconds(150);
+        let records: Vec<RecordInfo> = db.query_range(from, to).expect("could not query db.");
+        let values: Vec<u8> = records.iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![10, 11, 12, 13, 14]);
+
+        // The start of the range is included, the end is excluded.
+        let from = origin_date + chrono::Duration::seconds(100);
+        let to = origin_date + chrono::Duration::milliseconds(150_500);
+        let records: Vec<RecordInfo> = db.query_range(from, to).expect("could not query db.");
+        let values: Vec<u8> = records.iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![10, 11, 12, 13, 14, 15]);
+
+        // Range anterior to the origin date or after the last record.
+        let from = origin_date - chrono::Duration::days(1);
+        let records: Vec<RecordInfo> = db
+            .query_range(from, origin_date + chrono::Duration::seconds(1))
+            .expect("could not query db.");
+        assert_eq!(records.len(), 1);
+        let from = origin_date + chrono::Duration::days(1);
+        let records: Vec<RecordInfo> = db
+            .query_range(from, from + chrono::Duration::days(1))
+            .expect("could not query db.");
+        assert!(records.is_empty());
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn iterate_records() {
+        let path = "iterate_records.db";
+        let _ = fs::remove_file(path);
+
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db =
+            PhysicalDB::create_with_type(Path::new(path), Some(origin_date), ValueType::U16)
+                .expect("could not create db.");
+        // Enough records to need more than one block.
+        let count = BLOCK_RECORDS as i64 * 2 + 10;
+        for i in 0..count {
+            db.append_record(RecordInfo {
+                time_offset: i * 60,
+                value: i as u16,
+            })
+            .expect("could not append record.");
+        }
+
+        let values: Vec<u16> = db
+            .iter::<u16>()
+            .expect("could not iterate db.")
+            .map(|r| r.expect("could not read record.").1.value)
+            .collect();
+        assert_eq!(values, (0..count as u16).collect::<Vec<u16>>());
+
+        // Last 3 records, most recent first.
+        let last: Vec<(Timestamp, RecordInfo<u16>)> = db
+            .iter::<u16>()
+            .expect("could not iterate db.")
+            .rev()
+            .take(3)
+            .map(|r| r.expect("could not read record."))
+            .collect();
+        let values: Vec<u16> = last.iter().map(|(_, r)| r.value).collect();
+        let expected = count as u16 - 1;
+        assert_eq!(values, vec![expected, expected - 1, expected - 2]);
+        let date: DateTime<Utc> = (&last[0].0).into();
+        assert_eq!(
+            date,
+            origin_date + chrono::Duration::minutes(expected as i64)
+        );
+
+        // Iterating from both ends meet in the middle without returning a record twice.
+        let mut it = db.iter::<u16>().expect("could not iterate db.");
+        let mut seen = 0;
+        while let Some(r) = it.next() {
+            r.expect("could not read record.");
+            seen += 1;
+            if it.next_back().is_some() {
+                seen += 1;
+            }
+        }
+        assert_eq!(seen, count);
+
+        let from = origin_date + chrono::Duration::hours(1);
+        let to = origin_date + chrono::Duration::hours(2);
+        let values: Vec<u16> = db
+            .iter_range::<u16>(from, to)
+            .expect("could not iterate db.")
+            .rev()
+            .map(|r| r.expect("could not read record.").1.value)
+            .collect();
+        assert_eq!(values, (60..120).rev().collect::<Vec<u16>>());
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn iterate_truncated_db() {
+        let path = "iterate_truncated_db.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        for i in 0..10 {
+            db.append_record(RecordInfo {
+                time_offset: i,
+                value: i as u8,
+            })
+            .expect("could not append record.");
+        }
+        // Cut the last record in half.
+        let len = db.header.record_pos(10) - 2;
+        db.file.as_ref().unwrap().set_len(len).unwrap();
+
+        let results: Vec<Result<(Timestamp, RecordInfo), TSLiteError>> =
+            db.iter().expect("could not iterate db.").collect();
+        assert_eq!(results.len(), 10);
+        assert!(results[..9].iter().all(|r| r.is_ok()));
+        assert!(results[9].is_err());
+
+        let err = db.check_db_file().expect("could not check db file.");
+        assert_eq!(err, DbIssue::RecordCorrupted(9));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn aggregate_range() {
+        let path = "aggregate_range.db";
+        let _ = fs::remove_file(path);
+
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db =
+            PhysicalDB::create(Path::new(path), Some(origin_date)).expect("could not create db.");
+        // Values at the top of the u8 domain, their sum does not fit in a u8.
+        for i in 0..100 {
+            db.append_record(RecordInfo {
+                time_offset: i,
+                value: 255 - (i % 10) as u8,
+            })
+            .expect("could not append record.");
+        }
+
+        let from = origin_date + chrono::Duration::seconds(10);
+        let to = origin_date + chrono::Duration::seconds(30);
+        let agg: Aggregate = db.aggregate(from, to).expect("could not aggregate db.");
+        assert_eq!(agg.count, 20);
+        assert_eq!(agg.sum, 2.0 * (255.0 * 10.0 - 45.0));
+        assert_eq!(agg.min, Some(246));
+        assert_eq!(agg.max, Some(255));
+        assert_eq!(agg.mean(), Some(250.5));
+        assert_eq!(agg.first.map(|r| r.time_offset), Some(10));
+        assert_eq!(agg.last.map(|r| r.time_offset), Some(29));
+        assert_eq!(agg.get(Aggregation::Last), Some(246.0));
+
+        let from = origin_date + chrono::Duration::days(1);
+        let agg: Aggregate = db
+            .aggregate(from, from + chrono::Duration::days(1))
+            .expect("could not aggregate db.");
+        assert_eq!(agg.count, 0);
+        assert_eq!(agg.get(Aggregation::Count), Some(0.0));
+        assert_eq!(agg.get(Aggregation::Mean), None);
+        assert_eq!(agg.get(Aggregation::Min), None);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn downsample_range() {
+        let path = "downsample_range.db";
+        let _ = fs::remove_file(path);
+
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db =
+            PhysicalDB::create(Path::new(path), Some(origin_date)).expect("could not create db.");
+        // Two records per minute during minute 0, 1 and 4.
+        for (offset, value) in &[(0, 10), (30, 20), (60, 30), (90, 40), (240, 70), (270, 90)] {
+            db.append_record(RecordInfo {
+                time_offset: *offset,
+                value: *value as u8,
+            })
+            .expect("could not append record.");
+        }
+
+        let to = origin_date + chrono::Duration::minutes(5);
+        let mut downsample = |fill| -> Vec<Option<f64>> {
+            db.downsample::<u8>(
+                origin_date,
+                to,
+                chrono::Duration::minutes(1),
+                Aggregation::Mean,
+                fill,
+            )
+            .expect("could not downsample db.")
+            .iter()
+            .map(|b| b.value)
+            .collect()
+        };
+
+        assert_eq!(
+            downsample(Fill::None),
+            vec![Some(15.0), Some(35.0), Some(80.0)]
+        );
+        assert_eq!(
+            downsample(Fill::Null),
+            vec![Some(15.0), Some(35.0), None, None, Some(80.0)]
+        );
+        assert_eq!(
+            downsample(Fill::Previous),
+            vec![Some(15.0), Some(35.0), Some(35.0), Some(35.0), Some(80.0)]
+        );
+        assert_eq!(
+            downsample(Fill::Linear),
+            vec![Some(15.0), Some(35.0), Some(50.0), Some(65.0), Some(80.0)]
+        );
+
+        // Empty buckets after the last record are filled too.
+        let buckets = db
+            .downsample::<u8>(
+                origin_date,
+                origin_date + chrono::Duration::minutes(7),
+                chrono::Duration::minutes(1),
+                Aggregation::Mean,
+                Fill::Previous,
+            )
+            .expect("could not downsample db.");
+        let values: Vec<Option<f64>> = buckets.iter().map(|b| b.value).collect();
+        assert_eq!(
+            values[4..].to_vec(),
+            vec![Some(80.0), Some(80.0), Some(80.0)]
+        );
+
+        // Only the non-empty buckets are kept in memory, however many buckets the range holds.
+        let buckets = db
+            .downsample::<u8>(
+                origin_date,
+                origin_date + chrono::Duration::days(365 * 100),
+                chrono::Duration::seconds(1),
+                Aggregation::Count,
+                Fill::None,
+            )
+            .expect("could not downsample db.");
+        assert_eq!(buckets.len(), 6);
+
+        // Buckets are aligned on the origin date, not on the start of the range.
+        let from = origin_date + chrono::Duration::seconds(45);
+        let buckets = db
+            .downsample::<u8>(
+                from,
+                to,
+                chrono::Duration::minutes(2),
+                Aggregation::Count,
+                Fill::Null,
+            )
+            .expect("could not downsample db.");
+        let starts: Vec<Timestamp> = buckets.iter().map(|b| b.start).collect();
+        assert_eq!(
+            starts,
+            vec![
+                Timestamp::try_from(origin_date).unwrap(),
+                Timestamp::try_from(origin_date + chrono::Duration::minutes(2)).unwrap(),
+                Timestamp::try_from(origin_date + chrono::Duration::minutes(4)).unwrap(),
+            ]
+        );
+        let values: Vec<Option<f64>> = buckets.iter().map(|b| b.value).collect();
+        assert_eq!(values, vec![Some(2.0), None, Some(2.0)]);
+
+        // A reversed range has no bucket, even within a single interval.
+        let buckets = db
+            .downsample::<u8>(
+                to,
+                from,
+                chrono::Duration::minutes(10),
+                Aggregation::Count,
+                Fill::Null,
+            )
+            .expect("could not downsample db.");
+        assert_eq!(buckets, vec![]);
+
+        let res = db.downsample::<u8>(
+            from,
+            to,
+            chrono::Duration::milliseconds(1500),
+            Aggregation::Count,
+            Fill::Null,
+        );
+        assert_eq!(res, Err(TSLiteError::InvalidInterval));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn append_records_batch() {
+        let path = "append_records_batch.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create_with_type(Path::new(path), None, ValueType::F32)
+            .expect("could not create db.");
+        db.append_records::<f32>(&[])
+            .expect("could not append records.");
+        assert_eq!(db.header.records_number, 0);
+
+        // A day of samples at 1Hz.
+        let records: Vec<RecordInfo<f32>> = (0..86_400)
+            .map(|i| RecordInfo {
+                time_offset: i,
+                value: i as f32 / 10.0,
+            })
+            .collect();
+        db.append_records(&records)
+            .expect("could not append records.");
+        db.append_record(RecordInfo {
+            time_offset: 86_400,
+            value: -1.0f32,
+        })
+        .expect("could not append record.");
+
+        let header = db.read_header().expect("could not read header.");
+        assert_eq!(header.records_number, 86_401);
+        assert_eq!(db.header.records_number, 86_401);
+        let fs_record: RecordInfo<f32> = db.read_record(43_200).expect("could not get record.");
+        assert_eq!(fs_record, records[43_200]);
+        let err = db.check_db_file().expect("could not check db file.");
+        assert_eq!(err, DbIssue::None);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn recover_interrupted_append() {
+        let path = "recover_interrupted_append.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        for i in 0..5 {
+            db.append_record(RecordInfo {
+                time_offset: i,
+                value: i as u8,
+            })
+            .expect("could not append record.");
+        }
+        let committed_len = db.header.record_pos(5);
+
+        // Records written but never committed, as if we crashed before updating the header.
+        let mut fref = db.file.as_ref().unwrap();
+        fref.seek(SeekFrom::End(0)).unwrap();
+        fref.write_all(&[42; 12]).unwrap();
+        db.close().expect("could not close db.");
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.records_number, 5);
+        assert_eq!(fs::metadata(path).unwrap().len(), committed_len);
+        assert_eq!(
+            db.check_db_file().expect("could not check db file."),
+            DbIssue::None
+        );
+
+        // The DB keeps working after the recovery.
+        db.append_record(RecordInfo {
+            time_offset: 5,
+            value: 5u8,
+        })
+        .expect("could not append record.");
+        assert_eq!(db.read_record(5).map(|r| r.value), Ok(5u8));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn recover_torn_commit() {
+        let path = "recover_torn_commit.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        db.append_records(&[
+            RecordInfo {
+                time_offset: 0,
+                value: 0u8,
+            },
+            RecordInfo {
+                time_offset: 1,
+                value: 1u8,
+            },
+        ])
+        .expect("could not append records.");
+        db.append_record(RecordInfo {
+            time_offset: 2,
+            value: 2u8,
+        })
+        .expect("could not append record.");
+
+        // Crash while writing the last commit: its slot is only half written.
+        let sequence = db.header.commit_sequence;
+        let mut fref = db.file.as_ref().unwrap();
+        fref.seek(SeekFrom::Start(DbHeader::slot_pos(sequence % 2) + 4))
+            .unwrap();
+        fref.write_all(&[0xFF; 8]).unwrap();
+        db.close().expect("could not close db.");
+
+        let db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.records_number, 2);
+        assert_eq!(db.header.commit_sequence, sequence - 1);
+        assert_eq!(fs::metadata(path).unwrap().len(), db.header.record_pos(2));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn repair_truncated_db() {
+        let path = "repair_truncated_db.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        for i in 0..10 {
+            db.append_record(RecordInfo {
+                time_offset: i,
+                value: i as u8,
+            })
+            .expect("could not append record.");
+        }
+        // Cut the last two records, leaving 3 octets of the 9th one.
+        let len = db.header.record_pos(8) + 3;
+        db.file.as_ref().unwrap().set_len(len).unwrap();
+        assert_eq!(
+            db.check_db_file().expect("could not check db file."),
+            DbIssue::RecordCorrupted(8)
+        );
+
+        let planned = db.repair(true).expect("could not repair db.");
+        assert_eq!(
+            planned,
+            vec![
+                Repair::TruncatePartialRecord { octets: 3 },
+                Repair::SetRecordsNumber { from: 10, to: 8 }
+            ]
+        );
+        // Nothing is written in dry-run mode.
+        assert_eq!(fs::metadata(path).unwrap().len(), len);
+        assert_eq!(db.header.records_number, 10);
+
+        let repairs = db.repair(false).expect("could not repair db.");
+        assert_eq!(repairs, planned);
+        assert_eq!(
+            db.check_db_file().expect("could not check db file."),
+            DbIssue::None
+        );
+        assert_eq!(db.header.records_number, 8);
+        assert_eq!(db.read_header().map(|h| h.records_number), Ok(8));
+        assert_eq!(db.repair(false), Ok(vec![]));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn repair_unordered_db() {
+        let path = "repair_unordered_db.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        for i in 0..10 {
+            db.append_record(RecordInfo {
+                time_offset: 9 - i,
+                value: i as u8,
+            })
+            .expect("could not append record.");
+        }
+
+        assert_eq!(db.repair(true), Ok(vec![Repair::SortRecords]));
+        assert_eq!(
+            db.check_db_file().expect("could not check db file."),
+            DbIssue::UnorderedRecord
+        );
+        assert_eq!(db.repair(false), Ok(vec![Repair::SortRecords]));
+        assert_eq!(
+            db.check_db_file().expect("could not check db file."),
+            DbIssue::None
+        );
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn checksummed_records() {
+        let path = "checksummed_records.db";
+        let _ = fs::remove_file(path);
+
+        let options = DbOptions {
+            value_type: ValueType::I32,
+            checksums: true,
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &options)
+            .expect("could not create db.");
+        for i in 0..10 {
+            db.append_record(RecordInfo {
+                time_offset: i,
+                value: i as i32 * 100,
+            })
+            .expect("could not append record.");
+        }
+        db.update_record(3, -1i32)
+            .expect("could not update record.");
+        db.close().expect("could not close db.");
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert!(db.header.checksums);
+        assert_eq!(db.header.record_size(), 12);
+        assert_eq!(db.read_record(3).map(|r| r.value), Ok(-1i32));
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+        // Flip one bit of the value of the 6th record.
+        let pos = db.header.record_pos(5) + 4;
+        let mut data = fs::read(path).unwrap();
+        data[pos as usize] ^= 0x10;
+        fs::write(path, data).unwrap();
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(
+            db.read_record::<i32>(5),
+            Err(TSLiteError::ChecksumMismatch(5))
+        );
+        assert_eq!(db.read_record(4).map(|r| r.value), Ok(400i32));
+        let res: Result<Vec<_>, TSLiteError> =
+            db.iter::<i32>().expect("could not iterate db.").collect();
+        assert_eq!(res.err(), Some(TSLiteError::ChecksumMismatch(5)));
+        assert_eq!(db.check_db_file(), Ok(DbIssue::RecordCorrupted(5)));
+
+        let report = db.verify().expect("could not verify db.");
+        assert_eq!(report.corrupted_records, 1);
+        assert_eq!(
+            report.findings,
+            vec![Finding {
+                issue: DbIssue::RecordCorrupted(5),
+                record: Some(5),
+                byte_offset: db.header.record_pos(5),
+            }]
+        );
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn millisecond_precision() {
+        let path = "millisecond_precision.db";
+        let _ = fs::remove_file(path);
+
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let options = DbOptions {
+            precision: Precision::Milliseconds,
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), Some(origin_date), &options)
+            .expect("could not create db.");
+        // Two records in the same second.
+        for (offset, value) in &[(1_250, 1u8), (1_750, 2u8), (2_000, 3u8)] {
+            db.append_record(RecordInfo {
+                time_offset: *offset,
+                value: *value,
+            })
+            .expect("could not append record.");
+        }
+        db.close().expect("could not close db.");
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.precision, Precision::Milliseconds);
+
+        let dates: Vec<DateTime<Utc>> = db
+            .iter::<u8>()
+            .expect("could not iterate db.")
+            .map(|res| DateTime::from(&res.expect("could not read record.").0))
+            .collect();
+        assert_eq!(
+            dates[1],
+            origin_date + chrono::Duration::milliseconds(1_750)
+        );
+
+        let from = origin_date + chrono::Duration::milliseconds(1_500);
+        let to = origin_date + chrono::Duration::milliseconds(2_001);
+        let values: Vec<u8> = db
+            .query_range(from, to)
+            .expect("could not query db.")
+            .iter()
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(values, vec![2, 3]);
+
+        let buckets = db
+            .downsample::<u8>(
+                origin_date + chrono::Duration::seconds(1),
+                origin_date + chrono::Duration::seconds(2),
+                chrono::Duration::milliseconds(500),
+                Aggregation::Count,
+                Fill::Null,
+            )
+            .expect("could not downsample db.");
+        let values: Vec<Option<f64>> = buckets.iter().map(|b| b.value).collect();
+        assert_eq!(values, vec![Some(1.0), Some(1.0)]);
+        assert_eq!(
+            db.downsample::<u8>(
+                from,
+                to,
+                chrono::Duration::microseconds(1_500),
+                Aggregation::Count,
+                Fill::Null
+            ),
+            Err(TSLiteError::InvalidInterval)
+        );
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn wide_offsets() {
+        let path = "wide_offsets.db";
+        let _ = fs::remove_file(path);
+
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db =
+            PhysicalDB::create(Path::new(path), Some(origin_date)).expect("could not create db.");
+        let before_origin = RecordInfo {
+            time_offset: -60,
+            value: 1u8,
+        };
+        assert_eq!(
+            db.append_record(before_origin),
+            Err(TSLiteError::TimeOffsetOutOfRange(-60))
+        );
+        assert_eq!(
+            db.append_record(RecordInfo {
+                time_offset: u32::MAX as i64 + 1,
+                value: 1u8,
+            }),
+            Err(TSLiteError::TimeOffsetOutOfRange(u32::MAX as i64 + 1))
+        );
+        assert_eq!(db.header.records_number, 0);
+        let _ = fs::remove_file(path);
+
+        let options = DbOptions {
+            wide_offsets: true,
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), Some(origin_date), &options)
+            .expect("could not create db.");
+        // Two centuries before the origin date, then far beyond the reach of 32bit offsets.
+        let far = 200 * 365 * 24 * 3600;
+        for (offset, value) in &[(-far, 1u8), (-60, 2u8), (0, 3u8), (far, 4u8)] {
+            db.append_record(RecordInfo {
+                time_offset: *offset,
+                value: *value,
+            })
+            .expect("could not append record.");
+        }
+        db.close().expect("could not close db.");
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert!(db.header.wide_offsets);
+        assert_eq!(db.header.record_size(), 9);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert_eq!(db.read_record::<u8>(0).map(|r| r.time_offset), Ok(-far));
+
+        let (date, _) = db
+            .iter::<u8>()
+            .expect("could not iterate db.")
+            .next()
+            .unwrap()
+            .expect("could not read record.");
+        assert_eq!(date.year, 1820);
+
+        let values: Vec<u8> = db
+            .query_range(origin_date - chrono::Duration::hours(1), origin_date)
+            .expect("could not query db.")
+            .iter()
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(values, vec![2]);
+
+        let buckets = db
+            .downsample::<u8>(
+                origin_date - chrono::Duration::minutes(2),
+                origin_date + chrono::Duration::minutes(1),
+                chrono::Duration::minutes(1),
+                Aggregation::Max,
+                Fill::Null,
+            )
+            .expect("could not downsample db.");
+        let values: Vec<Option<f64>> = buckets.iter().map(|b| b.value).collect();
+        assert_eq!(values, vec![None, Some(2.0), Some(3.0)]);
+
+        // Offsets whose date cannot be represented are refused rather than failing once read.
+        for offset in &[i64::MAX, i64::MIN] {
+            assert_eq!(
+                db.append_record(RecordInfo {
+                    time_offset: *offset,
+                    value: 5u8,
+                }),
+                Err(TSLiteError::TimeOffsetOutOfRange(*offset))
+            );
+        }
+        assert_eq!(db.header.records_number, 4);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn timestamp_out_of_range() {
+        for year in &[-1, 70000] {
+            let date = Utc.with_ymd_and_hms(*year, 1, 1, 0, 0, 0).unwrap();
+            assert_eq!(Timestamp::try_from(date), Err(TSLiteError::DateOutOfRange));
+        }
+
+        let origin =
+            Timestamp::try_from(Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap()).unwrap();
+        assert_eq!(origin.shift_in(i64::MAX, Precision::Seconds), None);
+        assert_eq!(
+            origin.shift_in(-2021 * 366 * 24 * 3600, Precision::Seconds),
+            None
+        );
+        assert_eq!(origin.shift(60).map(|date| date.minute), Some(1));
+    }
 }
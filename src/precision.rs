@@ -0,0 +1,83 @@
+//! The precision of the time offsets of a DB.
+//!
+//! Every DB declares in its header the unit of the time offset of its records. A finer precision
+//! allows to tell apart records in the same second, but reduces how far from the origin date
+//! a record can be since the time offset has a fixed size.
+
+use std::convert::TryFrom;
+
+use crate::TSLiteError;
+
+/// The unit of the time offsets of a DB, as written in its header.
+/// With a 32bit time offset, records can be stored up to about 136 years after the origin date
+/// with `Seconds`, 49 days with `Milliseconds`, 71 minutes with `Microseconds`
+/// and 4 seconds with `Nanoseconds`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Precision {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+}
+
+impl Precision {
+    /// The code used to store this precision in the header.
+    pub fn code(self) -> u8 {
+        match self {
+            Precision::Seconds => 0,
+            Precision::Milliseconds => 1,
+            Precision::Microseconds => 2,
+            Precision::Nanoseconds => 3,
+        }
+    }
+
+    /// The duration of `units` time units, `None` if it is too long to be represented.
+    pub fn checked_duration(self, units: i64) -> Option<chrono::Duration> {
+        match self {
+            Precision::Seconds => chrono::Duration::try_seconds(units),
+            Precision::Milliseconds => chrono::Duration::try_milliseconds(units),
+            Precision::Microseconds => Some(chrono::Duration::microseconds(units)),
+            Precision::Nanoseconds => Some(chrono::Duration::nanoseconds(units)),
+        }
+    }
+
+    /// The duration of `units` time units.
+    pub fn duration(self, units: i64) -> chrono::Duration {
+        match self {
+            Precision::Seconds => chrono::Duration::seconds(units),
+            Precision::Milliseconds => chrono::Duration::milliseconds(units),
+            Precision::Microseconds => chrono::Duration::microseconds(units),
+            Precision::Nanoseconds => chrono::Duration::nanoseconds(units),
+        }
+    }
+
+    /// The number of whole time units in `duration`, rounded toward zero.
+    /// Durations too long to be counted saturate.
+    pub fn units(self, duration: chrono::Duration) -> i64 {
+        let saturated = if duration < chrono::Duration::zero() {
+            i64::MIN
+        } else {
+            i64::MAX
+        };
+        match self {
+            Precision::Seconds => duration.num_seconds(),
+            Precision::Milliseconds => duration.num_milliseconds(),
+            Precision::Microseconds => duration.num_microseconds().unwrap_or(saturated),
+            Precision::Nanoseconds => duration.num_nanoseconds().unwrap_or(saturated),
+        }
+    }
+}
+
+impl TryFrom<u8> for Precision {
+    type Error = TSLiteError;
+
+    fn try_from(code: u8) -> Result<Precision, TSLiteError> {
+        match code {
+            0 => Ok(Precision::Seconds),
+            1 => Ok(Precision::Milliseconds),
+            2 => Ok(Precision::Microseconds),
+            3 => Ok(Precision::Nanoseconds),
+            _ => Err(TSLiteError::UnknownPrecision(code)),
+        }
+    }
+}
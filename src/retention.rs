@@ -0,0 +1,342 @@
+//! Retention policies, removing the oldest records of a DB.
+//!
+//! A DB can keep only its most recent records, up to a maximal age and/or a maximal file size.
+//! The policy is stored in the header and enforced when records are appended, or explicitly with
+//! [`PhysicalDB::enforce_retention`].
+//!
+//! Removing the leading records means rewriting the records that are kept. The origin date of the DB
+//! is moved to the date of the first record kept, and the time offset of every record is shifted
+//! accordingly, so the time offsets do not grow forever and a DB without wide offsets never runs out
+//! of them. The records are rewritten to a new file which then replaces the DB file, so an interrupted
+//! removal leaves the DB as it was.
+//!
+//! Rewriting the DB every time a record gets too old would be very costly, so appending records only
+//! enforces the policy once the records exceed it by more than 1/[`RETENTION_SLACK`]th.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Seek, SeekFrom, Write};
+
+use crate::iter::RawRecords;
+use crate::{DbHeader, PhysicalDB, Precision, TSLiteError, Timestamp};
+
+/// When records are appended, the retention policy is only enforced once the age of the records
+/// or the size of the file exceeds the policy by more than 1/`RETENTION_SLACK`th.
+pub const RETENTION_SLACK: u64 = 8;
+
+/// How long and how many records a DB keeps. Every limit is optional.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Retention {
+    /// Records older than this, compared to the most recent record of the DB, are removed.
+    /// It is rounded toward zero to the time units of the DB.
+    pub max_age: Option<chrono::Duration>,
+    /// The oldest records are removed until the DB file is at most this size, in octets.
+    pub max_size: Option<u64>,
+}
+
+impl Retention {
+    /// The size of the retention policy in the header: 8 for the maximal age, 8 for the maximal size.
+    pub(crate) const SIZE: u64 = 8 + 8;
+
+    /// Tell if there is any limit.
+    pub fn is_set(&self) -> bool {
+        self.max_age.is_some() || self.max_size.is_some()
+    }
+
+    /// The maximal age in time units of `precision`, 0 if there is none.
+    fn max_age_units(&self, precision: Precision) -> u64 {
+        self.max_age
+            .map_or(0, |age| precision.units(age).max(0) as u64)
+    }
+
+    /// The policy as it is stored in a DB with the given precision.
+    pub(crate) fn truncated(&self, precision: Precision) -> Retention {
+        let units = self.max_age_units(precision);
+        Retention {
+            max_age: Some(units)
+                .filter(|units| *units > 0)
+                .map(|units| precision.duration(units as i64)),
+            max_size: self.max_size.filter(|size| *size > 0),
+        }
+    }
+
+    pub(crate) fn as_bytes(&self, precision: Precision) -> Vec<u8> {
+        let mut store: Vec<u8> = Vec::with_capacity(Retention::SIZE as usize);
+        store
+            .write_u64::<LittleEndian>(self.max_age_units(precision))
+            .unwrap();
+        store
+            .write_u64::<LittleEndian>(self.max_size.unwrap_or(0))
+            .unwrap();
+        store
+    }
+
+    pub(crate) fn from_bytes(d: &[u8], precision: Precision) -> Retention {
+        let mut reader = Cursor::new(d);
+        let max_age = reader.read_u64::<LittleEndian>().unwrap();
+        let max_size = reader.read_u64::<LittleEndian>().unwrap();
+        Retention {
+            max_age: Some(max_age)
+                .filter(|units| *units > 0)
+                .map(|units| precision.duration(units.min(i64::MAX as u64) as i64)),
+            max_size: Some(max_size).filter(|size| *size > 0),
+        }
+    }
+}
+
+impl DbHeader {
+    /// Shift the time offset of the raw octets of a record by `delta`, updating its checksum if any.
+    pub(crate) fn rebase_record(&self, record: &[u8], delta: i64) -> Vec<u8> {
+        let value_pos = self.offset_size() as usize;
+        let value = &record[value_pos..value_pos + self.value_type.size() as usize];
+        let mut store = self.raw_record(self.raw_time_offset(record) - delta, value);
+        if self.checksums {
+            let checksum = crc32fast::hash(&store);
+            store.write_u32::<LittleEndian>(checksum).unwrap();
+        }
+        store
+    }
+}
+
+impl PhysicalDB {
+    /// Change the retention policy of the DB. It is enforced the next time records are appended.
+    /// Ring DBs already have a fixed capacity, `TSLiteError::UnsupportedOperation` is returned.
+    pub fn set_retention(&mut self, retention: Retention) -> Result<(), TSLiteError> {
+        if self.header.capacity.is_some() {
+            return Err(TSLiteError::UnsupportedOperation);
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let retention = retention.truncated(self.header.precision);
+        let mut fref = self.file.as_ref().unwrap();
+        fref.seek(SeekFrom::Start(DbHeader::RETENTION_POS))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        fref.write_all(&retention.as_bytes(self.header.precision))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        fref.sync_data()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        self.header.retention = retention;
+
+        Ok(())
+    }
+
+    /// The size of the DB file.
+    fn file_len(&self) -> Result<u64, TSLiteError> {
+        Ok(self
+            .file
+            .as_ref()
+            .unwrap()
+            .metadata()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?
+            .len())
+    }
+
+    /// The number of leading records to remove so the DB follows its retention policy,
+    /// with the limits of the policy raised by 1/`slack`th if `slack` is not 0.
+    /// Records are expected to be ordered, see [`PhysicalDB::check_db_file`].
+    fn expired_records(&mut self, slack: u64) -> Result<u64, TSLiteError> {
+        let retention = self.header.retention;
+        let records_number = self.header.records_number;
+        let with_slack = |limit: u64| match slack {
+            0 => limit,
+            _ => limit.saturating_add(limit / slack),
+        };
+        if records_number == 0 {
+            return Ok(0);
+        }
+
+        let mut expired = 0;
+        let max_age = retention.max_age_units(self.header.precision);
+        if max_age > 0 {
+            let last = self.read_record_bytes(records_number - 1)?;
+            let last = self.header.raw_time_offset(&last);
+            let cutoff = last.saturating_sub(with_slack(max_age).min(i64::MAX as u64) as i64);
+            expired = self.search_offset(cutoff)?;
+        }
+
+        // Records of compressed and run-length encoded DBs do not have a fixed size, so the
+        // number of records to remove is estimated with their average size.
+        if let Some(max_size) = retention.max_size {
+            let len = self.file_len()?;
+            if len > with_slack(max_size) {
+                let records_len = len.saturating_sub(self.header.header_len as u64);
+                let record_size = records_len.div_ceil(records_number).max(1);
+                let excess = (len - max_size).div_ceil(record_size);
+                expired = expired.max(excess.min(records_number));
+            }
+        }
+
+        Ok(expired)
+    }
+
+    /// Enforce the retention policy when records were appended, see [`RETENTION_SLACK`].
+    pub(crate) fn enforce_retention_on_append(&mut self) -> Result<(), TSLiteError> {
+        if self.header.retention.is_set() && self.expired_records(RETENTION_SLACK)? > 0 {
+            self.enforce_retention()?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the leading records that do not follow the retention policy of the DB,
+    /// and return how many were removed. Records are expected to be ordered.
+    /// The origin date of the DB is moved to the date of the first record kept, without its
+    /// fraction of second, and the time offsets of the records are shifted accordingly.
+    /// Records appended afterwards must have a time offset computed from the new origin date.
+    /// The deleted records are removed first, see [`PhysicalDB::compact`].
+    pub fn enforce_retention(&mut self) -> Result<u64, TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+        self.compact()?;
+
+        let mut removed = 0;
+        loop {
+            let expired = self.expired_records(0)?;
+            if expired == 0 {
+                return Ok(removed);
+            }
+            self.remove_leading_records(expired)?;
+            removed += expired;
+        }
+    }
+
+    /// Remove the `count` first records and rebase the origin date on the first record kept.
+    /// The records kept are rewritten to a new file with the new origin date, which then replaces
+    /// the DB file, so the origin date only changes together with the time offsets of the records.
+    fn remove_leading_records(&mut self, count: u64) -> Result<(), TSLiteError> {
+        let header = self.header;
+        let records_number = header.records_number;
+        let origin_date = match RawRecords::unchecked(self, count, records_number).next() {
+            Some(first) => Timestamp {
+                nanosecond: 0,
+                ..header.record_date(header.raw_time_offset(&first?))?
+            },
+            None => header.origin_date,
+        };
+        let delta = header.origin_date.offset_in(&origin_date, header.precision);
+
+        self.rewrite(origin_date, |db, new| {
+            db.copy_records(new, count, records_number, |record| {
+                header.rebase_record(record, delta)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbIssue, DbOptions, RecordInfo};
+    use chrono::{DateTime, TimeZone, Utc};
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn retention_max_age() {
+        let path = "retention_max_age.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let options = DbOptions {
+            retention: Retention {
+                max_age: Some(chrono::Duration::hours(1)),
+                max_size: None,
+            },
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), Some(origin_date), &options)
+            .expect("could not create db.");
+
+        // A record every minute for three hours, the offsets following the origin date of the DB.
+        for i in 0..180 {
+            let date = origin_date + chrono::Duration::minutes(i);
+            let origin: DateTime<Utc> = (&db.header.origin_date).into();
+            db.append_record(RecordInfo {
+                time_offset: (date - origin).num_seconds(),
+                value: (i % 256) as u8,
+            })
+            .expect("could not append record.");
+        }
+
+        // Old records are removed on append, but only once they are old enough.
+        let first: DateTime<Utc> = (&db.header.origin_date).into();
+        let last = origin_date + chrono::Duration::minutes(179);
+        assert!(last - first <= chrono::Duration::minutes(60 + 60 / RETENTION_SLACK as i64));
+        assert!(db.header.records_number < 180);
+
+        assert!(
+            db.enforce_retention()
+                .expect("could not enforce retention.")
+                > 0
+        );
+        assert_eq!(db.header.records_number, 61);
+        assert_eq!(
+            db.header.origin_date,
+            Timestamp::try_from(origin_date + chrono::Duration::minutes(119)).unwrap()
+        );
+        assert_eq!(
+            db.read_record(0),
+            Ok(RecordInfo {
+                time_offset: 0,
+                value: 119u8
+            })
+        );
+        assert_eq!(db.enforce_retention(), Ok(0));
+        db.close().expect("could not close db.");
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.retention, options.retention);
+        let from = origin_date + chrono::Duration::minutes(150);
+        let to = origin_date + chrono::Duration::minutes(152);
+        let values: Vec<u8> = db
+            .query_range::<u8>(from, to)
+            .expect("could not query db.")
+            .iter()
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(values, vec![150, 151]);
+
+        db.set_retention(Retention::default())
+            .expect("could not set retention.");
+        db.close().expect("could not close db.");
+        let db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert!(!db.header.retention.is_set());
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn retention_max_size() {
+        let path = "retention_max_size.db";
+        let options = DbOptions {
+            checksums: true,
+            retention: Retention {
+                max_age: None,
+                max_size: Some(DbHeader::SIZE + 100 * 9),
+            },
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &options)
+            .expect("could not create db.");
+        let records: Vec<RecordInfo> = (0..500)
+            .map(|i| RecordInfo {
+                time_offset: 10 + i * 2,
+                value: (i % 256) as u8,
+            })
+            .collect();
+        db.append_records(&records)
+            .expect("could not append records.");
+        assert_eq!(db.header.records_number, 100);
+        assert_eq!(fs::metadata(path).unwrap().len(), DbHeader::SIZE + 100 * 9);
+
+        // The first record kept is now at the origin date, and checksums still match.
+        assert_eq!(db.read_record::<u8>(0).map(|r| r.time_offset), Ok(0));
+        assert_eq!(db.read_record::<u8>(99).map(|r| r.time_offset), Ok(198));
+        assert_eq!(db.read_record::<u8>(99).map(|r| r.value), Ok(243));
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert!(db.verify().expect("could not verify db.").is_healthy());
+
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,261 @@
+//! Rewriting the records of a DB into a new file.
+//!
+//! Operations that move every record of a DB, like removing its oldest records, write the records that
+//! are kept to a new DB file next to it, named after it with `.tmp` appended. The new file holds the same
+//! header, so it keeps the options and the labels of the DB, with the origin date of the records written.
+//! Once its records are committed and it is synced, it replaces the DB file, so a crash while rewriting
+//! leaves the DB as it was.
+//!
+//! Deleted records are not written to the new file. The tombstones are removed right before the DB file is
+//! replaced, so a crash in between brings back the deleted records rather than deleting others. The index
+//! of a compressed DB is emptied first too, so it is rebuilt whichever file is found when the DB is opened.
+
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::iter::RawRecords;
+use crate::tombstone::Tombstones;
+use crate::{replace_file, DbHeader, PhysicalDB, Storage, TSLiteError, Timestamp};
+
+/// The number of records read at once when they are copied to the new file.
+const REWRITE_BATCH_RECORDS: u64 = 4096;
+
+impl PhysicalDB {
+    /// The path of the new file of a rewrite: the path of the DB followed by `.tmp`.
+    pub(crate) fn rewrite_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        PathBuf::from(path)
+    }
+
+    /// Replace the records of the DB by the ones `fill` appends to the new DB given to it, whose origin
+    /// date is `origin_date`, see the module documentation.
+    pub(crate) fn rewrite<F>(&mut self, origin_date: Timestamp, fill: F) -> Result<(), TSLiteError>
+    where
+        F: FnOnce(&mut PhysicalDB, &mut PhysicalDB) -> Result<(), TSLiteError>,
+    {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let tmp_path = self.rewrite_path();
+        let res = self
+            .create_rewrite(&tmp_path, origin_date)
+            .and_then(|mut new| {
+                fill(self, &mut new)?;
+                new.finish_rewrite()?;
+                Ok(new)
+            });
+        let new = match res {
+            Ok(new) => new,
+            Err(e) => {
+                let mut index_path = tmp_path.clone().into_os_string();
+                index_path.push(".idx");
+                let _ = fs::remove_file(&tmp_path);
+                let _ = fs::remove_file(index_path);
+                return Err(e);
+            }
+        };
+
+        if self.header.storage == Storage::Compressed {
+            let index = self.open_index()?;
+            index
+                .set_len(0)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            index
+                .sync_all()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        }
+        self.clear_tombstones()?;
+        self.file = None;
+        replace_file(&new.path, &self.path)?;
+        if self.header.storage == Storage::Compressed {
+            replace_file(&new.index_path(), &self.index_path())?;
+        }
+
+        self.header = new.header;
+        self.open()
+    }
+
+    /// Append the records in `[first, last)` that are not deleted to the new DB of a rewrite,
+    /// as given by `map` from their raw octets.
+    pub(crate) fn copy_records<F>(
+        &mut self,
+        new: &mut PhysicalDB,
+        first: u64,
+        last: u64,
+        map: F,
+    ) -> Result<(), TSLiteError>
+    where
+        F: Fn(&[u8]) -> Vec<u8>,
+    {
+        let mut start = first;
+        while start < last {
+            let end = (start + REWRITE_BATCH_RECORDS).min(last);
+            let records = RawRecords::unchecked(self, start, end)
+                .collect::<Result<Vec<Vec<u8>>, TSLiteError>>()?;
+            let kept: Vec<Vec<u8>> = (start..)
+                .zip(records)
+                .filter(|(rec_id, _)| !self.is_deleted(*rec_id))
+                .map(|(_, record)| map(&record))
+                .collect();
+            new.append_rewrite(&kept)?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    /// Create an empty DB at `path` with the header of the DB, labels included,
+    /// and the origin date `origin_date`.
+    pub(crate) fn create_rewrite(
+        &mut self,
+        path: &Path,
+        origin_date: Timestamp,
+    ) -> Result<PhysicalDB, TSLiteError> {
+        let mut bytes = vec![0u8; self.header.header_len as usize];
+        let mut fref = self.file.as_ref().unwrap();
+        fref.seek(SeekFrom::Start(0))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        fref.read_exact(&mut bytes)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let date_pos = DbHeader::PREAMBLE_SIZE as usize;
+        let date = origin_date.as_bytes();
+        bytes[date_pos..date_pos + date.len()].copy_from_slice(&date);
+
+        let mut header = self.header;
+        header.origin_date = origin_date;
+        header.records_number = 0;
+        header.overwritten = 0;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        file.write_all(&bytes)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        if header.capacity.is_some() {
+            file.set_len(header.record_pos(0) + header.ring_slots() * header.record_size())
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        }
+
+        let new = PhysicalDB {
+            path: path.to_path_buf(),
+            file: Some(file),
+            header,
+            tombstones: Tombstones::default(),
+        };
+        let _ = fs::remove_file(new.index_path());
+        Ok(new)
+    }
+
+    /// Add records after the records of a DB being created by [`PhysicalDB::create_rewrite`].
+    /// Records of the plain storage are only committed by [`PhysicalDB::finish_rewrite`].
+    pub(crate) fn append_rewrite(&mut self, records: &[Vec<u8>]) -> Result<(), TSLiteError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        match self.header.storage {
+            Storage::Plain => {
+                let rec_id = self.header.records_number;
+                self.write_records(rec_id, &records.concat())?;
+                self.header.records_number += records.len() as u64;
+                Ok(())
+            }
+            Storage::Compressed => self.append_compressed(records),
+            Storage::Rle => self.append_rle(records),
+        }
+    }
+
+    /// Sync and commit the records of a DB created by [`PhysicalDB::create_rewrite`], then close it.
+    /// The header copied from the DB holds its number of records, so it is committed even if there
+    /// is no record.
+    pub(crate) fn finish_rewrite(&mut self) -> Result<(), TSLiteError> {
+        self.file
+            .as_ref()
+            .unwrap()
+            .sync_all()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        self.commit_records_number(self.header.records_number)?;
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbIssue, DbOptions, RecordInfo};
+    use chrono::{TimeZone, Utc};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn interrupted_rewrite() {
+        let path = "interrupted_rewrite.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        for &storage in [Storage::Plain, Storage::Compressed, Storage::Rle].iter() {
+            let options = DbOptions {
+                storage,
+                ..DbOptions::default()
+            };
+            let mut db =
+                PhysicalDB::create_with_options(Path::new(path), Some(origin_date), &options)
+                    .expect("could not create db.");
+            let records: Vec<RecordInfo> = (0..1000)
+                .map(|i| RecordInfo {
+                    time_offset: i * 60,
+                    value: (i / 10 % 256) as u8,
+                })
+                .collect();
+            db.append_records(&records)
+                .expect("could not append records.");
+
+            // The rewrite fails once part of the records are written to the new file.
+            let later = Timestamp::try_from(origin_date + chrono::Duration::hours(1)).unwrap();
+            let res = db.rewrite(later, |db, new| {
+                db.copy_records(new, 0, 500, |record| record.to_vec())?;
+                Err(TSLiteError::IOError("interrupted".to_string()))
+            });
+            assert_eq!(res, Err(TSLiteError::IOError("interrupted".to_string())));
+            assert!(!db.rewrite_path().exists());
+            db.close().expect("could not close db.");
+
+            let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+            assert_eq!(
+                db.header.origin_date,
+                Timestamp::try_from(origin_date).unwrap()
+            );
+            assert_eq!(db.header.records_number, 1000);
+            assert_eq!(db.read_record(999), Ok(records[999]));
+
+            // The records that are kept replace the DB along with the new origin date.
+            db.delete_record(600).expect("could not delete record.");
+            let header = db.header;
+            db.rewrite(later, |db, new| {
+                db.copy_records(new, 60, 1000, |record| header.rebase_record(record, 3600))
+            })
+            .expect("could not rewrite db.");
+            assert_eq!(db.header.origin_date, later);
+            assert_eq!(db.header.records_number, 939);
+            assert_eq!(db.deleted(), 0);
+            assert!(!db.tombstones_path().exists());
+            db.close().expect("could not close db.");
+
+            let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+            assert_eq!(db.header.origin_date, later);
+            assert_eq!(db.read_record::<u8>(0).map(|r| r.time_offset), Ok(0));
+            assert_eq!(
+                db.read_record::<u8>(540).map(|r| r.time_offset),
+                Ok(601 * 60 - 3600)
+            );
+            assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+            let _ = fs::remove_file(db.index_path());
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Ring DBs, holding a fixed number of records.
+//!
+//! A DB created with a capacity (see [`DbOptions::capacity`](crate::DbOptions::capacity)) allocates
+//! all its records at once, in one more slot than its capacity. Records are written one after the
+//! other in the slots, wrapping around to the first slot after the last one, so the file never changes
+//! size. Once the DB is full, every new record overwrites the oldest one.
+//!
+//! The commit slots hold the number of records the DB has ever held, from which the position of the
+//! oldest record (the head) and of the next record to write (the tail) are derived. Since there is
+//! always a free slot after the last record, new records are written there before being committed,
+//! and an interrupted append leaves the committed records untouched. Records appended to a full DB
+//! are committed one at a time, since each of them needs the slot freed by the previous one.
+
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::{DbHeader, PhysicalDB, TSLiteError};
+
+impl DbHeader {
+    /// The number of record slots in the file of a ring, one more than its capacity.
+    pub fn ring_slots(&self) -> u64 {
+        self.capacity.map_or(u64::MAX, |capacity| capacity + 1)
+    }
+
+    /// The slot holding the record `rec_id`, which is `rec_id` unless the DB is a ring.
+    pub fn ring_slot(&self, rec_id: u64) -> u64 {
+        match self.capacity {
+            Some(_) => (self.overwritten + rec_id) % self.ring_slots(),
+            None => rec_id,
+        }
+    }
+
+    /// The number of records that can be read or written one after the other in the file from the
+    /// record `rec_id`, before wrapping around to the first slot of a ring.
+    pub(crate) fn contiguous_records(&self, rec_id: u64) -> u64 {
+        self.ring_slots() - self.ring_slot(rec_id)
+    }
+}
+
+impl PhysicalDB {
+    /// Write the encoded records in `data` from the record `rec_id` on, wrapping around the slots
+    /// of a ring. Nothing is synced.
+    pub(crate) fn write_records(&self, rec_id: u64, data: &[u8]) -> Result<(), TSLiteError> {
+        let record_size = self.header.record_size();
+        let mut fref = self.file.as_ref().unwrap();
+        let mut rec_id = rec_id;
+        let mut data = data;
+        while !data.is_empty() {
+            let count = self
+                .header
+                .contiguous_records(rec_id)
+                .min(data.len() as u64 / record_size);
+            let (chunk, rest) = data.split_at((count * record_size) as usize);
+            fref.seek(SeekFrom::Start(self.header.record_pos(rec_id)))
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            fref.write_all(chunk)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            rec_id += count;
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    /// Append encoded records to a ring. Records are written in the free slots and committed,
+    /// as many at a time as there are free slots.
+    pub(crate) fn append_ring(&mut self, data: &[u8]) -> Result<(), TSLiteError> {
+        let record_size = self.header.record_size();
+        let mut data = data;
+        while !data.is_empty() {
+            let free = self.header.ring_slots() - self.header.records_number;
+            let count = free.min(data.len() as u64 / record_size);
+            let (chunk, rest) = data.split_at((count * record_size) as usize);
+            self.write_records(self.header.records_number, chunk)?;
+            self.file
+                .as_ref()
+                .unwrap()
+                .sync_data()
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            self.commit_records_number(self.header.records_number + count)?;
+            data = rest;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DbIssue, DbOptions, PhysicalDB, RecordInfo, Retention, TSLiteError};
+    use chrono::{TimeZone, Utc};
+    use std::fs;
+    use std::path::Path;
+
+    fn ring_options(capacity: u64) -> DbOptions {
+        DbOptions {
+            capacity: Some(capacity),
+            ..DbOptions::default()
+        }
+    }
+
+    #[test]
+    fn ring_wraps_around() {
+        let path = "ring_wraps_around.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db =
+            PhysicalDB::create_with_options(Path::new(path), Some(origin_date), &ring_options(10))
+                .expect("could not create db.");
+        let size = fs::metadata(path).unwrap().len();
+        assert_eq!(size, db.header.record_pos(0) + 11 * db.header.record_size());
+
+        let records: Vec<RecordInfo> = (0..25)
+            .map(|i| RecordInfo {
+                time_offset: i * 10,
+                value: i as u8,
+            })
+            .collect();
+        db.append_records(&records[..7])
+            .expect("could not append records.");
+        for record in &records[7..12] {
+            db.append_record(*record).expect("could not append record.");
+        }
+        db.append_records(&records[12..])
+            .expect("could not append records.");
+        assert_eq!(fs::metadata(path).unwrap().len(), size);
+        assert_eq!(db.header.records_number, 10);
+        assert_eq!(db.header.overwritten, 15);
+        db.close().expect("could not close db.");
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.capacity, Some(10));
+        assert_eq!(db.header.records_number, 10);
+        assert_eq!(db.read_record(0), Ok(records[15]));
+        assert_eq!(db.read_record(9), Ok(records[24]));
+        assert_eq!(db.read_record::<u8>(10), Err(TSLiteError::IndexOutOfBound));
+
+        let read: Vec<RecordInfo> = db
+            .iter::<u8>()
+            .expect("could not iterate db.")
+            .rev()
+            .map(|res| res.expect("could not read record.").1)
+            .collect();
+        assert!(read.iter().rev().eq(records[15..].iter()));
+        let from = origin_date + chrono::Duration::seconds(195);
+        let to = origin_date + chrono::Duration::seconds(225);
+        let read = db.query_range::<u8>(from, to).expect("could not query db.");
+        assert_eq!(read, records[20..23].to_vec());
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert!(db.verify().expect("could not verify db.").is_healthy());
+
+        db.update_record(0, 42u8).expect("could not update record.");
+        assert_eq!(db.read_record::<u8>(0).map(|r| r.value), Ok(42));
+        assert_eq!(
+            db.set_retention(Retention::default()),
+            Err(TSLiteError::UnsupportedOperation)
+        );
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn ring_recover_and_reorder() {
+        let path = "ring_recover_and_reorder.db";
+        let options = DbOptions {
+            checksums: true,
+            ..ring_options(4)
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &options)
+            .expect("could not create db.");
+        for offset in &[1, 2, 3, 4, 5, 9, 7, 8] {
+            db.append_record(RecordInfo {
+                time_offset: *offset,
+                value: 0u8,
+            })
+            .expect("could not append record.");
+        }
+
+        // An append interrupted before its commit only wrote in the free slot.
+        let pos = db.header.record_pos(db.header.records_number) as usize;
+        let mut data = fs::read(path).unwrap();
+        data[pos..pos + db.header.record_size() as usize].copy_from_slice(&[0xff; 9]);
+        fs::write(path, data).unwrap();
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.check_db_file(), Ok(DbIssue::UnorderedRecord));
+        db.reorder_record().expect("could not reorder db.");
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        let offsets: Vec<i64> = db
+            .iter::<u8>()
+            .expect("could not iterate db.")
+            .map(|res| res.expect("could not read record.").1.time_offset)
+            .collect();
+        assert_eq!(offsets, vec![5, 7, 8, 9]);
+
+        let options = DbOptions {
+            retention: Retention {
+                max_age: None,
+                max_size: Some(1000),
+            },
+            ..ring_options(4)
+        };
+        assert_eq!(
+            PhysicalDB::create_with_options(Path::new(path), None, &options).err(),
+            Some(TSLiteError::IncompatibleOptions)
+        );
+
+        let _ = fs::remove_file(path);
+    }
+}
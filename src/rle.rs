@@ -0,0 +1,599 @@
+//! Run-length encoded storage of the records.
+//!
+//! Consecutive records with the same value are stored as a single run, holding the index of its first
+//! record, the time offset of its first record (the start), the time offset of its last record (the end)
+//! and their value. The number of records of a run is given by the first record of the next run, or by
+//! the number of records of the DB for the last run. A record with the same value as the last run
+//! extends it if it follows its last record by the same interval as the records of the run, any record
+//! not anterior to it extending a run of a single record. Otherwise it starts a new run.
+//!
+//! Only the start and the end of a run are stored, the records of a run being taken at a regular interval
+//! the time offsets of the records in between are computed from them.
+//!
+//! ```text
+//! +---------------------------------[RUN]-----------------------------------+
+//! |-[FIRST RECORD]-|-[START OFFSET]-|-[VALUE]-|-[END SLOT]-|-[END SLOT]-|
+//! |     64bit      | 32bit or 64bit | 8-64bit |   idem     |   idem     |
+//! +-------------------------------------------------------------------------+
+//!
+//! +---------------[END SLOT]---------------+
+//! |-[RECORDS OF THE RUN]-|-[END OFFSET]-|
+//! |        64bit         | 32bit or 64bit |
+//! +----------------------------------------+
+//! ```
+//!
+//! Appending a record that extends the last run writes its new end in the slot that does not hold the
+//! end for the committed number of records, then commits, like the commit slots of the header. The end
+//! of a run is the one of the slot matching its number of records, so an interrupted append never
+//! changes the committed records. Runs have a fixed size, so they can be found with a binary search
+//! like records with the plain storage.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use crate::{DbHeader, PhysicalDB, TSLiteError};
+
+/// Consecutive records sharing the same value.
+#[derive(Debug, Clone, PartialEq)]
+struct Run {
+    first_record: u64,
+    start: i64,
+    value: Vec<u8>,
+    /// The number of records of the run and the time offset of its last record, written alternately.
+    ends: [(u64, i64); 2],
+}
+
+impl DbHeader {
+    /// The size of a run in the file: 8 for the first record, the start offset, the value and
+    /// two end slots.
+    pub(crate) fn run_size(&self) -> u64 {
+        8 + self.offset_size() + self.value_type.size() + 2 * self.end_slot_size()
+    }
+
+    /// The size of an end slot of a run: 8 for the number of records and the end offset.
+    fn end_slot_size(&self) -> u64 {
+        8 + self.offset_size()
+    }
+}
+
+fn write_offset(header: &DbHeader, offset: i64, store: &mut Vec<u8>) {
+    if header.wide_offsets {
+        store.write_i64::<LittleEndian>(offset).unwrap();
+    } else {
+        store.write_u32::<LittleEndian>(offset as u32).unwrap();
+    }
+}
+
+fn read_offset(header: &DbHeader, reader: &mut Cursor<&[u8]>) -> i64 {
+    if header.wide_offsets {
+        reader.read_i64::<LittleEndian>().unwrap()
+    } else {
+        reader.read_u32::<LittleEndian>().unwrap() as i64
+    }
+}
+
+impl Run {
+    /// A run of a single record.
+    fn new(first_record: u64, start: i64, value: &[u8]) -> Run {
+        Run {
+            first_record,
+            start,
+            value: value.to_vec(),
+            ends: [(1, start), (0, 0)],
+        }
+    }
+
+    fn as_bytes(&self, header: &DbHeader) -> Vec<u8> {
+        let mut store: Vec<u8> = Vec::with_capacity(header.run_size() as usize);
+        store.write_u64::<LittleEndian>(self.first_record).unwrap();
+        write_offset(header, self.start, &mut store);
+        store.extend(&self.value);
+        for slot in 0..self.ends.len() {
+            store.extend(self.slot_bytes(header, slot));
+        }
+        store
+    }
+
+    fn slot_bytes(&self, header: &DbHeader, slot: usize) -> Vec<u8> {
+        let (count, end) = self.ends[slot];
+        let mut store: Vec<u8> = Vec::with_capacity(header.end_slot_size() as usize);
+        store.write_u64::<LittleEndian>(count).unwrap();
+        write_offset(header, end, &mut store);
+        store
+    }
+
+    /// The position of the end slot `slot` within a run.
+    fn slot_pos(header: &DbHeader, slot: usize) -> u64 {
+        8 + header.offset_size() + header.value_type.size() + slot as u64 * header.end_slot_size()
+    }
+
+    fn from_bytes(header: &DbHeader, d: &[u8]) -> Run {
+        let mut reader = Cursor::new(d);
+        let first_record = reader.read_u64::<LittleEndian>().unwrap();
+        let start = read_offset(header, &mut reader);
+        let value_pos = reader.position() as usize;
+        let value = d[value_pos..value_pos + header.value_type.size() as usize].to_vec();
+        reader.set_position(Run::slot_pos(header, 0));
+        let mut ends = [(0, 0); 2];
+        for end in ends.iter_mut() {
+            let count = reader.read_u64::<LittleEndian>().unwrap();
+            *end = (count, read_offset(header, &mut reader));
+        }
+        Run {
+            first_record,
+            start,
+            value,
+            ends,
+        }
+    }
+
+    /// The time offset of the last record of the run when it holds `count` records.
+    fn end(&self, count: u64) -> i64 {
+        self.ends
+            .iter()
+            .find(|(n, _)| *n == count)
+            .map_or(self.start, |(_, end)| *end)
+    }
+
+    /// Tell if a record at `offset` can follow the last of the `count` records of the run,
+    /// keeping them at a regular interval.
+    fn extended_by(&self, offset: i64, count: u64) -> bool {
+        let end = self.end(count);
+        if count == 1 {
+            return offset >= end;
+        }
+
+        let interval = (end as i128 - self.start as i128) / (count - 1) as i128;
+        offset as i128 - end as i128 == interval
+    }
+
+    /// The slot to write the end of the run in, keeping the end for `committed` records.
+    fn free_slot(&self, committed: u64) -> usize {
+        if self.ends[0].0 == committed {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// The time offset of the `k`-th record of the run holding `count` records.
+    fn offset(&self, k: u64, count: u64) -> i64 {
+        if count <= 1 {
+            return self.start;
+        }
+
+        let span = self.end(count) as i128 - self.start as i128;
+        (self.start as i128 + span * k as i128 / (count - 1) as i128) as i64
+    }
+}
+
+impl PhysicalDB {
+    /// The position of the run `run` within the file.
+    fn run_pos(&self, run: u64) -> u64 {
+        self.header.header_len as u64 + run * self.header.run_size()
+    }
+
+    /// The number of full runs in the file.
+    fn runs_number(&self) -> Result<u64, TSLiteError> {
+        let len = self
+            .file
+            .as_ref()
+            .unwrap()
+            .metadata()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?
+            .len();
+        Ok(len.saturating_sub(self.header.header_len as u64) / self.header.run_size())
+    }
+
+    fn read_run(&self, run: u64) -> Result<Run, TSLiteError> {
+        let mut fref = self.file.as_ref().unwrap();
+        fref.seek(SeekFrom::Start(self.run_pos(run)))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let mut buffer = vec![0; self.header.run_size() as usize];
+        fref.read_exact(&mut buffer)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        Ok(Run::from_bytes(&self.header, &buffer))
+    }
+
+    /// Find the last run among the `runs` first ones whose first record is before or at `rec_id`.
+    fn run_of(&self, rec_id: u64, runs: u64) -> Result<u64, TSLiteError> {
+        let mut low = 0;
+        let mut high = runs;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.read_run(mid)?.first_record <= rec_id {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(low.saturating_sub(1))
+    }
+
+    /// The number of records of the run `run` among `runs` runs.
+    fn run_count(&self, run: &Run, index: u64, runs: u64) -> Result<u64, TSLiteError> {
+        let end = if index + 1 < runs {
+            self.read_run(index + 1)?.first_record
+        } else {
+            self.header.records_number
+        };
+
+        Ok(end.saturating_sub(run.first_record))
+    }
+
+    /// Read the raw octets of the records in `[start, start + count)` of a run-length encoded DB.
+    /// Records beyond the number of records of the DB are not returned.
+    pub(crate) fn read_rle(&mut self, start: u64, count: u64) -> Result<Vec<u8>, TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let end = (start + count).min(self.header.records_number);
+        let runs = self.runs_number()?;
+        let mut data = Vec::new();
+        if start >= end || runs == 0 {
+            return Ok(data);
+        }
+
+        let mut index = self.run_of(start, runs)?;
+        let mut run = self.read_run(index)?;
+        let mut rec_id = start;
+        while rec_id < end {
+            let next = if index + 1 < runs {
+                Some(self.read_run(index + 1)?)
+            } else {
+                None
+            };
+            let run_end = next
+                .as_ref()
+                .map_or(self.header.records_number, |next| next.first_record);
+            let run_count = run_end.saturating_sub(run.first_record);
+            while rec_id < end && rec_id < run_end {
+                let offset = run.offset(rec_id - run.first_record, run_count);
+                data.extend(self.header.raw_record(offset, &run.value));
+                rec_id += 1;
+            }
+
+            match next {
+                Some(next) => {
+                    run = next;
+                    index += 1;
+                }
+                None => break,
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// The position in the file of the run holding the record `rec_id` of a run-length encoded DB.
+    pub(crate) fn rle_record_pos(&self, rec_id: u64) -> Result<u64, TSLiteError> {
+        let runs = self.runs_number()?;
+        Ok(self.run_pos(self.run_of(rec_id, runs)?))
+    }
+
+    /// Find the index of the first record with a time offset greater or equal to `offset` in a
+    /// run-length encoded DB, with a binary search over the start of the runs.
+    pub(crate) fn search_rle(&mut self, offset: i64) -> Result<u64, TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        // Find the first run starting at or after `offset`, the record is either its first one
+        // or in the run before it.
+        let runs = self.runs_number()?;
+        let mut low = 0;
+        let mut high = runs;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.read_run(mid)?.start < offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low == 0 {
+            return Ok(0);
+        }
+
+        // The first record of the run at or after `offset`.
+        let index = low - 1;
+        let run = self.read_run(index)?;
+        let count = self.run_count(&run, index, runs)?;
+        let mut low = 0;
+        let mut high = count;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if run.offset(mid, count) < offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(run.first_record + low)
+    }
+
+    /// Append the raw octets of records to a run-length encoded DB, then commit them.
+    /// Records extending the last run only write its new end in its free slot.
+    pub(crate) fn append_rle(&mut self, records: &[Vec<u8>]) -> Result<(), TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let header = self.header;
+        let records_number = header.records_number;
+        let runs = self.runs_number()?;
+        // The runs with their committed number of records and their new one.
+        let mut pending: Vec<(Run, u64, u64)> = Vec::new();
+        if runs > 0 {
+            let last = self.read_run(runs - 1)?;
+            let count = records_number.saturating_sub(last.first_record);
+            pending.push((last, count, count));
+        }
+
+        for (i, record) in records.iter().enumerate() {
+            let offset = header.raw_time_offset(record);
+            let value = &record[header.offset_size() as usize..];
+            match pending.last_mut() {
+                Some((run, committed, count))
+                    if *count > 0 && value == &run.value[..] && run.extended_by(offset, *count) =>
+                {
+                    *count += 1;
+                    let slot = run.free_slot(*committed);
+                    run.ends[slot] = (*count, offset);
+                }
+                _ => pending.push((Run::new(records_number + i as u64, offset, value), 0, 1)),
+            }
+        }
+
+        let mut fref = self.file.as_ref().unwrap();
+        let mut first_new = 0;
+        if runs > 0 {
+            first_new = 1;
+            let (run, committed, count) = &pending[0];
+            if count > committed {
+                let slot = run.free_slot(*committed);
+                fref.seek(SeekFrom::Start(
+                    self.run_pos(runs - 1) + Run::slot_pos(&header, slot),
+                ))
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+                fref.write_all(&run.slot_bytes(&header, slot))
+                    .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            }
+        }
+        let mut store: Vec<u8> = Vec::new();
+        for (run, _, _) in &pending[first_new..] {
+            store.extend(run.as_bytes(&header));
+        }
+        fref.seek(SeekFrom::Start(self.run_pos(runs)))
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        fref.write_all(&store)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        fref.sync_data()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        self.commit_records_number(records_number + records.len() as u64)
+    }
+
+    /// Bring a run-length encoded DB back to the last committed state, see [`PhysicalDB::recover`].
+    /// The runs added by an interrupted append start at or after the committed number of records.
+    pub(crate) fn recover_rle(&mut self) -> Result<(), TSLiteError> {
+        let mut runs = self.runs_number()?;
+        while runs > 0 && self.read_run(runs - 1)?.first_record >= self.header.records_number {
+            runs -= 1;
+        }
+
+        self.truncate_records(self.run_pos(runs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbIssue, DbOptions, RecordInfo, Storage, ValueType};
+    use chrono::{TimeZone, Utc};
+    use std::fs;
+    use std::path::Path;
+
+    fn rle_options() -> DbOptions {
+        DbOptions {
+            storage: Storage::Rle,
+            ..DbOptions::default()
+        }
+    }
+
+    #[test]
+    fn rle_series() {
+        let path = "rle_series.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let mut db =
+            PhysicalDB::create_with_options(Path::new(path), Some(origin_date), &rle_options())
+                .expect("could not create db.");
+
+        // A state flag sampled every minute, which changes every 100 samples.
+        let records: Vec<RecordInfo> = (0..1000)
+            .map(|i| RecordInfo {
+                time_offset: i * 60,
+                value: (i / 100 % 2) as u8,
+            })
+            .collect();
+        for batch in records.chunks(30) {
+            db.append_records(batch).expect("could not append records.");
+        }
+        for record in &records[..10] {
+            db.append_record(RecordInfo {
+                time_offset: 60_000 + record.time_offset * 2,
+                value: 7u8,
+            })
+            .expect("could not append record.");
+        }
+        db.close().expect("could not close db.");
+
+        let runs = (fs::metadata(path).unwrap().len() - db.header.header_len as u64)
+            / db.header.run_size();
+        assert_eq!(runs, 11);
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.storage, Storage::Rle);
+        assert_eq!(db.header.records_number, 1010);
+        assert_eq!(db.read_record(0), Ok(records[0]));
+        assert_eq!(db.read_record(450), Ok(records[450]));
+        assert_eq!(
+            db.read_record(1009),
+            Ok(RecordInfo {
+                time_offset: 61_080,
+                value: 7u8
+            })
+        );
+        assert_eq!(
+            db.read_record::<u8>(1010),
+            Err(TSLiteError::IndexOutOfBound)
+        );
+
+        let read: Vec<RecordInfo> = db
+            .iter::<u8>()
+            .expect("could not iterate db.")
+            .take(1000)
+            .map(|res| res.expect("could not read record.").1)
+            .collect();
+        assert_eq!(read, records);
+
+        let from = origin_date + chrono::Duration::seconds(5950);
+        let to = origin_date + chrono::Duration::seconds(6120);
+        let offsets: Vec<i64> = db
+            .query_range::<u8>(from, to)
+            .expect("could not query db.")
+            .iter()
+            .map(|r| r.time_offset)
+            .collect();
+        assert_eq!(offsets, vec![6000, 6060]);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert!(db.verify().expect("could not verify db.").is_healthy());
+        assert_eq!(
+            db.update_record(0, 1u8),
+            Err(TSLiteError::UnsupportedOperation)
+        );
+
+        let options = DbOptions {
+            value_type: ValueType::U8,
+            checksums: true,
+            ..rle_options()
+        };
+        assert_eq!(
+            PhysicalDB::create_with_options(Path::new(path), None, &options).err(),
+            Some(TSLiteError::IncompatibleOptions)
+        );
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn rle_recover_and_reorder() {
+        let path = "rle_recover_and_reorder.db";
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &rle_options())
+            .expect("could not create db.");
+        for (offset, value) in &[(30, 1u8), (10, 1), (20, 1), (40, 2), (50, 2)] {
+            db.append_record(RecordInfo {
+                time_offset: *offset,
+                value: *value,
+            })
+            .expect("could not append record.");
+        }
+
+        // An append interrupted before its commit: a new run and part of another one.
+        let run = Run::new(5, 60, &[3]);
+        let mut data = fs::read(path).unwrap();
+        data.extend(run.as_bytes(&db.header));
+        data.extend(&[0xff; 5]);
+        fs::write(path, data).unwrap();
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.records_number, 5);
+        assert_eq!(db.runs_number(), Ok(3));
+        assert_eq!(db.check_db_file(), Ok(DbIssue::UnorderedRecord));
+
+        db.reorder_record().expect("could not reorder db.");
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert_eq!(db.runs_number(), Ok(2));
+        let offsets: Vec<i64> = db
+            .iter::<u8>()
+            .expect("could not iterate db.")
+            .map(|res| res.expect("could not read record.").1.time_offset)
+            .collect();
+        assert_eq!(offsets, vec![10, 20, 30, 40, 50]);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn rle_irregular_samples() {
+        let path = "rle_irregular_samples.db";
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &rle_options())
+            .expect("could not create db.");
+
+        // A flag sampled whenever it is polled, which only changes once: a run only holds
+        // records taken at the same interval.
+        for (offset, value) in &[
+            (0, 1u8),
+            (7, 1),
+            (30, 1),
+            (31, 1),
+            (60, 1),
+            (65, 0),
+            (100, 0),
+        ] {
+            db.append_record(RecordInfo {
+                time_offset: *offset,
+                value: *value,
+            })
+            .expect("could not append record.");
+        }
+        assert_eq!(db.runs_number(), Ok(4));
+
+        let offsets: Vec<i64> = db
+            .iter::<u8>()
+            .expect("could not iterate db.")
+            .map(|res| res.expect("could not read record.").1.time_offset)
+            .collect();
+        assert_eq!(offsets, vec![0, 7, 30, 31, 60, 65, 100]);
+        assert_eq!(db.search_offset(8), Ok(2));
+        assert_eq!(db.search_offset(31), Ok(3));
+        assert_eq!(db.search_offset(61), Ok(5));
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+        // An interrupted append extending the last run does not change the committed records.
+        let mut run = db.read_run(3).expect("could not read run.");
+        let slot = run.free_slot(2);
+        run.ends[slot] = (4, 500);
+        let mut data = fs::read(path).unwrap();
+        let pos = db.run_pos(3) as usize;
+        data[pos..pos + run.as_bytes(&db.header).len()].copy_from_slice(&run.as_bytes(&db.header));
+        fs::write(path, data).unwrap();
+
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(
+            db.read_record(6),
+            Ok(RecordInfo {
+                time_offset: 100,
+                value: 0u8
+            })
+        );
+        db.append_record(RecordInfo {
+            time_offset: 135,
+            value: 0u8,
+        })
+        .expect("could not append record.");
+        db.append_record(RecordInfo {
+            time_offset: 140,
+            value: 0u8,
+        })
+        .expect("could not append record.");
+        assert_eq!(db.runs_number(), Ok(5));
+        assert_eq!(db.read_record::<u8>(6).map(|r| r.time_offset), Ok(100));
+        assert_eq!(db.read_record::<u8>(7).map(|r| r.time_offset), Ok(135));
+        assert_eq!(db.read_record::<u8>(8).map(|r| r.time_offset), Ok(140));
+
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,510 @@
+//! A series split across several DB files, one per time window.
+//!
+//! A [`SegmentedDB`] is a directory of segments. Each segment is a regular DB file with its own header,
+//! holding the records of a window of time of fixed length, the span (e.g. one day). Every segment shares
+//! the same origin date and options, so a time offset means the same thing in every segment.
+//! Segments are named after the time offset at which their window starts, like `86400.db`.
+//!
+//! Queries go through every segment overlapping the requested range, and old records can be dropped
+//! by removing whole segment files. Since each segment is a small file, reordering only rewrites
+//! the segments that need it.
+//!
+//! The span is stored in the directory, in a file named `span` holding the number of time units of the
+//! span and its CRC32, so a series cannot be opened with another span and get overlapping segments.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, Utc};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    replace_file, Aggregate, Aggregation, Bucket, Buckets, DbHeader, DbIssue, DbOptions, Fill,
+    PhysicalDB, RecordInfo, Retention, Storage, TSLiteError, Timestamp, Value,
+};
+
+/// A DB file holding the records of the window starting at `start`.
+#[derive(Debug)]
+struct Segment {
+    start: i64,
+    db: PhysicalDB,
+}
+
+/// A series stored as a directory of DB files, each covering a window of time.
+#[derive(Debug)]
+pub struct SegmentedDB {
+    dir: PathBuf,
+    header: DbHeader,
+    span: i64,
+    segments: Vec<Segment>,
+}
+
+impl SegmentedDB {
+    /// Open the series stored in the directory `dir`, creating the directory if it does not exist.
+    /// Every segment covers `span`, which must be a whole number of time units of the DB,
+    /// otherwise `TSLiteError::InvalidInterval` is returned. It must also be the span the series
+    /// was created with, otherwise `TSLiteError::SpanMismatch` is returned.
+    /// If the directory already holds segments, the origin date and the options are read from them
+    /// and `origin_date` and `options` are ignored, like with [`PhysicalDB::new_with_options`].
+    /// The retention policy of `options` is ignored since segments must keep the same origin date,
+    /// old segments are removed with [`SegmentedDB::drop_before`] instead.
+    pub fn new(
+        dir: &Path,
+        origin_date: Option<DateTime<Utc>>,
+        span: chrono::Duration,
+        options: &DbOptions,
+    ) -> Result<SegmentedDB, TSLiteError> {
+        fs::create_dir_all(dir).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir).map_err(|e| TSLiteError::IOError(e.to_string()))? {
+            let path = entry
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?
+                .path();
+            let start = match segment_start(&path) {
+                Some(start) => start,
+                None => continue,
+            };
+            let mut db = PhysicalDB::new(&path, None)?;
+            // Segments are only opened when they are used.
+            db.close()?;
+            segments.push(Segment { start, db });
+        }
+        segments.sort_by_key(|segment| segment.start);
+
+        let header = match segments.first() {
+            Some(segment) => segment.db.header,
+            None => {
+                if options.storage != Storage::Plain && options.checksums {
+                    return Err(TSLiteError::IncompatibleOptions);
+                }
+                let date = Timestamp::try_from(origin_date.unwrap_or_else(Utc::now))?;
+                let options = DbOptions {
+                    retention: Retention::default(),
+                    ..*options
+                };
+                DbHeader::new(date, &options)
+            }
+        };
+
+        let precision = header.precision;
+        let units = precision.units(span);
+        if units <= 0 || span != precision.duration(units) {
+            return Err(TSLiteError::InvalidInterval);
+        }
+        let span_path = dir.join("span");
+        if span_path.exists() {
+            let stored = read_span(&span_path)?;
+            if stored != units {
+                return Err(TSLiteError::SpanMismatch(stored));
+            }
+        } else {
+            write_span(dir, units)?;
+        }
+
+        Ok(SegmentedDB {
+            dir: PathBuf::from(dir),
+            header,
+            span: units,
+            segments,
+        })
+    }
+
+    /// The header shared by every segment. Its number of records is not meaningful.
+    pub fn header(&self) -> &DbHeader {
+        &self.header
+    }
+
+    /// The segments, from the oldest to the most recent.
+    pub fn segments(&self) -> impl Iterator<Item = &PhysicalDB> {
+        self.segments.iter().map(|segment| &segment.db)
+    }
+
+    /// The number of records of every segment.
+    pub fn records_number(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| segment.db.header.records_number)
+            .sum()
+    }
+
+    /// Close every segment file.
+    pub fn close(&mut self) -> Result<(), TSLiteError> {
+        for segment in self.segments.iter_mut() {
+            segment.db.close()?;
+        }
+
+        Ok(())
+    }
+
+    /// The index of the segment whose window holds `offset`, creating the segment if needed.
+    fn segment_for(&mut self, offset: i64) -> Result<usize, TSLiteError> {
+        let start = offset.div_euclid(self.span) * self.span;
+        match self
+            .segments
+            .binary_search_by_key(&start, |segment| segment.start)
+        {
+            Ok(index) => Ok(index),
+            Err(index) => {
+                let path = self.dir.join(format!("{}.db", start));
+                let origin_date = (&self.header.origin_date).into();
+                let db = PhysicalDB::create_with_options(
+                    &path,
+                    Some(origin_date),
+                    &self.header.options(),
+                )?;
+                self.segments.insert(index, Segment { start, db });
+                Ok(index)
+            }
+        }
+    }
+
+    /// Append a record to the segment whose window holds it, creating the segment if needed.
+    /// Records are expected to be appended in order, but a record older than the last segment
+    /// still goes to its own segment.
+    /// `V` must be the value type of the series, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn append_record<V: Value>(&mut self, rec_nfo: RecordInfo<V>) -> Result<(), TSLiteError> {
+        self.append_records(&[rec_nfo])
+    }
+
+    /// Append several records, with a single commit per segment they go to.
+    /// No segment is created or written if one of the records cannot be stored.
+    /// `V` must be the value type of the series, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn append_records<V: Value>(
+        &mut self,
+        records: &[RecordInfo<V>],
+    ) -> Result<(), TSLiteError> {
+        if V::TYPE != self.header.value_type {
+            return Err(TSLiteError::TypeMismatch);
+        }
+        for record in records {
+            self.header.check_time_offset(record.time_offset)?;
+        }
+
+        let mut first = 0;
+        while first < records.len() {
+            let index = self.segment_for(records[first].time_offset)?;
+            let end = self.segments[index].start.saturating_add(self.span);
+            let start = self.segments[index].start;
+            let count = records[first..]
+                .iter()
+                .take_while(|r| start <= r.time_offset && r.time_offset < end)
+                .count();
+            self.segments[index]
+                .db
+                .append_records(&records[first..first + count])?;
+            first += count;
+        }
+
+        Ok(())
+    }
+
+    /// Append a record with the current time.
+    pub fn append_record_now<V: Value>(&mut self, value: V) -> Result<(), TSLiteError> {
+        let now = Timestamp::try_from(Utc::now())?;
+        let off = self
+            .header
+            .origin_date
+            .offset_in(&now, self.header.precision);
+        self.append_record(RecordInfo {
+            value,
+            time_offset: off,
+        })
+    }
+
+    /// Read a record, `rec_id` being its index among the records of every segment.
+    pub fn read_record<V: Value>(&mut self, rec_id: u64) -> Result<RecordInfo<V>, TSLiteError> {
+        let mut rec_id = rec_id;
+        for segment in self.segments.iter_mut() {
+            let records_number = segment.db.header.records_number;
+            if rec_id < records_number {
+                return segment.db.read_record(rec_id);
+            }
+            rec_id -= records_number;
+        }
+
+        Err(TSLiteError::IndexOutOfBound)
+    }
+
+    /// The records between `from` (included) and `to` (excluded), read from every segment whose
+    /// window overlaps the range.
+    fn range_records<'a, V: Value + 'a>(
+        &'a mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> impl Iterator<Item = Result<RecordInfo<V>, TSLiteError>> + 'a {
+        let first = self.header.offset_from_date(from);
+        let last = self.header.offset_from_date(to);
+        let span = self.span;
+        self.segments
+            .iter_mut()
+            .filter(move |segment| segment.start < last && first < segment.start + span)
+            .flat_map(move |segment| {
+                let records: Box<dyn Iterator<Item = Result<RecordInfo<V>, TSLiteError>> + 'a> =
+                    match segment.db.iter_range::<V>(from, to) {
+                        Ok(iter) => Box::new(iter.map(|res| res.map(|(_, r)| r))),
+                        Err(e) => Box::new(std::iter::once(Err(e))),
+                    };
+                records
+            })
+    }
+
+    /// Return every record between `from` (included) and `to` (excluded), see [`PhysicalDB::query_range`].
+    /// `V` must be the value type of the series, otherwise `TSLiteError::TypeMismatch` is returned.
+    pub fn query_range<V: Value>(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<RecordInfo<V>>, TSLiteError> {
+        self.range_records(from, to).collect()
+    }
+
+    /// Compute the statistics of the records between `from` (included) and `to` (excluded),
+    /// see [`PhysicalDB::aggregate`].
+    pub fn aggregate<V: Value>(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Aggregate<V>, TSLiteError> {
+        let mut aggregate = Aggregate::default();
+        for res in self.range_records(from, to) {
+            aggregate.push(res?);
+        }
+
+        Ok(aggregate)
+    }
+
+    /// Downsample the records between `from` (included) and `to` (excluded), see [`PhysicalDB::downsample`].
+    pub fn downsample<V: Value>(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: chrono::Duration,
+        aggregation: Aggregation,
+        fill: Fill,
+    ) -> Result<Vec<Bucket>, TSLiteError> {
+        let buckets = match Buckets::new(
+            self.header.offset_from_date(from),
+            self.header.offset_from_date(to),
+            interval,
+            self.header.precision,
+        )? {
+            Some(buckets) => buckets,
+            None => return Ok(Vec::new()),
+        };
+
+        let origin = self.header.origin_date;
+        let records = self.range_records::<V>(from, to);
+        buckets.aggregate(origin, records, aggregation, fill)
+    }
+
+    /// Check every segment, see [`PhysicalDB::check_db_file`].
+    /// Return the first issue found, records being ordered across segments by construction.
+    pub fn check_db_file(&mut self) -> Result<DbIssue, TSLiteError> {
+        for segment in self.segments.iter_mut() {
+            let issue = segment.db.check_db_file()?;
+            if issue != DbIssue::None {
+                return Ok(issue);
+            }
+        }
+
+        Ok(DbIssue::None)
+    }
+
+    /// Sort the records of the segments that are not ordered, see [`PhysicalDB::reorder_record`].
+    /// The other segments are left untouched.
+    pub fn reorder_record(&mut self) -> Result<(), TSLiteError> {
+        for segment in self.segments.iter_mut() {
+            if segment.db.check_db_file()? == DbIssue::UnorderedRecord {
+                segment.db.reorder_record()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove every segment whose window ends at or before `date`, and return how many were removed.
+    /// Only whole segments are removed, so records anterior to `date` can remain in the oldest segment.
+    pub fn drop_before(&mut self, date: DateTime<Utc>) -> Result<usize, TSLiteError> {
+        let offset = self.header.offset_from_date(date);
+        let span = self.span;
+        let count = self
+            .segments
+            .iter()
+            .take_while(|segment| segment.start.saturating_add(span) <= offset)
+            .count();
+
+        for segment in self.segments.drain(..count) {
+            let mut db = segment.db;
+            db.close()?;
+            fs::remove_file(&db.path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+            if db.header.storage == Storage::Compressed {
+                let _ = fs::remove_file(db.index_path());
+            }
+            let _ = fs::remove_file(db.overflow_path());
+            let _ = fs::remove_file(db.journal_path());
+            let _ = fs::remove_file(db.tombstones_path());
+        }
+
+        Ok(count)
+    }
+}
+
+/// Read the span stored at `path`, in time units of the DB.
+fn read_span(path: &Path) -> Result<i64, TSLiteError> {
+    let data = fs::read(path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    if data.len() != 8 + 4 {
+        return Err(TSLiteError::IOError("Span is corrupted.".to_string()));
+    }
+    let (content, checksum) = data.split_at(8);
+    if Cursor::new(checksum).read_u32::<LittleEndian>().unwrap() != crc32fast::hash(content) {
+        return Err(TSLiteError::IOError("Span is corrupted.".to_string()));
+    }
+
+    Ok(Cursor::new(content).read_i64::<LittleEndian>().unwrap())
+}
+
+/// Write the span of the series in the directory `dir`, through a temporary file.
+fn write_span(dir: &Path, units: i64) -> Result<(), TSLiteError> {
+    let mut store: Vec<u8> = Vec::with_capacity(8 + 4);
+    store.write_i64::<LittleEndian>(units).unwrap();
+    let checksum = crc32fast::hash(&store);
+    store.write_u32::<LittleEndian>(checksum).unwrap();
+
+    let tmp_path = dir.join("span.tmp");
+    let mut file = File::create(&tmp_path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    file.write_all(&store)
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    file.sync_all()
+        .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    replace_file(&tmp_path, &dir.join("span"))
+}
+
+/// The start of the window of a segment file, or `None` if the file is not a segment.
+fn segment_start(path: &Path) -> Option<i64> {
+    path.file_name()?
+        .to_str()?
+        .strip_suffix(".db")?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const DAY: i64 = 86_400;
+
+    #[test]
+    fn segmented_series() {
+        let dir = "segmented_series";
+        let _ = fs::remove_dir_all(dir);
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let span = chrono::Duration::days(1);
+        let mut db = SegmentedDB::new(
+            Path::new(dir),
+            Some(origin_date),
+            span,
+            &DbOptions::default(),
+        )
+        .expect("could not create db.");
+
+        // A record every hour for three days.
+        let records: Vec<RecordInfo> = (0..72)
+            .map(|i| RecordInfo {
+                time_offset: i * 3600,
+                value: i as u8,
+            })
+            .collect();
+        for batch in records.chunks(20) {
+            db.append_records(batch).expect("could not append records.");
+        }
+        assert_eq!(db.segments().count(), 3);
+        db.close().expect("could not close db.");
+
+        let mut db = SegmentedDB::new(Path::new(dir), None, span, &DbOptions::default())
+            .expect("could not open db.");
+        assert_eq!(
+            db.header().origin_date,
+            Timestamp::try_from(origin_date).unwrap()
+        );
+        assert_eq!(db.records_number(), 72);
+        assert_eq!(db.read_record(30), Ok(records[30]));
+        assert_eq!(db.read_record::<u8>(72), Err(TSLiteError::IndexOutOfBound));
+        assert!(Path::new(dir).join(format!("{}.db", DAY)).exists());
+
+        // The range spans the three segments.
+        let from = origin_date + chrono::Duration::hours(20);
+        let to = origin_date + chrono::Duration::hours(50);
+        let read = db.query_range::<u8>(from, to).expect("could not query db.");
+        assert_eq!(read, records[20..50].to_vec());
+        let aggregate = db
+            .aggregate::<u8>(from, to)
+            .expect("could not aggregate db.");
+        assert_eq!(aggregate.count, 30);
+        let buckets = db
+            .downsample::<u8>(
+                origin_date,
+                origin_date + chrono::Duration::days(3),
+                span,
+                Aggregation::Count,
+                Fill::None,
+            )
+            .expect("could not downsample db.");
+        assert_eq!(buckets.len(), 3);
+        assert_eq!(
+            db.query_range::<u16>(from, to),
+            Err(TSLiteError::TypeMismatch)
+        );
+
+        // A late record goes to its own segment, which is reordered alone.
+        db.append_record(RecordInfo {
+            time_offset: 3601,
+            value: 0u8,
+        })
+        .expect("could not append record.");
+        assert_eq!(db.check_db_file(), Ok(DbIssue::UnorderedRecord));
+        db.reorder_record().expect("could not reorder db.");
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert_eq!(db.read_record::<u8>(2).map(|r| r.time_offset), Ok(3601));
+
+        // Only whole segments are dropped, along with their side files.
+        let journal_path = Path::new(dir).join("0.db.jrn");
+        fs::write(&journal_path, b"").unwrap();
+        let dropped = db
+            .drop_before(origin_date + chrono::Duration::hours(36))
+            .expect("could not drop segments.");
+        assert_eq!(dropped, 1);
+        assert_eq!(db.records_number(), 48);
+        assert!(!Path::new(dir).join("0.db").exists());
+        assert!(!journal_path.exists());
+        assert_eq!(db.read_record(0), Ok(records[24]));
+
+        assert_eq!(
+            SegmentedDB::new(
+                Path::new(dir),
+                None,
+                chrono::Duration::milliseconds(1500),
+                &DbOptions::default()
+            )
+            .err(),
+            Some(TSLiteError::InvalidInterval)
+        );
+
+        // The segments would overlap with another span.
+        assert_eq!(
+            SegmentedDB::new(
+                Path::new(dir),
+                None,
+                chrono::Duration::hours(1),
+                &DbOptions::default()
+            )
+            .err(),
+            Some(TSLiteError::SpanMismatch(DAY))
+        );
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
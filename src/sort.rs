@@ -0,0 +1,297 @@
+//! Sorting the records of a DB with a bounded amount of memory.
+//!
+//! [`PhysicalDB::reorder_record`] reads the records by runs of [`SORT_RUN_RECORDS`] records, sorts each
+//! run in memory and writes it to a temporary file next to the DB file, named after it with `.run`
+//! and the number of the run appended. The runs are then merged, at most 64 at a time, until
+//! the last merge writes the sorted records to a new DB file which then replaces the DB file, so a crash
+//! while sorting leaves the DB as it was. Deleted records are left out of the runs, so they are removed
+//! from the DB along with its tombstones.
+//!
+//! Records with the same time offset keep their order. The records of a ring start from its first slot
+//! once sorted, like when it is compacted.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::iter::RawRecords;
+use crate::{DbHeader, PhysicalDB, TSLiteError};
+
+/// The number of records sorted in memory at once by [`PhysicalDB::reorder_record`].
+pub const SORT_RUN_RECORDS: u64 = 65536;
+
+/// The maximal number of runs merged at once, so only as many files are open at the same time.
+const MERGE_FAN_IN: usize = 64;
+
+/// Write sorted records to a run file.
+fn write_run(path: &Path, records: &[Vec<u8>]) -> Result<(), TSLiteError> {
+    let file = File::create(path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    let mut writer = BufWriter::new(file);
+    for record in records {
+        writer
+            .write_all(record)
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+    }
+    writer
+        .flush()
+        .map_err(|e| TSLiteError::IOError(e.to_string()))
+}
+
+/// Read the next record of a run file, if there is one.
+fn read_run_record(
+    reader: &mut BufReader<File>,
+    record_size: u64,
+) -> Result<Option<Vec<u8>>, TSLiteError> {
+    let mut record = vec![0u8; record_size as usize];
+    match reader.read_exact(&mut record) {
+        Ok(()) => Ok(Some(record)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(TSLiteError::IOError(e.to_string())),
+    }
+}
+
+/// Merge sorted run files, giving their records to `output` in order. Records with the same
+/// time offset are given in the order of the runs.
+fn merge_runs<F>(header: &DbHeader, runs: &[PathBuf], mut output: F) -> Result<(), TSLiteError>
+where
+    F: FnMut(Vec<u8>) -> Result<(), TSLiteError>,
+{
+    let record_size = header.record_size();
+    let mut readers = Vec::with_capacity(runs.len());
+    let mut current: Vec<Option<Vec<u8>>> = Vec::with_capacity(runs.len());
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (i, run) in runs.iter().enumerate() {
+        let file = File::open(run).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        let mut reader = BufReader::new(file);
+        let record = read_run_record(&mut reader, record_size)?;
+        if let Some(record) = &record {
+            heap.push(Reverse((header.raw_time_offset(record), i)));
+        }
+        readers.push(reader);
+        current.push(record);
+    }
+
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let record = current[i].take().unwrap();
+        current[i] = read_run_record(&mut readers[i], record_size)?;
+        if let Some(next) = &current[i] {
+            heap.push(Reverse((header.raw_time_offset(next), i)));
+        }
+        output(record)?;
+    }
+
+    Ok(())
+}
+
+impl PhysicalDB {
+    /// The path of the run `run` of a sort, see the module documentation.
+    fn run_path(&self, run: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".run{}", run));
+        PathBuf::from(path)
+    }
+
+    /// Reorder the records of the DB like [`PhysicalDB::reorder_record`], sorting `run_records`
+    /// records in memory at once.
+    pub fn reorder_record_in_runs(&mut self, run_records: u64) -> Result<(), TSLiteError> {
+        if run_records == 0 {
+            return Err(TSLiteError::UnsupportedOperation);
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+        if self.header.records_number == 0 {
+            return Ok(());
+        }
+
+        let mut runs = Vec::new();
+        let origin_date = self.header.origin_date;
+        let res = self.rewrite(origin_date, |db, sorted| {
+            db.sort_into(sorted, run_records, &mut runs)
+        });
+        for run in &runs {
+            let _ = fs::remove_file(run);
+        }
+        res
+    }
+
+    /// Sort the records of the DB that are not deleted and append them to `sorted`, the new DB
+    /// of a rewrite. The paths of the run files are added to `runs` as they are created.
+    fn sort_into(
+        &mut self,
+        sorted: &mut PhysicalDB,
+        run_records: u64,
+        runs: &mut Vec<PathBuf>,
+    ) -> Result<(), TSLiteError> {
+        let header = self.header;
+        let records_number = header.records_number;
+
+        // Records are kept as raw octets so this works whatever the value type of the DB is.
+        let mut first = 0;
+        while first < records_number {
+            let last = (first + run_records).min(records_number);
+            let records = RawRecords::unchecked(self, first, last)
+                .collect::<Result<Vec<Vec<u8>>, TSLiteError>>()?;
+            let mut records: Vec<Vec<u8>> = (first..)
+                .zip(records)
+                .filter(|(rec_id, _)| !self.is_deleted(*rec_id))
+                .map(|(_, record)| record)
+                .collect();
+            records.sort_by_key(|r| header.raw_time_offset(r));
+            runs.push(self.run_path(runs.len()));
+            write_run(runs.last().unwrap(), &records)?;
+            first = last;
+        }
+
+        // Consecutive runs are merged together, so records with the same time offset keep their order.
+        let mut pending: Vec<PathBuf> = runs.clone();
+        while pending.len() > MERGE_FAN_IN {
+            let mut merged = Vec::new();
+            for group in pending.chunks(MERGE_FAN_IN) {
+                let path = self.run_path(runs.len());
+                runs.push(path.clone());
+                let file = File::create(&path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+                let mut writer = BufWriter::new(file);
+                merge_runs(&header, group, |record| {
+                    writer
+                        .write_all(&record)
+                        .map_err(|e| TSLiteError::IOError(e.to_string()))
+                })?;
+                writer
+                    .flush()
+                    .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+                merged.push(path);
+            }
+            pending = merged;
+        }
+
+        let mut batch: Vec<Vec<u8>> = Vec::new();
+        merge_runs(&header, &pending, |record| {
+            batch.push(record);
+            if batch.len() as u64 == run_records {
+                sorted.append_rewrite(&std::mem::take(&mut batch))?;
+            }
+            Ok(())
+        })?;
+        sorted.append_rewrite(&batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbIssue, DbOptions, Labels, RecordInfo, Storage};
+    use chrono::{TimeZone, Utc};
+
+    fn offsets(db: &mut PhysicalDB) -> Vec<i64> {
+        db.iter::<u16>()
+            .expect("could not iterate db.")
+            .map(|res| res.expect("could not read record.").1.time_offset)
+            .collect()
+    }
+
+    #[test]
+    fn reorder_in_runs() {
+        let path = "reorder_in_runs.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let labels: Labels = vec![("host".to_string(), "a".to_string())]
+            .into_iter()
+            .collect();
+
+        for &storage in [Storage::Plain, Storage::Compressed, Storage::Rle].iter() {
+            let options = DbOptions {
+                value_type: crate::ValueType::U16,
+                storage,
+                ..DbOptions::default()
+            };
+            let mut db = PhysicalDB::create_with_labels(
+                Path::new(path),
+                Some(origin_date),
+                &options,
+                &labels,
+            )
+            .expect("could not create db.");
+            // Every offset appears twice, the value telling which one came first.
+            let records: Vec<RecordInfo<u16>> = (0..1000u16)
+                .map(|i| RecordInfo {
+                    time_offset: (i % 500) as i64 * 7919 % 500,
+                    value: i,
+                })
+                .collect();
+            db.append_records(&records)
+                .expect("could not append records.");
+            assert_eq!(db.check_db_file(), Ok(DbIssue::UnorderedRecord));
+
+            // 100 runs need two passes of merges.
+            db.reorder_record_in_runs(10)
+                .expect("could not reorder db.");
+            assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+            assert_eq!(db.header.records_number, 1000);
+            let expected: Vec<i64> = (0..1000).map(|i| i / 2).collect();
+            assert_eq!(offsets(&mut db), expected);
+            let first = db.read_record::<u16>(0).expect("could not read record.");
+            let second = db.read_record::<u16>(1).expect("could not read record.");
+            assert!(first.value < second.value);
+            assert_eq!(db.labels(), Ok(labels.clone()));
+            assert!(!Path::new("reorder_in_runs.db.tmp").exists());
+            assert!(!Path::new("reorder_in_runs.db.run0").exists());
+            db.close().expect("could not close db.");
+
+            let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+            assert_eq!(offsets(&mut db), expected);
+        }
+
+        assert_eq!(
+            PhysicalDB::new(Path::new(path), None)
+                .expect("could not open db.")
+                .reorder_record_in_runs(0),
+            Err(TSLiteError::UnsupportedOperation)
+        );
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.idx", path));
+    }
+
+    #[test]
+    fn reorder_without_deleted() {
+        let path = "reorder_without_deleted.db";
+        for &storage in [Storage::Plain, Storage::Compressed, Storage::Rle].iter() {
+            let options = DbOptions {
+                value_type: crate::ValueType::U16,
+                storage,
+                ..DbOptions::default()
+            };
+            let mut db = PhysicalDB::create_with_options(Path::new(path), None, &options)
+                .expect("could not create db.");
+            let records: Vec<RecordInfo<u16>> = (0..100u16)
+                .map(|i| RecordInfo {
+                    time_offset: 99 - i as i64,
+                    value: i,
+                })
+                .collect();
+            db.append_records(&records)
+                .expect("could not append records.");
+            db.delete_record(0).expect("could not delete record.");
+            db.delete_record(50).expect("could not delete record.");
+
+            // The deleted records are left out and their tombstones removed with the old file.
+            db.reorder_record_in_runs(30)
+                .expect("could not reorder db.");
+            assert_eq!(db.deleted(), 0);
+            assert!(!db.tombstones_path().exists());
+            assert_eq!(db.header.records_number, 98);
+            let expected: Vec<i64> = (0..99).filter(|&offset| offset != 49).collect();
+            assert_eq!(offsets(&mut db), expected);
+            assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+            db.close().expect("could not close db.");
+
+            let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+            assert_eq!(offsets(&mut db), expected);
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.idx", path));
+    }
+}
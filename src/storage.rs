@@ -0,0 +1,46 @@
+//! The ways the records of a DB can be laid out in its file.
+//!
+//! Every DB declares in its header how its records are stored. Some operations only make sense
+//! for some storages, they return `TSLiteError::UnsupportedOperation` on the others.
+
+use std::convert::TryFrom;
+
+use crate::TSLiteError;
+
+/// How the records of a DB are stored, as written in its header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Storage {
+    /// Every record has the same size and is stored as is, so any record can be found directly.
+    Plain,
+    /// Records are compressed by blocks, see [`COMPRESSED_BLOCK_RECORDS`](crate::COMPRESSED_BLOCK_RECORDS).
+    /// Records cannot be updated in place.
+    Compressed,
+    /// Consecutive records with the same value, taken at a regular interval, are stored as a single run,
+    /// which suits values that rarely change like sampled state flags. Only the first and last time offsets
+    /// of a run are kept, see the [crate documentation](crate). Records cannot be updated in place.
+    Rle,
+}
+
+impl Storage {
+    /// The code used to store this storage in the header.
+    pub fn code(self) -> u8 {
+        match self {
+            Storage::Plain => 0,
+            Storage::Compressed => 1,
+            Storage::Rle => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for Storage {
+    type Error = TSLiteError;
+
+    fn try_from(code: u8) -> Result<Storage, TSLiteError> {
+        match code {
+            0 => Ok(Storage::Plain),
+            1 => Ok(Storage::Compressed),
+            2 => Ok(Storage::Rle),
+            _ => Err(TSLiteError::UnknownStorage(code)),
+        }
+    }
+}
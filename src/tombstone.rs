@@ -0,0 +1,466 @@
+//! Deletion of records with tombstones.
+//!
+//! Deleting records does not touch the DB file: the ranges of deleted records are kept in a file next
+//! to it, named after it with `.del` appended, and the deleted records are skipped by reads and queries.
+//! Records are only removed from the file by [`PhysicalDB::compact`], which rewrites the records
+//! following the first deleted one, or every record of a compressed or run-length encoded DB,
+//! and forgets the tombstones.
+//!
+//! ```text
+//! +----------------[TOMBSTONES]---------------+
+//! |-[COUNT]-|-[START]-|-[END]-|-...-|-[CRC32]-|
+//! |  32bit  |  64bit  | 64bit |     |  32bit  |
+//! +-------------------------------------------+
+//! ```
+//!
+//! Every range goes from its start (included) to its end (excluded), counted from the first record the DB
+//! has ever held so the ranges of a ring do not move when its oldest records are overwritten. The file is
+//! written to a temporary file which then replaces it, and the checksum is the CRC32 of everything before it.
+//!
+//! Operations rewriting every record, like [`PhysicalDB::reorder_record`], leave the deleted records out
+//! and forget the tombstones once the new file replaces the DB file. [`PhysicalDB::insert_record`] compacts
+//! the DB first when the records it moves include deleted ones, so the tombstones never point to the wrong
+//! records.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, Utc};
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::insert::{write_journal, Journal};
+use crate::iter::RawRecords;
+use crate::{replace_file, PhysicalDB, Storage, TSLiteError};
+
+/// The ranges of deleted records, ordered and disjoint.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Tombstones {
+    ranges: Vec<(u64, u64)>,
+}
+
+impl Tombstones {
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The range holding `index`, if it is deleted.
+    fn find(&self, index: u64) -> Option<(u64, u64)> {
+        let i = self.ranges.partition_point(|range| range.1 <= index);
+        self.ranges.get(i).copied().filter(|range| range.0 <= index)
+    }
+
+    /// The number of deleted records in `[start, end)`.
+    fn count(&self, start: u64, end: u64) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| range.1.min(end).saturating_sub(range.0.max(start)))
+            .sum()
+    }
+
+    /// Delete the records in `[start, end)` and return how many were not deleted yet.
+    fn add(&mut self, start: u64, end: u64) -> u64 {
+        if start >= end {
+            return 0;
+        }
+
+        let added = (end - start) - self.count(start, end);
+        let first = self.ranges.partition_point(|range| range.1 < start);
+        let last = self.ranges.partition_point(|range| range.0 <= end);
+        let merged = self.ranges[first..last]
+            .iter()
+            .fold((start, end), |acc, range| {
+                (acc.0.min(range.0), acc.1.max(range.1))
+            });
+        self.ranges.splice(first..last, std::iter::once(merged));
+        added
+    }
+
+    /// Forget the ranges of records before `index`.
+    fn forget_before(&mut self, index: u64) {
+        self.ranges.retain(|range| range.1 > index);
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut store: Vec<u8> = Vec::with_capacity(4 + self.ranges.len() * 16 + 4);
+        store
+            .write_u32::<LittleEndian>(self.ranges.len() as u32)
+            .unwrap();
+        for (start, end) in &self.ranges {
+            store.write_u64::<LittleEndian>(*start).unwrap();
+            store.write_u64::<LittleEndian>(*end).unwrap();
+        }
+        let checksum = crc32fast::hash(&store);
+        store.write_u32::<LittleEndian>(checksum).unwrap();
+        store
+    }
+
+    fn from_bytes(d: &[u8]) -> Result<Tombstones, TSLiteError> {
+        let corrupted = || TSLiteError::IOError("Tombstones are corrupted.".to_string());
+        if d.len() < 4 + 4 {
+            return Err(corrupted());
+        }
+        let (content, checksum) = d.split_at(d.len() - 4);
+        if Cursor::new(checksum).read_u32::<LittleEndian>().unwrap() != crc32fast::hash(content) {
+            return Err(corrupted());
+        }
+
+        let mut reader = Cursor::new(content);
+        let count = reader.read_u32::<LittleEndian>().unwrap();
+        let ranges = (0..count)
+            .map(|_| {
+                let start = reader.read_u64::<LittleEndian>().ok()?;
+                let end = reader.read_u64::<LittleEndian>().ok()?;
+                Some((start, end))
+            })
+            .collect::<Option<Vec<(u64, u64)>>>()
+            .ok_or_else(corrupted)?;
+
+        Ok(Tombstones { ranges })
+    }
+}
+
+impl PhysicalDB {
+    /// The path of the tombstones of the DB: the path of the DB followed by `.del`.
+    pub fn tombstones_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".del");
+        PathBuf::from(path)
+    }
+
+    /// Read the tombstones of the DB, if it has some.
+    pub(crate) fn read_tombstones(&mut self) -> Result<(), TSLiteError> {
+        let mut data = Vec::new();
+        match File::open(self.tombstones_path()) {
+            Ok(mut file) => file
+                .read_to_end(&mut data)
+                .map_err(|e| TSLiteError::IOError(e.to_string()))?,
+            Err(_) => {
+                self.tombstones = Tombstones::default();
+                return Ok(());
+            }
+        };
+
+        self.tombstones = Tombstones::from_bytes(&data)?;
+        Ok(())
+    }
+
+    /// Write the tombstones to a temporary file, then replace the tombstones with it.
+    fn write_tombstones(&self) -> Result<(), TSLiteError> {
+        let mut tmp_path = self.tombstones_path().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path).map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        file.write_all(&self.tombstones.as_bytes())
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        file.sync_all()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?;
+        replace_file(Path::new(&tmp_path), &self.tombstones_path())
+    }
+
+    /// Forget every tombstone.
+    pub(crate) fn clear_tombstones(&mut self) -> Result<(), TSLiteError> {
+        self.tombstones = Tombstones::default();
+        match fs::remove_file(self.tombstones_path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(TSLiteError::IOError(e.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The records deleted with the record `rec_id`, from the first one (included) to the last one
+    /// (excluded), if it is deleted.
+    pub(crate) fn deleted_range(&self, rec_id: u64) -> Option<(u64, u64)> {
+        let overwritten = self.header.overwritten;
+        self.tombstones
+            .find(overwritten + rec_id)
+            .map(|(start, end)| (start.max(overwritten) - overwritten, end - overwritten))
+    }
+
+    /// Tell if the record `rec_id` is deleted.
+    pub fn is_deleted(&self, rec_id: u64) -> bool {
+        self.deleted_range(rec_id).is_some()
+    }
+
+    /// Tell if a record from `rec_id` on is deleted, so moving them needs a compaction first.
+    pub(crate) fn deleted_from(&self, rec_id: u64) -> bool {
+        let overwritten = self.header.overwritten;
+        self.tombstones.count(
+            overwritten + rec_id,
+            overwritten + self.header.records_number,
+        ) > 0
+    }
+
+    /// The number of deleted records still in the DB file, see [`PhysicalDB::compact`].
+    pub fn deleted(&self) -> u64 {
+        let overwritten = self.header.overwritten;
+        self.tombstones
+            .count(overwritten, overwritten + self.header.records_number)
+    }
+
+    /// Mark the records in `[first, last)` as deleted and return how many were not deleted yet.
+    fn delete(&mut self, first: u64, last: u64) -> Result<u64, TSLiteError> {
+        let overwritten = self.header.overwritten;
+        let mut tombstones = self.tombstones.clone();
+        tombstones.forget_before(overwritten);
+        let added = tombstones.add(overwritten + first, overwritten + last);
+        if added > 0 {
+            let previous = std::mem::replace(&mut self.tombstones, tombstones);
+            if let Err(e) = self.write_tombstones() {
+                self.tombstones = previous;
+                return Err(e);
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Mark the record `rec_id` as deleted, so it is skipped by reads and queries.
+    /// It stays in the file until the DB is compacted, see [`PhysicalDB::compact`].
+    pub fn delete_record(&mut self, rec_id: u64) -> Result<(), TSLiteError> {
+        if rec_id >= self.header.records_number {
+            return Err(TSLiteError::IndexOutOfBound);
+        }
+
+        self.delete(rec_id, rec_id + 1)?;
+        Ok(())
+    }
+
+    /// Mark every record between `from` (included) and `to` (excluded) as deleted, and return
+    /// how many were not deleted yet. Records are expected to be ordered, see [`PhysicalDB::check_db_file`].
+    pub fn delete_range(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<u64, TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let first = self.search_offset(self.offset_from_date(from))?;
+        let last = self.search_offset(self.offset_from_date(to))?;
+        self.delete(first, last)
+    }
+
+    /// Remove the deleted records from the file and return how many were removed.
+    /// With the plain storage, the records following the first deleted one are rewritten through
+    /// the journal (see [`PhysicalDB::insert_record`]), so an interrupted compaction is finished when
+    /// the DB is opened again. The other storages rewrite every record to a new file which then
+    /// replaces the DB file, so an interrupted compaction leaves the DB as it was.
+    pub fn compact(&mut self) -> Result<u64, TSLiteError> {
+        if self.tombstones.is_empty() {
+            return Ok(0);
+        }
+        let removed = self.deleted();
+        if removed == 0 {
+            // Only records overwritten by a ring were deleted.
+            self.clear_tombstones()?;
+            return Ok(0);
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let header = self.header;
+        if header.storage != Storage::Plain {
+            self.rewrite(header.origin_date, |db, new| {
+                db.copy_records(new, 0, header.records_number, |record| record.to_vec())
+            })?;
+            return Ok(removed);
+        }
+
+        // A ring is rewritten from its first slot, since its records must start there
+        // as long as it is not full.
+        let first = match header.capacity {
+            None => (0..header.records_number)
+                .find(|rec_id| self.is_deleted(*rec_id))
+                .unwrap(),
+            Some(_) => 0,
+        };
+        let journal = Journal {
+            slot: first,
+            records_number: header.records_number - removed,
+            overflow: false,
+            compaction: true,
+        };
+        let journal_path = self.journal_path();
+        let tombstones = self.tombstones.clone();
+        let kept = RawRecords::unchecked(self, first, header.records_number)
+            .zip(first..)
+            .filter(|(res, rec_id)| {
+                res.is_err() || tombstones.find(header.overwritten + rec_id).is_none()
+            })
+            .map(|(res, _)| res);
+        write_journal(&journal_path, &journal, kept)?;
+        self.replay_journal()?;
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DbIssue, DbOptions, RecordInfo};
+    use chrono::TimeZone;
+    use std::path::Path;
+
+    fn offsets(db: &mut PhysicalDB) -> Vec<i64> {
+        db.iter::<u8>()
+            .expect("could not iterate db.")
+            .map(|res| res.expect("could not read record.").1.time_offset)
+            .collect()
+    }
+
+    #[test]
+    fn tombstone_ranges() {
+        let mut tombstones = Tombstones::default();
+        assert_eq!(tombstones.add(10, 20), 10);
+        assert_eq!(tombstones.add(30, 40), 10);
+        assert_eq!(tombstones.add(15, 32), 10);
+        assert_eq!(tombstones.add(40, 41), 1);
+        assert_eq!(tombstones.ranges, vec![(10, 41)]);
+        assert_eq!(tombstones.add(50, 50), 0);
+        assert_eq!(tombstones.find(40), Some((10, 41)));
+        assert_eq!(tombstones.find(41), None);
+        assert_eq!(tombstones.count(0, 20), 10);
+        assert_eq!(
+            Tombstones::from_bytes(&tombstones.as_bytes()),
+            Ok(tombstones)
+        );
+    }
+
+    #[test]
+    fn delete_and_compact() {
+        let path = "delete_and_compact.db";
+        let origin_date = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let seconds = |s: i64| origin_date + chrono::Duration::seconds(s);
+        let mut db =
+            PhysicalDB::create(Path::new(path), Some(origin_date)).expect("could not create db.");
+        let records: Vec<RecordInfo> = (0..100)
+            .map(|i| RecordInfo {
+                time_offset: i,
+                value: i as u8,
+            })
+            .collect();
+        db.append_records(&records)
+            .expect("could not append records.");
+
+        db.delete_record(3).expect("could not delete record.");
+        assert_eq!(db.delete_range(seconds(10), seconds(20)), Ok(10));
+        assert_eq!(db.delete_range(seconds(15), seconds(25)), Ok(5));
+        assert_eq!(db.delete_record(100), Err(TSLiteError::IndexOutOfBound));
+        assert_eq!(db.deleted(), 16);
+        assert_eq!(db.header.records_number, 100);
+        db.close().expect("could not close db.");
+
+        // Deleted records are skipped by reads and queries, from both ends.
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.deleted(), 16);
+        assert_eq!(db.read_record::<u8>(3), Err(TSLiteError::DeletedRecord(3)));
+        assert_eq!(db.update_record(3, 1u8), Err(TSLiteError::DeletedRecord(3)));
+        assert_eq!(db.read_record(4), Ok(records[4]));
+        let read = db
+            .query_range::<u8>(seconds(0), seconds(30))
+            .expect("could not query db.");
+        assert_eq!(read.len(), 14);
+        let back: Vec<i64> = db
+            .iter_range::<u8>(seconds(5), seconds(30))
+            .expect("could not iterate db.")
+            .rev()
+            .map(|res| res.expect("could not read record.").1.time_offset)
+            .collect();
+        assert_eq!(back, vec![29, 28, 27, 26, 25, 9, 8, 7, 6, 5]);
+        let aggregate = db
+            .aggregate::<u8>(seconds(0), seconds(100))
+            .expect("could not aggregate db.");
+        assert_eq!(aggregate.count, 84);
+
+        assert_eq!(db.compact(), Ok(16));
+        assert_eq!(db.deleted(), 0);
+        assert!(!db.tombstones_path().exists());
+        assert_eq!(db.header.records_number, 84);
+        assert_eq!(fs::metadata(path).unwrap().len(), db.header.record_pos(84));
+        assert_eq!(db.read_record(3), Ok(records[4]));
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+        assert_eq!(db.compact(), Ok(0));
+
+        // Inserting a record compacts the DB first only if the records it moves include deleted ones.
+        db.delete_record(0).expect("could not delete record.");
+        db.insert_record(RecordInfo {
+            time_offset: 50,
+            value: 0u8,
+        })
+        .expect("could not insert record.");
+        assert_eq!(db.deleted(), 1);
+        assert_eq!(db.header.records_number, 85);
+        assert_eq!(offsets(&mut db)[..3].to_vec(), vec![1, 2, 4]);
+        db.delete_record(80).expect("could not delete record.");
+        db.insert_record(RecordInfo {
+            time_offset: 60,
+            value: 0u8,
+        })
+        .expect("could not insert record.");
+        assert_eq!(db.deleted(), 0);
+        assert_eq!(db.header.records_number, 84);
+        assert_eq!(offsets(&mut db)[..3].to_vec(), vec![1, 2, 4]);
+        assert_eq!(db.check_db_file(), Ok(DbIssue::None));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn delete_in_ring_and_compressed() {
+        let path = "delete_in_ring_and_compressed.db";
+        let options = DbOptions {
+            capacity: Some(10),
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &options)
+            .expect("could not create db.");
+        let records: Vec<RecordInfo> = (0..15)
+            .map(|i| RecordInfo {
+                time_offset: i,
+                value: i as u8,
+            })
+            .collect();
+        db.append_records(&records)
+            .expect("could not append records.");
+        db.delete_record(0).expect("could not delete record.");
+        db.delete_record(9).expect("could not delete record.");
+
+        // The tombstones follow the records when the oldest ones are overwritten.
+        db.append_record(records[14])
+            .expect("could not append record.");
+        assert_eq!(db.deleted(), 1);
+        assert!(db.is_deleted(8));
+        assert_eq!(db.compact(), Ok(1));
+        assert_eq!(db.header.records_number, 9);
+        assert_eq!(db.header.overwritten, 0);
+        assert_eq!(offsets(&mut db), vec![6, 7, 8, 9, 10, 11, 12, 13, 14]);
+        db.close().expect("could not close db.");
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(offsets(&mut db), vec![6, 7, 8, 9, 10, 11, 12, 13, 14]);
+
+        let options = DbOptions {
+            storage: Storage::Compressed,
+            ..DbOptions::default()
+        };
+        let mut db = PhysicalDB::create_with_options(Path::new(path), None, &options)
+            .expect("could not create db.");
+        assert_eq!(db.deleted(), 0);
+        db.append_records(&records)
+            .expect("could not append records.");
+        db.delete_record(7).expect("could not delete record.");
+        assert_eq!(db.compact(), Ok(1));
+        assert_eq!(db.header.records_number, 14);
+        assert_eq!(db.read_record(7), Ok(records[8]));
+        assert!(!db.rewrite_path().exists());
+        db.close().expect("could not close db.");
+        let mut db = PhysicalDB::new(Path::new(path), None).expect("could not open db.");
+        assert_eq!(db.header.records_number, 14);
+        assert_eq!(db.read_record(13), Ok(records[14]));
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.idx", path));
+    }
+}
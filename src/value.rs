@@ -0,0 +1,194 @@
+//! The types of value that can be stored in a DB.
+//!
+//! Every DB declares in its header the type of the values it holds. All the records of a DB
+//! share this type, so the size of a record is fixed for a given DB.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::io::Cursor;
+
+use crate::TSLiteError;
+
+/// The type of the values stored in a DB, as written in its header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    U16,
+    I32,
+    I64,
+    F32,
+    F64,
+    Bool,
+}
+
+impl ValueType {
+    /// The number of octets taken by one value of this type.
+    pub fn size(self) -> u64 {
+        match self {
+            ValueType::U8 | ValueType::Bool => 1,
+            ValueType::U16 => 2,
+            ValueType::I32 | ValueType::F32 => 4,
+            ValueType::I64 | ValueType::F64 => 8,
+        }
+    }
+
+    /// The code used to store this type in the header.
+    pub fn code(self) -> u8 {
+        match self {
+            ValueType::U8 => 0,
+            ValueType::U16 => 1,
+            ValueType::I32 => 2,
+            ValueType::I64 => 3,
+            ValueType::F32 => 4,
+            ValueType::F64 => 5,
+            ValueType::Bool => 6,
+        }
+    }
+}
+
+impl TryFrom<u8> for ValueType {
+    type Error = TSLiteError;
+
+    fn try_from(code: u8) -> Result<ValueType, TSLiteError> {
+        match code {
+            0 => Ok(ValueType::U8),
+            1 => Ok(ValueType::U16),
+            2 => Ok(ValueType::I32),
+            3 => Ok(ValueType::I64),
+            4 => Ok(ValueType::F32),
+            5 => Ok(ValueType::F64),
+            6 => Ok(ValueType::Bool),
+            _ => Err(TSLiteError::UnknownValueType(code)),
+        }
+    }
+}
+
+/// A value that can be stored in a record.
+/// Every value is stored with little-endian ordering.
+pub trait Value: Copy + Debug + PartialEq + PartialOrd {
+    /// The type declared in the header of a DB holding this kind of value.
+    const TYPE: ValueType;
+
+    /// Write the value at the end of `store`.
+    fn write_to(&self, store: &mut Vec<u8>);
+
+    /// Read a value from `reader`. The reader must hold at least `TYPE.size()` octets.
+    fn read_from(reader: &mut Cursor<&[u8]>) -> Self;
+
+    /// Convert the value to a `f64`, used to compute aggregations.
+    fn to_f64(self) -> f64;
+}
+
+impl Value for u8 {
+    const TYPE: ValueType = ValueType::U8;
+
+    fn write_to(&self, store: &mut Vec<u8>) {
+        store.write_u8(*self).unwrap();
+    }
+
+    fn read_from(reader: &mut Cursor<&[u8]>) -> u8 {
+        reader.read_u8().unwrap()
+    }
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+impl Value for u16 {
+    const TYPE: ValueType = ValueType::U16;
+
+    fn write_to(&self, store: &mut Vec<u8>) {
+        store.write_u16::<LittleEndian>(*self).unwrap();
+    }
+
+    fn read_from(reader: &mut Cursor<&[u8]>) -> u16 {
+        reader.read_u16::<LittleEndian>().unwrap()
+    }
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+impl Value for i32 {
+    const TYPE: ValueType = ValueType::I32;
+
+    fn write_to(&self, store: &mut Vec<u8>) {
+        store.write_i32::<LittleEndian>(*self).unwrap();
+    }
+
+    fn read_from(reader: &mut Cursor<&[u8]>) -> i32 {
+        reader.read_i32::<LittleEndian>().unwrap()
+    }
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+impl Value for i64 {
+    const TYPE: ValueType = ValueType::I64;
+
+    fn write_to(&self, store: &mut Vec<u8>) {
+        store.write_i64::<LittleEndian>(*self).unwrap();
+    }
+
+    fn read_from(reader: &mut Cursor<&[u8]>) -> i64 {
+        reader.read_i64::<LittleEndian>().unwrap()
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Value for f32 {
+    const TYPE: ValueType = ValueType::F32;
+
+    fn write_to(&self, store: &mut Vec<u8>) {
+        store.write_f32::<LittleEndian>(*self).unwrap();
+    }
+
+    fn read_from(reader: &mut Cursor<&[u8]>) -> f32 {
+        reader.read_f32::<LittleEndian>().unwrap()
+    }
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+impl Value for f64 {
+    const TYPE: ValueType = ValueType::F64;
+
+    fn write_to(&self, store: &mut Vec<u8>) {
+        store.write_f64::<LittleEndian>(*self).unwrap();
+    }
+
+    fn read_from(reader: &mut Cursor<&[u8]>) -> f64 {
+        reader.read_f64::<LittleEndian>().unwrap()
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+/// Booleans are stored on one octet, `0` being `false` and anything else `true`.
+impl Value for bool {
+    const TYPE: ValueType = ValueType::Bool;
+
+    fn write_to(&self, store: &mut Vec<u8>) {
+        store.write_u8(*self as u8).unwrap();
+    }
+
+    fn read_from(reader: &mut Cursor<&[u8]>) -> bool {
+        reader.read_u8().unwrap() != 0
+    }
+
+    fn to_f64(self) -> f64 {
+        f64::from(self as u8)
+    }
+}
@@ -0,0 +1,209 @@
+//! Full integrity check of a DB file.
+//!
+//! [`PhysicalDB::check_db_file`] stops at the first issue it finds, [`PhysicalDB::verify`] walks
+//! the whole file once and reports every issue with where it is.
+
+use crate::iter::RawRecords;
+use crate::{DbHeader, DbIssue, PhysicalDB, Storage, TSLiteError};
+
+/// An issue found in a DB file.
+/// `record` is the index of the record concerned, if any, and `byte_offset` its position in the file.
+/// For compressed and run-length encoded DBs, `byte_offset` is the position of the block or run holding the record.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Finding {
+    pub issue: DbIssue,
+    pub record: Option<u64>,
+    pub byte_offset: u64,
+}
+
+/// The result of [`PhysicalDB::verify`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntegrityReport {
+    /// Every issue found, in the order of the file.
+    pub findings: Vec<Finding>,
+    /// The number of records according to the header.
+    pub records_number: u64,
+    /// The number of full records in the file.
+    pub physical_records: u64,
+    /// The number of records anterior to the record preceding them.
+    pub unordered_records: u64,
+    /// The number of records counted in the header that cannot be fully read or whose checksum is wrong.
+    pub corrupted_records: u64,
+}
+
+impl IntegrityReport {
+    /// Return `true` if no issue was found.
+    pub fn is_healthy(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+impl PhysicalDB {
+    /// Check the whole DB file and report every issue found with its record index and byte offset.
+    /// If the header cannot be read, it is the only issue reported since the records cannot be located.
+    pub fn verify(&mut self) -> Result<IntegrityReport, TSLiteError> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+        self.merge_overflow()?;
+
+        let mut report = IntegrityReport {
+            findings: Vec::new(),
+            records_number: 0,
+            physical_records: 0,
+            unordered_records: 0,
+            corrupted_records: 0,
+        };
+
+        let header = match self.read_header() {
+            Ok(header) => header,
+            Err(_) => {
+                report.findings.push(Finding {
+                    issue: DbIssue::HeaderCorrupted,
+                    record: None,
+                    byte_offset: 0,
+                });
+                return Ok(report);
+            }
+        };
+        if !header.origin_date.is_valid() {
+            report.findings.push(Finding {
+                issue: DbIssue::OriginDateInvalid,
+                record: None,
+                byte_offset: DbHeader::PREAMBLE_SIZE,
+            });
+        }
+
+        let len = self
+            .file
+            .as_ref()
+            .unwrap()
+            .metadata()
+            .map_err(|e| TSLiteError::IOError(e.to_string()))?
+            .len();
+        let records_len = len.saturating_sub(header.header_len as u64);
+        report.records_number = header.records_number;
+        report.physical_records = self.physical_records()?;
+
+        // The issues are located once every record has been read.
+        let mut issues: Vec<(DbIssue, Option<u64>)> = Vec::new();
+        let mut time_offset = i64::MIN;
+        for (i, res_record) in RawRecords::unchecked(self, 0, report.physical_records).enumerate() {
+            let record = res_record?;
+            if header.check_record(i as u64, &record).is_err() {
+                report.corrupted_records += 1;
+                issues.push((DbIssue::RecordCorrupted(i as u64), Some(i as u64)));
+            }
+            let offset = header.raw_time_offset(&record);
+            if time_offset > offset {
+                report.unordered_records += 1;
+                issues.push((DbIssue::UnorderedRecord, Some(i as u64)));
+            }
+            time_offset = offset;
+        }
+
+        // Only the first record that cannot be read is reported, the following ones are just counted.
+        if report.physical_records < header.records_number {
+            report.corrupted_records += header.records_number - report.physical_records;
+            issues.push((
+                DbIssue::RecordCorrupted(report.physical_records),
+                Some(report.physical_records),
+            ));
+        }
+        let partial = header.storage == Storage::Plain && records_len % header.record_size() != 0;
+        if report.physical_records != header.records_number || partial {
+            issues.push((DbIssue::MismatchRecordAmount, None));
+        }
+
+        for (issue, record) in issues {
+            let rec_id =
+                record.unwrap_or_else(|| header.records_number.min(report.physical_records));
+            report.findings.push(Finding {
+                issue,
+                record,
+                byte_offset: self.record_file_pos(rec_id)?,
+            });
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DbIssue, PhysicalDB, RecordInfo};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn verify_healthy_db() {
+        let path = "verify_healthy_db.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        for i in 0..10 {
+            db.append_record(RecordInfo {
+                time_offset: i,
+                value: i as u8,
+            })
+            .expect("could not append record.");
+        }
+
+        let report = db.verify().expect("could not verify db.");
+        assert!(report.is_healthy());
+        assert_eq!(report.records_number, 10);
+        assert_eq!(report.physical_records, 10);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn verify_reports_every_issue() {
+        let path = "verify_reports_every_issue.db";
+        let _ = fs::remove_file(path);
+
+        let mut db = PhysicalDB::create(Path::new(path), None).expect("could not create db.");
+        // Two records out of order.
+        for offset in &[0, 5, 3, 8, 9, 1, 10, 11] {
+            db.append_record(RecordInfo {
+                time_offset: *offset,
+                value: 0u8,
+            })
+            .expect("could not append record.");
+        }
+        // Cut the last two records, leaving 1 octet of the 7th one.
+        let len = db.header.record_pos(6) + 1;
+        db.file.as_ref().unwrap().set_len(len).unwrap();
+
+        let report = db.verify().expect("could not verify db.");
+        assert_eq!(report.records_number, 8);
+        assert_eq!(report.physical_records, 6);
+        assert_eq!(report.unordered_records, 2);
+        assert_eq!(report.corrupted_records, 2);
+
+        let issues: Vec<(DbIssue, Option<u64>, u64)> = report
+            .findings
+            .iter()
+            .map(|f| (f.issue, f.record, f.byte_offset))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                (DbIssue::UnorderedRecord, Some(2), db.header.record_pos(2)),
+                (DbIssue::UnorderedRecord, Some(5), db.header.record_pos(5)),
+                (
+                    DbIssue::RecordCorrupted(6),
+                    Some(6),
+                    db.header.record_pos(6)
+                ),
+                (DbIssue::MismatchRecordAmount, None, db.header.record_pos(6)),
+            ]
+        );
+
+        // Once repaired, nothing is left.
+        db.repair(false).expect("could not repair db.");
+        assert!(db.verify().expect("could not verify db.").is_healthy());
+
+        let _ = fs::remove_file(path);
+    }
+}